@@ -0,0 +1,53 @@
+// C API of the quadtree_ffi library. Coordinates are qt_float: float, or double when the
+// library is built with the f64 feature, in which case define QUADTREE_F64 before including
+// this header. See ffi/src/lib.rs for the full description of each call.
+#ifndef QUADTREE_H
+#define QUADTREE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#ifdef QUADTREE_F64
+typedef double qt_float;
+#else
+typedef float qt_float;
+#endif
+
+typedef struct QuadTree QuadTree;
+
+// Returned by the batch calls instead of a count when a required array is NULL while count
+// is not 0, or when only one of width and height is given
+#define QUADTREE_INVALID_ARGUMENTS SIZE_MAX
+
+QuadTree *quadtree_new(qt_float x, qt_float y, qt_float width, qt_float height);
+void quadtree_free(QuadTree *tree);
+
+// Shapes come from parallel arrays of count entries: radius makes circles, width and
+// height make rectangles, and neither (NULL) makes points. entity_types may be NULL, and
+// every array may be NULL when count is 0.
+size_t quadtree_insert_batch(QuadTree *tree, const uint32_t *ids, const qt_float *x,
+                             const qt_float *y, const qt_float *width,
+                             const qt_float *height, const qt_float *radius,
+                             const int64_t *entity_types, size_t count);
+size_t quadtree_relocate_batch(QuadTree *tree, const uint32_t *ids, const qt_float *x,
+                               const qt_float *y, const qt_float *width,
+                               const qt_float *height, const qt_float *radius,
+                               const int64_t *entity_types, size_t count);
+size_t quadtree_delete_batch(QuadTree *tree, const uint32_t *ids, size_t count);
+
+// offsets holds count + 1 entries, so it is never NULL. Returns the total number of hits,
+// which may exceed capacity, in which case only the first capacity hits are written.
+size_t quadtree_collisions_batch(const QuadTree *tree, const qt_float *x,
+                                 const qt_float *y, const qt_float *width,
+                                 const qt_float *height, const qt_float *radius, size_t count,
+                                 uint32_t *offsets, uint32_t *results, size_t capacity);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
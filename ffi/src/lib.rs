@@ -0,0 +1,232 @@
+// C API shaped for P/Invoke from C# and Unity. Batches are passed as parallel arrays of
+// blittable values and results are written into buffers the caller preallocates, so a
+// call marshals no objects and allocates nothing on the managed side.
+//
+// Every function taking a tree expects a pointer returned by quadtree_new that has not
+// been freed, and every array must hold at least the number of entries the call says.
+// Arrays may be NULL when count is 0.
+#![allow(clippy::missing_safety_doc)]
+
+use quadtree::quadtree::QuadTree;
+use quadtree::shapes::{Circle, Float, Point, Rectangle, ShapeEnum};
+
+use std::ops::ControlFlow;
+use std::slice;
+
+// Returned by the batch calls instead of a count when a required array is NULL while
+// count is not 0, or when only one of width and height is given
+const INVALID_ARGUMENTS: usize = usize::MAX;
+
+#[no_mangle]
+pub extern "C" fn quadtree_new(x: Float, y: Float, width: Float, height: Float) -> *mut QuadTree {
+    Box::into_raw(Box::new(QuadTree::new(Rectangle::new(x, y, width, height))))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn quadtree_free(tree: *mut QuadTree) {
+    if !tree.is_null() {
+        drop(Box::from_raw(tree));
+    }
+}
+
+// Insert count entries. Shapes are read from parallel arrays: passing radius makes
+// circles, width and height make rectangles, and neither makes points. entity_types may
+// be null, and negative types mean no type. Returns the number of entries inserted,
+// which is less than count if an entry was rejected, because its value is already in
+// the tree or its shape is not allowed by the tree configuration. Returns
+// INVALID_ARGUMENTS without inserting anything if ids, x or y is null while count is not
+// 0, or only one of width and height is given.
+#[no_mangle]
+pub unsafe extern "C" fn quadtree_insert_batch(
+    tree: *mut QuadTree,
+    ids: *const u32,
+    x: *const Float,
+    y: *const Float,
+    width: *const Float,
+    height: *const Float,
+    radius: *const Float,
+    entity_types: *const i64,
+    count: usize,
+) -> usize {
+    let tree = &mut *tree;
+    let (Some(shapes), Some(ids)) = (
+        ShapeArrays::new(x, y, width, height, radius, count),
+        required_slice(ids, count),
+    ) else {
+        return INVALID_ARGUMENTS;
+    };
+    for (index, &value) in ids.iter().enumerate() {
+        let entity_type = entity_type_at(entity_types, index);
+        if tree
+            .try_insert(value, shapes.shape(index), entity_type)
+            .is_err()
+        {
+            return index;
+        }
+    }
+    count
+}
+
+// Relocate count entries, with the array layout of quadtree_insert_batch. Returns the
+// number of entries relocated, or INVALID_ARGUMENTS for the arrays quadtree_insert_batch
+// rejects.
+#[no_mangle]
+pub unsafe extern "C" fn quadtree_relocate_batch(
+    tree: *mut QuadTree,
+    ids: *const u32,
+    x: *const Float,
+    y: *const Float,
+    width: *const Float,
+    height: *const Float,
+    radius: *const Float,
+    entity_types: *const i64,
+    count: usize,
+) -> usize {
+    let tree = &mut *tree;
+    let (Some(shapes), Some(ids)) = (
+        ShapeArrays::new(x, y, width, height, radius, count),
+        required_slice(ids, count),
+    ) else {
+        return INVALID_ARGUMENTS;
+    };
+    for (index, &value) in ids.iter().enumerate() {
+        let entity_type = entity_type_at(entity_types, index);
+        if tree
+            .try_relocate(value, shapes.shape(index), entity_type)
+            .is_err()
+        {
+            return index;
+        }
+    }
+    count
+}
+
+// Returns the number of ids that had an entry to delete, or INVALID_ARGUMENTS if ids is
+// null while count is not 0
+#[no_mangle]
+pub unsafe extern "C" fn quadtree_delete_batch(
+    tree: *mut QuadTree,
+    ids: *const u32,
+    count: usize,
+) -> usize {
+    let tree = &mut *tree;
+    let Some(ids) = required_slice(ids, count) else {
+        return INVALID_ARGUMENTS;
+    };
+    ids.iter().filter(|&&value| tree.delete(value)).count()
+}
+
+// Run count collision queries, with query shapes laid out as for quadtree_insert_batch.
+// The hits of query i are written to results[offsets[i]..offsets[i + 1]], so offsets
+// must hold count + 1 entries. Returns the total number of hits. When that is more than
+// capacity only the first capacity hits are written, and the call can be repeated with
+// a buffer of the returned size. Returns INVALID_ARGUMENTS for the shape arrays
+// quadtree_insert_batch rejects, or when offsets is null.
+#[no_mangle]
+pub unsafe extern "C" fn quadtree_collisions_batch(
+    tree: *const QuadTree,
+    x: *const Float,
+    y: *const Float,
+    width: *const Float,
+    height: *const Float,
+    radius: *const Float,
+    count: usize,
+    offsets: *mut u32,
+    results: *mut u32,
+    capacity: usize,
+) -> usize {
+    let tree = &*tree;
+    let Some(shapes) = ShapeArrays::new(x, y, width, height, radius, count) else {
+        return INVALID_ARGUMENTS;
+    };
+    if offsets.is_null() {
+        return INVALID_ARGUMENTS;
+    }
+    let offsets = slice::from_raw_parts_mut(offsets, count + 1);
+    let results = optional_slice_mut(results, capacity);
+
+    let mut total = 0;
+    for (index, offset) in offsets.iter_mut().take(count).enumerate() {
+        *offset = total as u32;
+        let _ = tree.collisions_with(&shapes.shape(index), None, |value| {
+            if let Some(slot) = results.get_mut(total) {
+                *slot = value;
+            }
+            total += 1;
+            ControlFlow::Continue(())
+        });
+    }
+    offsets[count] = total as u32;
+    total
+}
+
+// Parallel coordinate arrays borrowed from the caller
+struct ShapeArrays<'a> {
+    x: &'a [Float],
+    y: &'a [Float],
+    width: Option<&'a [Float]>,
+    height: Option<&'a [Float]>,
+    radius: Option<&'a [Float]>,
+}
+
+impl<'a> ShapeArrays<'a> {
+    // None if x or y is missing, or only one of width and height is given
+    unsafe fn new(
+        x: *const Float,
+        y: *const Float,
+        width: *const Float,
+        height: *const Float,
+        radius: *const Float,
+        count: usize,
+    ) -> Option<Self> {
+        let width = optional_slice(width, count);
+        let height = optional_slice(height, count);
+        if width.is_some() != height.is_some() {
+            return None;
+        }
+        Some(ShapeArrays {
+            x: required_slice(x, count)?,
+            y: required_slice(y, count)?,
+            width,
+            height,
+            radius: optional_slice(radius, count),
+        })
+    }
+
+    fn shape(&self, index: usize) -> ShapeEnum {
+        let (x, y) = (self.x[index], self.y[index]);
+        match (self.radius, self.width, self.height) {
+            (Some(radius), _, _) => ShapeEnum::Circle(Circle::new(x, y, radius[index])),
+            (None, Some(width), Some(height)) => {
+                ShapeEnum::Rectangle(Rectangle::new(x, y, width[index], height[index]))
+            }
+            _ => ShapeEnum::Point(Point::new(x, y)),
+        }
+    }
+}
+
+unsafe fn required_slice<'a, T>(data: *const T, count: usize) -> Option<&'a [T]> {
+    if count == 0 {
+        return Some(&[]);
+    }
+    optional_slice(data, count)
+}
+
+unsafe fn optional_slice<'a, T>(data: *const T, count: usize) -> Option<&'a [T]> {
+    (!data.is_null()).then(|| slice::from_raw_parts(data, count))
+}
+
+unsafe fn optional_slice_mut<'a, T>(data: *mut T, count: usize) -> &'a mut [T] {
+    if data.is_null() {
+        &mut []
+    } else {
+        slice::from_raw_parts_mut(data, count)
+    }
+}
+
+unsafe fn entity_type_at(entity_types: *const i64, index: usize) -> Option<u32> {
+    if entity_types.is_null() {
+        return None;
+    }
+    u32::try_from(*entity_types.add(index)).ok()
+}
@@ -1,49 +1,72 @@
-use quadtree::quadtree::{Config, QuadTree, RelocationRequest};
-use quadtree::shapes::{Circle, Rectangle, Shape, ShapeEnum};
+use quadtree::cancellation::{CancellationToken, Cancelled};
+use quadtree::error::QuadTreeError;
+use quadtree::quadtree::{
+    Config, Cursor, DegenerateShapeMode, JoinPredicate, QuadTree, RelocationRequest,
+    RootHeavyCounts, RootHeavyReason, SizeClasses,
+};
+use quadtree::region_lock::RegionLock;
+use quadtree::shapes::{Circle, Float, Point, Rectangle, ShapeEnum};
+use quadtree::trace::{replay, Trace};
 
-use pyo3::exceptions::PyTypeError;
-#[cfg(feature = "pyo3")]
-use pyo3::prelude::*;
+use numpy::{Element, PyArray1, PyReadonlyArray1};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyKeyError, PyTypeError, PyValueError};
 use pyo3::pyclass;
+use pyo3::pyfunction;
 use pyo3::pymethods;
 use pyo3::pymodule;
 use pyo3::types::PyTuple;
-use pyo3::types::{PyList, PyModule};
+use pyo3::types::{PyAny, PyBytes, PyDict, PyList, PyModule};
+use pyo3::wrap_pyfunction;
 use pyo3::IntoPy;
 use pyo3::Py;
+use pyo3::PyErr;
 use pyo3::PyObject;
+use pyo3::PyRef;
+use pyo3::PyRefMut;
 use pyo3::PyResult;
 use pyo3::Python;
 
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
 #[derive(Debug, Clone)]
 #[pyclass(name = "Circle")]
 struct PyCircle {
-    x: f32,
-    y: f32,
-    radius: f32,
+    x: Float,
+    y: Float,
+    radius: Float,
 }
 
 #[pymethods]
 impl PyCircle {
     #[new]
-    pub fn new(x: f32, y: f32, radius: f32) -> Self {
+    pub fn new(x: Float, y: Float, radius: Float) -> Self {
         PyCircle { x, y, radius }
     }
+
+    pub fn to_dict(&self, py: Python) -> PyResult<PyObject> {
+        shape_to_dict(
+            py,
+            &ShapeEnum::Circle(Circle::new(self.x, self.y, self.radius)),
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
 #[pyclass(name = "Rectangle")]
 struct PyRectangle {
-    x: f32,
-    y: f32,
-    width: f32,
-    height: f32,
+    x: Float,
+    y: Float,
+    width: Float,
+    height: Float,
 }
 
 #[pymethods]
 impl PyRectangle {
     #[new]
-    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+    pub fn new(x: Float, y: Float, width: Float, height: Float) -> Self {
         PyRectangle {
             x,
             y,
@@ -51,6 +74,32 @@ impl PyRectangle {
             height,
         }
     }
+
+    pub fn to_dict(&self, py: Python) -> PyResult<PyObject> {
+        shape_to_dict(
+            py,
+            &ShapeEnum::Rectangle(Rectangle::new(self.x, self.y, self.width, self.height)),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+#[pyclass(name = "Point")]
+struct PyPoint {
+    x: Float,
+    y: Float,
+}
+
+#[pymethods]
+impl PyPoint {
+    #[new]
+    pub fn new(x: Float, y: Float) -> Self {
+        PyPoint { x, y }
+    }
+
+    pub fn to_dict(&self, py: Python) -> PyResult<PyObject> {
+        shape_to_dict(py, &ShapeEnum::Point(Point::new(self.x, self.y)))
+    }
 }
 
 #[derive(Clone)]
@@ -59,60 +108,493 @@ pub struct PyConfig {
     pool_size: usize,
     node_capacity: usize,
     max_depth: usize,
+    min_cell_size: Option<Float>,
+    // (x, y) point roots are split at instead of their center
+    root_split: Option<(Float, Float)>,
+    // (tiny_max, medium_max) thresholds for splitting entities into size classes
+    size_classes: Option<(Float, Float)>,
+    looseness: Float,
+    auto_expand: bool,
+    degenerate_shapes: DegenerateShapeMode,
 }
 
 #[pymethods]
 impl PyConfig {
     #[new]
-    pub fn new(pool_size: usize, node_capacity: usize, max_depth: usize) -> Self {
-        PyConfig {
+    #[pyo3(signature = (
+        pool_size,
+        node_capacity,
+        max_depth,
+        size_classes=None,
+        min_cell_size=None,
+        root_split=None,
+        looseness=1.0,
+        auto_expand=false,
+        degenerate_shapes="point_semantics"
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pool_size: usize,
+        node_capacity: usize,
+        max_depth: usize,
+        size_classes: Option<(Float, Float)>,
+        min_cell_size: Option<Float>,
+        root_split: Option<(Float, Float)>,
+        looseness: Float,
+        auto_expand: bool,
+        degenerate_shapes: &str,
+    ) -> PyResult<Self> {
+        let degenerate_shapes = match degenerate_shapes {
+            "always_miss" => DegenerateShapeMode::AlwaysMiss,
+            "point_semantics" => DegenerateShapeMode::PointSemantics,
+            "error_on_insert" => DegenerateShapeMode::ErrorOnInsert,
+            _ => return Err(PyValueError::new_err(
+                "Expected degenerate_shapes to be always_miss, point_semantics or error_on_insert",
+            )),
+        };
+        Ok(PyConfig {
             pool_size,
             node_capacity,
             max_depth,
+            min_cell_size,
+            root_split,
+            size_classes,
+            looseness,
+            auto_expand,
+            degenerate_shapes,
+        })
+    }
+}
+
+// Shared with a running query so another thread can stop it
+#[derive(Clone, Default)]
+#[pyclass(name = "CancellationToken")]
+pub struct PyCancellationToken {
+    token: CancellationToken,
+}
+
+#[pymethods]
+impl PyCancellationToken {
+    #[new]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+create_exception!(pyquadtree, QueryCancelled, PyException);
+create_exception!(pyquadtree, RegionLockedError, PyException);
+
+// Held region lock, usable as a context manager that releases it on exit
+#[pyclass(name = "RegionLock")]
+pub struct PyRegionLock {
+    lock: Option<RegionLock>,
+}
+
+#[pymethods]
+impl PyRegionLock {
+    pub fn release(&mut self) {
+        self.lock = None;
+    }
+
+    pub fn __enter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    pub fn __exit__(
+        &mut self,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> bool {
+        self.release();
+        false
+    }
+}
+
+fn quadtree_error(err: QuadTreeError) -> PyErr {
+    match err {
+        QuadTreeError::AlreadyExists(_) | QuadTreeError::UnknownTemplate(_) => {
+            PyKeyError::new_err(err.to_string())
+        }
+        QuadTreeError::DegenerateShape(_) => PyValueError::new_err(err.to_string()),
+        QuadTreeError::RegionLocked(_) => RegionLockedError::new_err(err.to_string()),
+    }
+}
+
+fn cancelled_error(cancelled: Cancelled) -> PyErr {
+    QueryCancelled::new_err(cancelled.to_string())
+}
+
+// A node as (depth, (x, y, width, height), entity_count, is_leaf)
+type NodeTuple = (usize, (Float, Float, Float, Float), usize, bool);
+// A coarse query hit as (depth, (x, y, width, height), entity_count)
+type SummaryTuple = (usize, (Float, Float, Float, Float), usize);
+
+fn object_to_shape(py: Python, shape: &PyObject) -> PyResult<ShapeEnum> {
+    if let Ok(py_rectangle) = shape.extract::<PyRectangle>(py) {
+        Ok(ShapeEnum::Rectangle(Rectangle {
+            x: py_rectangle.x,
+            y: py_rectangle.y,
+            width: py_rectangle.width,
+            height: py_rectangle.height,
+        }))
+    } else if let Ok(py_circle) = shape.extract::<PyCircle>(py) {
+        Ok(ShapeEnum::Circle(Circle::new(
+            py_circle.x,
+            py_circle.y,
+            py_circle.radius,
+        )))
+    } else if let Ok(py_point) = shape.extract::<PyPoint>(py) {
+        Ok(ShapeEnum::Point(Point::new(py_point.x, py_point.y)))
+    } else if let Ok(dict) = shape.as_ref(py).downcast::<PyDict>() {
+        dict_to_shape(dict)
+    } else {
+        Err(PyTypeError::new_err(
+            "Expected a Rectangle, Circle or Point object or a shape dict",
+        ))
+    }
+}
+
+// Read a shape from its dict form, as produced by shape_to_dict:
+// {"type": "circle", "x": .., "y": .., "r": ..},
+// {"type": "rectangle", "x": .., "y": .., "width": .., "height": ..} or
+// {"type": "point", "x": .., "y": ..}
+fn dict_to_shape(dict: &PyDict) -> PyResult<ShapeEnum> {
+    let field = |name: &str| -> PyResult<Float> {
+        dict.get_item(name)
+            .ok_or_else(|| PyValueError::new_err(format!("Shape dict is missing \"{}\"", name)))?
+            .extract()
+    };
+    let shape_type: String = dict
+        .get_item("type")
+        .ok_or_else(|| PyValueError::new_err("Shape dict is missing \"type\""))?
+        .extract()?;
+    match shape_type.as_str() {
+        "circle" => Ok(ShapeEnum::Circle(Circle::new(
+            field("x")?,
+            field("y")?,
+            field("r")?,
+        ))),
+        "rectangle" => Ok(ShapeEnum::Rectangle(Rectangle::new(
+            field("x")?,
+            field("y")?,
+            field("width")?,
+            field("height")?,
+        ))),
+        "point" => Ok(ShapeEnum::Point(Point::new(field("x")?, field("y")?))),
+        _ => Err(PyValueError::new_err(
+            "Expected shape type to be circle, rectangle or point",
+        )),
+    }
+}
+
+fn shape_to_dict(py: Python, shape: &ShapeEnum) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    match shape {
+        ShapeEnum::Circle(circle) => {
+            dict.set_item("type", "circle")?;
+            dict.set_item("x", circle.x)?;
+            dict.set_item("y", circle.y)?;
+            dict.set_item("r", circle.radius)?;
+        }
+        ShapeEnum::Rectangle(rect) => {
+            dict.set_item("type", "rectangle")?;
+            dict.set_item("x", rect.x)?;
+            dict.set_item("y", rect.y)?;
+            dict.set_item("width", rect.width)?;
+            dict.set_item("height", rect.height)?;
+        }
+        ShapeEnum::Point(point) => {
+            dict.set_item("type", "point")?;
+            dict.set_item("x", point.x)?;
+            dict.set_item("y", point.y)?;
+        }
+    }
+    Ok(dict.into())
+}
+
+// Shapes are returned as shape objects, or in dict form when as_dicts is set
+fn shape_output(py: Python, shape: &ShapeEnum, as_dicts: bool) -> PyResult<PyObject> {
+    if as_dicts {
+        shape_to_dict(py, shape)
+    } else {
+        shape_to_object(py, shape)
+    }
+}
+
+fn object_to_rectangle(py: Python, rectangle: &PyObject) -> PyResult<Rectangle> {
+    match object_to_shape(py, rectangle)? {
+        ShapeEnum::Rectangle(rectangle) => Ok(rectangle),
+        _ => Err(PyTypeError::new_err("Expected a Rectangle")),
+    }
+}
+
+fn check_length(length: usize, expected: usize) -> PyResult<()> {
+    if length != expected {
+        return Err(PyValueError::new_err(format!(
+            "Expected arrays of length {}, got {}",
+            expected, length
+        )));
+    }
+    Ok(())
+}
+
+// Build shapes from numpy coordinate arrays: circles when radius is given, rectangles
+// when width and height are, and points otherwise
+fn shapes_from_arrays(
+    x: &PyAny,
+    y: &PyAny,
+    width: Option<&PyAny>,
+    height: Option<&PyAny>,
+    radius: Option<&PyAny>,
+) -> PyResult<Vec<ShapeEnum>> {
+    let (x, y) = (float_array(x)?, float_array(y)?);
+    let (x, y) = (x.as_slice()?, y.as_slice()?);
+    check_length(y.len(), x.len())?;
+
+    match (width, height, radius) {
+        (None, None, Some(radius)) => {
+            let radius = float_array(radius)?;
+            let radius = radius.as_slice()?;
+            check_length(radius.len(), x.len())?;
+            Ok((0..x.len())
+                .map(|i| ShapeEnum::Circle(Circle::new(x[i], y[i], radius[i])))
+                .collect())
+        }
+        (Some(width), Some(height), None) => {
+            let (width, height) = (float_array(width)?, float_array(height)?);
+            let (width, height) = (width.as_slice()?, height.as_slice()?);
+            check_length(width.len(), x.len())?;
+            check_length(height.len(), x.len())?;
+            Ok((0..x.len())
+                .map(|i| ShapeEnum::Rectangle(Rectangle::new(x[i], y[i], width[i], height[i])))
+                .collect())
+        }
+        (None, None, None) => Ok((0..x.len())
+            .map(|i| ShapeEnum::Point(Point::new(x[i], y[i])))
+            .collect()),
+        _ => Err(PyValueError::new_err(
+            "Expected either radius, or both width and height",
+        )),
+    }
+}
+
+// Coordinates from a float32 or float64 numpy array. Arrays of the other precision than the
+// library was built with are converted, which copies them.
+fn float_array(array: &PyAny) -> PyResult<PyReadonlyArray1<'_, Float>> {
+    if let Ok(array) = array.downcast::<PyArray1<Float>>() {
+        return Ok(array.readonly());
+    }
+    let converted = if let Ok(array) = array.downcast::<PyArray1<f32>>() {
+        array.cast::<Float>(false)?
+    } else if let Ok(array) = array.downcast::<PyArray1<f64>>() {
+        array.cast::<Float>(false)?
+    } else {
+        return Err(PyTypeError::new_err(
+            "Expected a one-dimensional float32 or float64 array",
+        ));
+    };
+    Ok(converted.readonly())
+}
+
+// Values from a numpy array of any signed or unsigned integer dtype, widened to i64
+fn integer_array(array: &PyAny) -> PyResult<Vec<i64>> {
+    fn widen<T: Element + Copy>(array: &PyAny) -> Option<PyResult<Vec<i64>>>
+    where
+        i64: TryFrom<T>,
+    {
+        let array = array.downcast::<PyArray1<T>>().ok()?.readonly();
+        let values = array.as_array();
+        Some(
+            values
+                .iter()
+                .map(|&value| {
+                    i64::try_from(value)
+                        .map_err(|_| PyValueError::new_err("Integer value is out of range"))
+                })
+                .collect(),
+        )
+    }
+
+    widen::<i64>(array)
+        .or_else(|| widen::<i32>(array))
+        .or_else(|| widen::<i16>(array))
+        .or_else(|| widen::<i8>(array))
+        .or_else(|| widen::<u64>(array))
+        .or_else(|| widen::<u32>(array))
+        .or_else(|| widen::<u16>(array))
+        .or_else(|| widen::<u8>(array))
+        .unwrap_or_else(|| {
+            Err(PyTypeError::new_err(
+                "Expected a one-dimensional integer array",
+            ))
+        })
+}
+
+// Ids from a numpy array of any integer dtype. Raises ValueError for ids that do not fit
+// in a u32.
+fn ids_from_array(ids: &PyAny) -> PyResult<Vec<u32>> {
+    integer_array(ids)?
+        .into_iter()
+        .map(|id| {
+            u32::try_from(id)
+                .map_err(|_| PyValueError::new_err(format!("Id {} is out of range", id)))
+        })
+        .collect()
+}
+
+// Entity types from a numpy array of any integer dtype, where -1 means no type. Raises
+// ValueError for any other value that does not fit in a u32.
+fn entity_types_from_array(
+    entity_types: Option<&PyAny>,
+    length: usize,
+) -> PyResult<Vec<Option<u32>>> {
+    match entity_types {
+        Some(entity_types) => {
+            let entity_types = integer_array(entity_types)?;
+            check_length(entity_types.len(), length)?;
+            entity_types
+                .into_iter()
+                .map(|entity_type| match entity_type {
+                    -1 => Ok(None),
+                    _ => u32::try_from(entity_type).map(Some).map_err(|_| {
+                        PyValueError::new_err(format!(
+                            "Entity type {} is out of range",
+                            entity_type
+                        ))
+                    }),
+                })
+                .collect()
         }
+        None => Ok(vec![None; length]),
     }
 }
 
+// Blend between two shapes of the same kind, where t = 0 gives a and t = 1 gives b.
+// The result is a dict when a is one.
+#[pyfunction]
+fn lerp(py: Python, a: PyObject, b: PyObject, t: Float) -> PyResult<PyObject> {
+    let as_dicts = a.as_ref(py).downcast::<PyDict>().is_ok();
+    let a = object_to_shape(py, &a)?;
+    let b = object_to_shape(py, &b)?;
+    shape_output(py, &ShapeEnum::lerp(&a, &b, t), as_dicts)
+}
+
+fn shape_to_object(py: Python, shape: &ShapeEnum) -> PyResult<PyObject> {
+    Ok(match shape {
+        ShapeEnum::Circle(circle) => Py::new(
+            py,
+            PyCircle {
+                x: circle.x,
+                y: circle.y,
+                radius: circle.radius,
+            },
+        )?
+        .into_py(py),
+        ShapeEnum::Rectangle(rect) => Py::new(
+            py,
+            PyRectangle {
+                x: rect.x,
+                y: rect.y,
+                width: rect.width,
+                height: rect.height,
+            },
+        )?
+        .into_py(py),
+        ShapeEnum::Point(point) => Py::new(
+            py,
+            PyPoint {
+                x: point.x,
+                y: point.y,
+            },
+        )?
+        .into_py(py),
+    })
+}
+
 #[pymodule]
 fn pyquadtree(_py: Python, m: &PyModule) -> PyResult<()> {
-    #[pyclass(name = "QuadTree", unsendable)]
+    // The tree is Send + Sync, so it can be shared with other Python threads. Batch
+    // queries release the GIL, letting several threads query it at once.
+    #[pyclass(name = "QuadTree")]
     struct QuadTreeWrapper {
-        quadtree: QuadTree,
+        // Shared with the views taken from it, and copied on write while any are alive
+        quadtree: Arc<QuadTree>,
+        // Set on views, which only allow queries
+        read_only: bool,
     }
 
     #[pymethods]
     impl QuadTreeWrapper {
         #[new]
-        pub fn new(bounding_box: PyRectangle) -> Self {
-            let bounding_rect = Rectangle {
-                x: bounding_box.x,
-                y: bounding_box.y,
-                width: bounding_box.width,
-                height: bounding_box.height,
-            };
-            QuadTreeWrapper {
-                quadtree: QuadTree::new(bounding_rect),
-            }
+        pub fn new(py: Python, bounding_box: PyObject) -> PyResult<Self> {
+            let bounding_rect = object_to_rectangle(py, &bounding_box)?;
+            Ok(QuadTreeWrapper {
+                quadtree: Arc::new(QuadTree::new(bounding_rect)),
+                read_only: false,
+            })
         }
 
         #[staticmethod]
-        pub fn new_with_config(bounding_box: PyRectangle, config: PyConfig) -> Self {
-            let bounding_rect = Rectangle {
-                x: bounding_box.x,
-                y: bounding_box.y,
-                width: bounding_box.width,
-                height: bounding_box.height,
-            };
+        pub fn new_with_config(
+            py: Python,
+            bounding_box: PyObject,
+            config: PyConfig,
+        ) -> PyResult<Self> {
+            let bounding_rect = object_to_rectangle(py, &bounding_box)?;
             let rust_config = Config {
                 pool_size: config.pool_size,
                 node_capacity: config.node_capacity,
                 max_depth: config.max_depth,
+                min_cell_size: config.min_cell_size,
+                root_split: config.root_split.map(|(x, y)| Point::new(x, y)),
+                size_classes: config
+                    .size_classes
+                    .map(|(tiny_max, medium_max)| SizeClasses {
+                        tiny_max,
+                        medium_max,
+                    }),
+                looseness: config.looseness,
+                auto_expand: config.auto_expand,
+                degenerate_shapes: config.degenerate_shapes,
+            };
+            Ok(QuadTreeWrapper {
+                quadtree: Arc::new(QuadTree::new_with_config(bounding_rect, rust_config)),
+                read_only: false,
+            })
+        }
+
+        // Read-only snapshot of the tree as it is now, supporting every query. Worker
+        // threads can query a view while this tree keeps changing, since later mutations
+        // are not seen by the view. Taking a view is cheap: the tree is only copied by the
+        // next mutation made while a view of it is still alive. A recording tree is copied
+        // straight away instead, so queries made through the view stay out of the trace.
+        // Until the copy, node visits of view queries are counted with this tree.
+        pub fn view(&mut self) -> Self {
+            let view = QuadTreeWrapper {
+                quadtree: Arc::clone(&self.quadtree),
+                read_only: true,
             };
-            QuadTreeWrapper {
-                quadtree: QuadTree::new_with_config(bounding_rect, rust_config),
+            if self.quadtree.is_recording() {
+                self.tree_mut();
             }
+            view
         }
 
+        #[getter]
+        pub fn is_view(&self) -> bool {
+            self.read_only
+        }
+
+        // Raises KeyError if an entry already has this value, see upsert
         pub fn insert(
             &mut self,
             py: Python,
@@ -120,17 +602,103 @@ fn pyquadtree(_py: Python, m: &PyModule) -> PyResult<()> {
             shape: PyObject,
             entity_type: Option<u32>,
         ) -> PyResult<()> {
+            self.check_writable()?;
             let shape = self.extract_shape(py, shape)?;
-            self.quadtree.insert(value, shape, entity_type);
-            Ok(())
+            self.tree_mut()
+                .try_insert(value, shape, entity_type)
+                .map_err(quadtree_error)
+        }
+
+        // Raises KeyError, inserting nothing, if a value is already in the tree or repeated
+        // in the batch
+        pub fn insert_batch(&mut self, py: Python, items: Vec<&PyTuple>) -> PyResult<()> {
+            self.check_writable()?;
+            // Convert the Python (value, shape, entity_type) tuples into Rust tuples
+            let items = items
+                .into_iter()
+                .map(|tuple| {
+                    let value = tuple.get_item(0)?.extract::<u32>()?;
+                    let shape = self.extract_shape(py, tuple.get_item(1)?.into())?;
+                    let entity_type = tuple.get_item(2)?.extract::<Option<u32>>()?;
+                    Ok((value, shape, entity_type))
+                })
+                .collect::<PyResult<Vec<_>>>()?;
+
+            self.tree_mut()
+                .try_insert_batch(items)
+                .map_err(quadtree_error)
+        }
+
+        // Returns the id to pass to insert_from_template
+        #[pyo3(signature = (shape, entity_type=None))]
+        pub fn register_template(
+            &mut self,
+            py: Python,
+            shape: PyObject,
+            entity_type: Option<u32>,
+        ) -> PyResult<u32> {
+            self.check_writable()?;
+            let shape = self.extract_shape(py, shape)?;
+            Ok(self.tree_mut().register_template(shape, entity_type))
+        }
+
+        pub fn insert_from_template(
+            &mut self,
+            value: u32,
+            template: u32,
+            x: Float,
+            y: Float,
+        ) -> PyResult<()> {
+            self.check_writable()?;
+            self.tree_mut()
+                .insert_from_template(value, template, x, y)
+                .map_err(quadtree_error)
+        }
+
+        // Insert a shape, replacing any entry with the same value. Returns whether an
+        // entry was replaced.
+        pub fn upsert(
+            &mut self,
+            py: Python,
+            value: u32,
+            shape: PyObject,
+            entity_type: Option<u32>,
+        ) -> PyResult<bool> {
+            self.check_writable()?;
+            let shape = self.extract_shape(py, shape)?;
+            self.tree_mut()
+                .try_upsert(value, shape, entity_type)
+                .map_err(quadtree_error)
         }
 
-        pub fn delete(&mut self, value: u32) {
-            self.quadtree.delete(value);
+        // Returns whether there was an entry with this value
+        pub fn delete(&mut self, value: u32) -> PyResult<bool> {
+            self.check_writable()?;
+            self.tree_mut().try_delete(value).map_err(quadtree_error)
+        }
+
+        // Move an entry with its shape and entity type into another tree. Returns False if
+        // this tree has no entry with the value.
+        pub fn move_entity(&mut self, value: u32, mut target: PyRefMut<Self>) -> PyResult<bool> {
+            self.check_writable()?;
+            target.check_writable()?;
+            self.tree_mut()
+                .move_entity(value, QuadTree::make_mut(&mut target.quadtree))
+                .map_err(quadtree_error)
+        }
+
+        // Keep entries colliding with the region from changing until the returned lock is
+        // released. Mutations that would touch them raise RegionLockedError.
+        pub fn lock_region(&self, py: Python, region: PyObject) -> PyResult<PyRegionLock> {
+            self.check_writable()?;
+            let region = self.extract_shape(py, region)?;
+            Ok(PyRegionLock {
+                lock: Some(self.quadtree.lock_region(region)),
+            })
         }
 
         pub fn collisions(&self, py: Python, shape: PyObject) -> PyResult<Vec<u32>> {
-            return self.collisions_filter(py, shape, None);
+            self.collisions_filter(py, shape, None)
         }
 
         pub fn collisions_filter(
@@ -149,15 +717,125 @@ fn pyquadtree(_py: Python, m: &PyModule) -> PyResult<()> {
             Ok(collisions)
         }
 
+        // Collisions with entities whose type bit is set in category_mask
+        pub fn collisions_mask(
+            &self,
+            py: Python,
+            shape: PyObject,
+            category_mask: u64,
+        ) -> PyResult<Vec<u32>> {
+            let shape = self.extract_shape(py, shape)?;
+            let mut collisions = Vec::new();
+            self.quadtree
+                .collisions_mask(shape, category_mask, &mut collisions);
+            Ok(collisions)
+        }
+
+        // Call callback(id) for each collision until it returns True. Returns whether
+        // the search was stopped early.
+        #[pyo3(signature = (shape, callback, entity_types=None))]
+        pub fn collisions_with(
+            &self,
+            py: Python,
+            shape: PyObject,
+            callback: PyObject,
+            entity_types: Option<&PyList>,
+        ) -> PyResult<bool> {
+            let shape = self.extract_shape(py, shape)?;
+            let entity_types = self.extract_entity_types(entity_types)?;
+
+            // Errors raised by the callback stop the search and are re-raised afterwards
+            let mut error = None;
+            let flow = self
+                .quadtree
+                .collisions_with(&shape, entity_types.as_deref(), |value| {
+                    match callback
+                        .call1(py, (value,))
+                        .and_then(|result| result.is_true(py))
+                    {
+                        Ok(false) => ControlFlow::Continue(()),
+                        Ok(true) => ControlFlow::Break(()),
+                        Err(err) => {
+                            error = Some(err);
+                            ControlFlow::Break(())
+                        }
+                    }
+                });
+            match error {
+                Some(err) => Err(err),
+                None => Ok(flow.is_break()),
+            }
+        }
+
+        #[pyo3(signature = (shape, entity_types=None))]
+        pub fn any_collision(
+            &self,
+            py: Python,
+            shape: PyObject,
+            entity_types: Option<&PyList>,
+        ) -> PyResult<bool> {
+            let shape = self.extract_shape(py, shape)?;
+            let entity_types = self.extract_entity_types(entity_types)?;
+            Ok(self.quadtree.any_collision(&shape, entity_types.as_deref()))
+        }
+
+        // Returns (id, time_of_impact) for every entry the shape hits while moving at
+        // velocity for dt, earliest first
+        pub fn collisions_swept(
+            &self,
+            py: Python,
+            shape: PyObject,
+            velocity: (Float, Float),
+            dt: Float,
+        ) -> PyResult<Vec<(u32, Float)>> {
+            let shape = self.extract_shape(py, shape)?;
+            Ok(self
+                .quadtree
+                .collisions_swept(&shape, velocity, dt)
+                .into_iter()
+                .map(|hit| (hit.value, hit.time_of_impact))
+                .collect())
+        }
+
+        // Returns the ids hit above max_depth and the (depth, (x, y, width, height),
+        // entity_count) of each touched subtree at max_depth
+        pub fn collisions_coarse(
+            &self,
+            py: Python,
+            shape: PyObject,
+            max_depth: usize,
+        ) -> PyResult<(Vec<u32>, Vec<SummaryTuple>)> {
+            let shape = self.extract_shape(py, shape)?;
+            let mut collisions = Vec::new();
+            let mut summaries = Vec::new();
+            self.quadtree
+                .collisions_coarse(shape, max_depth, &mut collisions, &mut summaries);
+            let summaries = summaries
+                .into_iter()
+                .map(|summary| {
+                    let rect = summary.bounding_box;
+                    (
+                        summary.depth,
+                        (rect.x, rect.y, rect.width, rect.height),
+                        summary.entity_count,
+                    )
+                })
+                .collect();
+            Ok((collisions, summaries))
+        }
+
         pub fn collisions_batch(&self, py: Python, shapes: &PyList) -> PyResult<Vec<Vec<u32>>> {
-            self.collisions_batch_filter(py, shapes, None)
+            self.collisions_batch_filter(py, shapes, None, None)
         }
 
+        // Raises QueryCancelled if cancel_token is cancelled before the batch completes
+        #[pyo3(signature = (shapes, entity_types, cancel_token=None))]
         pub fn collisions_batch_filter(
             &self,
             py: Python,
             shapes: &PyList,
             entity_types: Option<&PyList>,
+            cancel_token: Option<PyCancellationToken>,
         ) -> PyResult<Vec<Vec<u32>>> {
             let shapes: Vec<ShapeEnum> = shapes
                 .iter()
@@ -166,18 +844,157 @@ fn pyquadtree(_py: Python, m: &PyModule) -> PyResult<()> {
 
             let entity_types = self.extract_entity_types(entity_types)?;
 
-            Ok(self.quadtree.collisions_batch_filter(shapes, entity_types))
+            // Queries only read the tree, so other Python threads can run meanwhile
+            let token = cancel_token.map(|token| token.token).unwrap_or_default();
+            let quadtree = &self.quadtree;
+            py.allow_threads(|| quadtree.collisions_batch_cancellable(shapes, entity_types, &token))
+                .map_err(cancelled_error)
+        }
+
+        pub fn line_of_sight_batch(
+            &self,
+            py: Python,
+            pairs: Vec<((Float, Float), (Float, Float))>,
+            blocker_types: Option<&PyList>,
+        ) -> PyResult<Vec<bool>> {
+            let pairs: Vec<(Point, Point)> = pairs
+                .into_iter()
+                .map(|((x1, y1), (x2, y2))| (Point::new(x1, y1), Point::new(x2, y2)))
+                .collect();
+
+            let blocker_types = self.extract_entity_types(blocker_types)?;
+
+            let quadtree = &self.quadtree;
+            Ok(py.allow_threads(|| quadtree.line_of_sight_batch(&pairs, blocker_types)))
+        }
+
+        #[pyo3(signature = (max_distance, cancel_token=None))]
+        pub fn proximity_graph(
+            &self,
+            py: Python,
+            max_distance: Float,
+            cancel_token: Option<PyCancellationToken>,
+        ) -> PyResult<Vec<(u32, u32, Float)>> {
+            let token = cancel_token.map(|token| token.token).unwrap_or_default();
+            let quadtree = &self.quadtree;
+            py.allow_threads(|| quadtree.proximity_graph_cancellable(max_distance, &token))
+                .map_err(cancelled_error)
+        }
+
+        // Pairs of (id in this tree, id in other tree) whose shapes intersect, or are at
+        // most max_distance apart when it is given
+        #[pyo3(signature = (other, max_distance=None, cancel_token=None))]
+        pub fn spatial_join(
+            &self,
+            py: Python,
+            other: PyRef<QuadTreeWrapper>,
+            max_distance: Option<Float>,
+            cancel_token: Option<PyCancellationToken>,
+        ) -> PyResult<Vec<(u32, u32)>> {
+            let predicate = match max_distance {
+                Some(max_distance) => JoinPredicate::WithinDistance(max_distance),
+                None => JoinPredicate::Intersects,
+            };
+            let token = cancel_token.map(|token| token.token).unwrap_or_default();
+            let (quadtree, other) = (&self.quadtree, &other.quadtree);
+            py.allow_threads(|| quadtree.spatial_join_cancellable(other, predicate, &token))
+                .map_err(cancelled_error)
+        }
+
+        // Cluster labels per entry id, with -1 marking noise as in scikit-learn
+        pub fn cluster(&self, py: Python, eps: Float, min_points: usize) -> HashMap<u32, i64> {
+            let quadtree = &self.quadtree;
+            py.allow_threads(|| {
+                quadtree
+                    .cluster(eps, min_points)
+                    .into_iter()
+                    .map(|(value, label)| (value, label.map_or(-1, |label| label as i64)))
+                    .collect()
+            })
+        }
+
+        pub fn nearest_free_point(
+            &self,
+            x: Float,
+            y: Float,
+            clearance_radius: Float,
+        ) -> Option<(Float, Float)> {
+            self.quadtree
+                .nearest_free_point(Point::new(x, y), clearance_radius)
+                .map(|point| (point.x, point.y))
+        }
+
+        // Random location in the region with min_clearance of free space around it, or
+        // None if none was found. rng defaults to random.random.
+        #[pyo3(signature = (region, min_clearance, entity_types=None, rng=None))]
+        pub fn spawn_position(
+            &self,
+            py: Python,
+            region: PyObject,
+            min_clearance: Float,
+            entity_types: Option<Vec<u32>>,
+            rng: Option<PyObject>,
+        ) -> PyResult<Option<(Float, Float)>> {
+            let region = object_to_rectangle(py, &region)?;
+            let rng = match rng {
+                Some(rng) => rng,
+                None => py.import("random")?.getattr("random")?.into(),
+            };
+            // The first error raised by rng is reported once the search is over
+            let mut error = None;
+            let position = self.quadtree.spawn_position(
+                &region,
+                min_clearance,
+                entity_types.as_deref(),
+                || match rng.call0(py).and_then(|value| value.extract::<Float>(py)) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        error.get_or_insert(err);
+                        0.0
+                    }
+                },
+            );
+            match error {
+                Some(err) => Err(err),
+                None => Ok(position.map(|point| (point.x, point.y))),
+            }
         }
 
+        // Returns whether there was an entry with this value. Otherwise the shape is
+        // inserted as a new entry.
         pub fn relocate(
             &mut self,
             py: Python,
             value: u32,
             shape: PyObject,
             entity_type: Option<u32>,
-        ) -> PyResult<()> {
+        ) -> PyResult<bool> {
+            self.check_writable()?;
+            let shape = self.extract_shape(py, shape)?;
+            self.tree_mut()
+                .try_relocate(value, shape, entity_type)
+                .map_err(quadtree_error)
+        }
+
+        // Change the shape of an entry, keeping its entity type. Returns False if there is
+        // no entry with this value.
+        pub fn update(&mut self, py: Python, value: u32, shape: PyObject) -> PyResult<bool> {
+            self.check_writable()?;
             let shape = self.extract_shape(py, shape)?;
-            self.quadtree.relocate(value, shape, entity_type);
+            self.tree_mut()
+                .try_update(value, shape)
+                .map_err(quadtree_error)
+        }
+
+        pub fn rebalance(&mut self) -> PyResult<()> {
+            self.check_writable()?;
+            self.tree_mut().rebalance();
+            Ok(())
+        }
+
+        pub fn shrink_to_fit(&mut self) -> PyResult<()> {
+            self.check_writable()?;
+            self.tree_mut().shrink_to_fit();
             Ok(())
         }
 
@@ -186,32 +1003,304 @@ fn pyquadtree(_py: Python, m: &PyModule) -> PyResult<()> {
             py: Python,
             relocation_requests: Vec<&PyTuple>,
         ) -> PyResult<()> {
+            self.check_writable()?;
             // Convert the Python tuples into Rust RelocationRequest objects
             let requests: Vec<RelocationRequest> = relocation_requests
                 .into_iter()
                 .map(|tuple| {
-                    let value = tuple.get_item(0).unwrap().extract::<u32>().unwrap();
-                    let shape = self
-                        .extract_shape(py, tuple.get_item(1).unwrap().into())
-                        .unwrap();
-                    let entity_type: Option<u32> = match tuple.get_item(2).unwrap() {
-                        obj if obj.is_none() => None, // Check if it's a Python None
-                        obj => Some(obj.extract::<u32>().unwrap()),
-                    };
-                    RelocationRequest {
+                    let value = tuple.get_item(0)?.extract::<u32>()?;
+                    let shape = self.extract_shape(py, tuple.get_item(1)?.into())?;
+                    let entity_type = tuple.get_item(2)?.extract::<Option<u32>>()?;
+                    Ok(RelocationRequest {
                         value,
                         shape,
                         entity_type,
-                    }
+                    })
                 })
-                .collect();
+                .collect::<PyResult<Vec<_>>>()?;
 
-            self.quadtree.relocate_batch(requests);
+            self.tree_mut()
+                .try_relocate_batch(requests)
+                .map_err(quadtree_error)
+        }
 
+        pub fn expand_to(&mut self, py: Python, rectangle: PyObject) -> PyResult<()> {
+            self.check_writable()?;
+            let rectangle = object_to_rectangle(py, &rectangle)?;
+            self.tree_mut().expand_to(&rectangle);
             Ok(())
         }
 
-        pub fn all_node_bounding_boxes(&self) -> Vec<(f32, f32, f32, f32)> {
+        pub fn root_heavy_entities(&self, threshold_depth: usize) -> Vec<(u32, usize, String)> {
+            self.quadtree
+                .root_heavy_entities(threshold_depth)
+                .into_iter()
+                .map(|entity| {
+                    let reason = match entity.reason {
+                        RootHeavyReason::TooLarge => "too_large",
+                        RootHeavyReason::Straddling => "straddling",
+                        RootHeavyReason::OutOfBounds => "out_of_bounds",
+                        RootHeavyReason::Unsubdivided => "unsubdivided",
+                    };
+                    (entity.value, entity.depth, reason.to_string())
+                })
+                .collect()
+        }
+
+        pub fn record_root_heavy_counts(
+            &mut self,
+            threshold_depth: usize,
+        ) -> PyResult<HashMap<&'static str, usize>> {
+            self.check_writable()?;
+            let counts = self.tree_mut().record_root_heavy_counts(threshold_depth);
+            Ok(self.root_heavy_counts_dict(&counts))
+        }
+
+        pub fn root_heavy_history(&self) -> Vec<HashMap<&'static str, usize>> {
+            self.quadtree
+                .root_heavy_history()
+                .iter()
+                .map(|counts| self.root_heavy_counts_dict(counts))
+                .collect()
+        }
+
+        #[pyo3(signature = (as_dicts=false))]
+        pub fn shape_snapshot(
+            &self,
+            py: Python,
+            as_dicts: bool,
+        ) -> PyResult<HashMap<u32, PyObject>> {
+            self.quadtree
+                .shape_snapshot()
+                .iter()
+                .map(|(&value, shape)| Ok((value, shape_output(py, shape, as_dicts)?)))
+                .collect()
+        }
+
+        #[pyo3(signature = (previous, alpha, as_dicts=false))]
+        pub fn interpolated_shapes(
+            &self,
+            py: Python,
+            previous: HashMap<u32, PyObject>,
+            alpha: Float,
+            as_dicts: bool,
+        ) -> PyResult<Vec<(u32, PyObject)>> {
+            let previous = previous
+                .into_iter()
+                .map(|(value, shape)| Ok((value, self.extract_shape(py, shape)?)))
+                .collect::<PyResult<HashMap<u32, ShapeEnum>>>()?;
+            self.quadtree
+                .interpolated_shapes(&previous, alpha)
+                .iter()
+                .map(|(value, shape)| Ok((*value, shape_output(py, shape, as_dicts)?)))
+                .collect()
+        }
+
+        // One grid per level, each a list of rows of entry counts
+        pub fn density_pyramid(&self, levels: usize) -> Vec<Vec<Vec<usize>>> {
+            self.quadtree
+                .density_pyramid(levels)
+                .into_iter()
+                .map(|grid| {
+                    grid.counts
+                        .chunks(grid.resolution)
+                        .map(|row| row.to_vec())
+                        .collect()
+                })
+                .collect()
+        }
+
+        pub fn fragmentation_report<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+            let report = self.quadtree.fragmentation_report();
+            let dict = PyDict::new(py);
+            dict.set_item("arena_slots", report.arena_slots)?;
+            dict.set_item("live_nodes", report.live_nodes)?;
+            dict.set_item("arena_holes", report.arena_holes)?;
+            dict.set_item("hole_ratio", report.hole_ratio)?;
+            dict.set_item("pooled_nodes", report.pooled_nodes)?;
+            dict.set_item("leaf_count", report.leaf_count)?;
+            dict.set_item("empty_leaves", report.empty_leaves)?;
+            dict.set_item("mergeable_subtrees", report.mergeable_subtrees)?;
+            dict.set_item("empty_subtrees", report.empty_subtrees)?;
+            dict.set_item("reclaimable_nodes", report.reclaimable_nodes)?;
+            dict.set_item("wasted_capacity", report.wasted_capacity)?;
+            dict.set_item("optimize_recommended", report.optimize_recommended)?;
+            Ok(dict)
+        }
+
+        pub fn optimize(&mut self) -> PyResult<()> {
+            self.check_writable()?;
+            self.tree_mut().optimize();
+            Ok(())
+        }
+
+        pub fn stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+            let stats = self.quadtree.stats();
+            let dict = PyDict::new(py);
+            dict.set_item("node_count", stats.node_count)?;
+            dict.set_item("leaf_count", stats.leaf_count)?;
+            dict.set_item("entry_count", stats.entry_count)?;
+            dict.set_item("max_depth", stats.max_depth)?;
+            dict.set_item("average_depth", stats.average_depth)?;
+            dict.set_item("entries_per_node", stats.entries_per_node)?;
+            dict.set_item("pool_size", stats.pool_size)?;
+            dict.set_item("pooled_nodes", stats.pooled_nodes)?;
+            dict.set_item("pool_utilization", stats.pool_utilization)?;
+            dict.set_item("splits", stats.splits)?;
+            dict.set_item("merges", stats.merges)?;
+            Ok(dict)
+        }
+
+        // Record mutations and queries until stop_recording, which returns the trace as
+        // bytes that QuadTree.replay turns back into a tree, for attaching to bug reports
+        pub fn start_recording(&mut self) -> PyResult<()> {
+            self.check_writable()?;
+            self.tree_mut().start_recording();
+            Ok(())
+        }
+
+        pub fn stop_recording<'py>(&mut self, py: Python<'py>) -> PyResult<Option<&'py PyBytes>> {
+            self.check_writable()?;
+            let trace = match self.tree_mut().stop_recording() {
+                Some(trace) => trace,
+                None => return Ok(None),
+            };
+            let mut bytes = Vec::new();
+            trace
+                .write_to(&mut bytes)
+                .map_err(|err| PyValueError::new_err(err.to_string()))?;
+            Ok(Some(PyBytes::new(py, &bytes)))
+        }
+
+        #[staticmethod]
+        pub fn replay(trace: &[u8]) -> PyResult<Self> {
+            let trace = Trace::read_from(&mut &trace[..])
+                .map_err(|err| PyValueError::new_err(err.to_string()))?;
+            Ok(QuadTreeWrapper {
+                quadtree: Arc::new(replay(&trace)),
+                read_only: false,
+            })
+        }
+
+        // Operation counts since the previous call, which resets them. Raises TypeError on
+        // views, whose counters may still be those of the live tree.
+        pub fn take_counters<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+            self.check_writable()?;
+            let counters = self.quadtree.take_counters();
+            let dict = PyDict::new(py);
+            dict.set_item("inserts", counters.inserts)?;
+            dict.set_item("deletes", counters.deletes)?;
+            dict.set_item("relocates", counters.relocates)?;
+            dict.set_item("splits", counters.splits)?;
+            dict.set_item("collapses", counters.collapses)?;
+            dict.set_item("nodes_visited", counters.nodes_visited)?;
+            Ok(dict)
+        }
+
+        // Insert entries from parallel numpy arrays without building a Python object per
+        // shape. Coordinates may be float32 or float64, and ids and entity types any integer
+        // dtype. Passing radius makes circles, width and height make rectangles, and neither
+        // makes points. An entity type of -1 means no type. Raises ValueError for ids or
+        // entity types outside the u32 range, and KeyError for duplicate ids, as
+        // insert_batch does.
+        #[pyo3(signature = (ids, x, y, width=None, height=None, radius=None, entity_types=None))]
+        #[allow(clippy::too_many_arguments)]
+        pub fn insert_batch_numpy(
+            &mut self,
+            ids: &PyAny,
+            x: &PyAny,
+            y: &PyAny,
+            width: Option<&PyAny>,
+            height: Option<&PyAny>,
+            radius: Option<&PyAny>,
+            entity_types: Option<&PyAny>,
+        ) -> PyResult<()> {
+            self.check_writable()?;
+            let ids = ids_from_array(ids)?;
+            let shapes = shapes_from_arrays(x, y, width, height, radius)?;
+            let entity_types = entity_types_from_array(entity_types, ids.len())?;
+            check_length(shapes.len(), ids.len())?;
+
+            let items = ids
+                .into_iter()
+                .zip(shapes)
+                .zip(entity_types)
+                .map(|((value, shape), entity_type)| (value, shape, entity_type))
+                .collect::<Vec<_>>();
+            self.tree_mut()
+                .try_insert_batch(items)
+                .map_err(quadtree_error)
+        }
+
+        // Relocate entries from parallel numpy arrays, laid out as for insert_batch_numpy
+        #[pyo3(signature = (ids, x, y, width=None, height=None, radius=None, entity_types=None))]
+        #[allow(clippy::too_many_arguments)]
+        pub fn relocate_batch_numpy(
+            &mut self,
+            ids: &PyAny,
+            x: &PyAny,
+            y: &PyAny,
+            width: Option<&PyAny>,
+            height: Option<&PyAny>,
+            radius: Option<&PyAny>,
+            entity_types: Option<&PyAny>,
+        ) -> PyResult<()> {
+            self.check_writable()?;
+            let ids = ids_from_array(ids)?;
+            let shapes = shapes_from_arrays(x, y, width, height, radius)?;
+            let entity_types = entity_types_from_array(entity_types, ids.len())?;
+            check_length(shapes.len(), ids.len())?;
+
+            let requests = ids
+                .into_iter()
+                .zip(shapes)
+                .zip(entity_types)
+                .map(|((value, shape), entity_type)| RelocationRequest {
+                    value,
+                    shape,
+                    entity_type,
+                })
+                .collect::<Vec<_>>();
+            self.tree_mut()
+                .try_relocate_batch(requests)
+                .map_err(quadtree_error)
+        }
+
+        // Run a query per row of the numpy arrays. Results come back flattened as
+        // (offsets, ids), where the hits of query i are ids[offsets[i]:offsets[i + 1]].
+        #[pyo3(signature = (x, y, width=None, height=None, radius=None, entity_types=None))]
+        #[allow(clippy::too_many_arguments)]
+        pub fn collisions_batch_numpy<'py>(
+            &self,
+            py: Python<'py>,
+            x: &PyAny,
+            y: &PyAny,
+            width: Option<&PyAny>,
+            height: Option<&PyAny>,
+            radius: Option<&PyAny>,
+            entity_types: Option<Vec<u32>>,
+        ) -> PyResult<(&'py PyArray1<u64>, &'py PyArray1<u32>)> {
+            let shapes = shapes_from_arrays(x, y, width, height, radius)?;
+
+            let quadtree = &self.quadtree;
+            let results = py.allow_threads(|| {
+                let collisions = quadtree.collisions_batch_filter(shapes, entity_types);
+                let mut offsets = Vec::with_capacity(collisions.len() + 1);
+                offsets.push(0);
+                let mut ids = Vec::new();
+                for hits in collisions {
+                    ids.extend(hits);
+                    offsets.push(ids.len() as u64);
+                }
+                (offsets, ids)
+            });
+            Ok((
+                PyArray1::from_vec(py, results.0),
+                PyArray1::from_vec(py, results.1),
+            ))
+        }
+
+        pub fn all_node_bounding_boxes(&self) -> Vec<(Float, Float, Float, Float)> {
             let mut bounding_boxes = Vec::new();
             self.quadtree.all_node_bounding_boxes(&mut bounding_boxes);
             bounding_boxes
@@ -220,61 +1309,140 @@ fn pyquadtree(_py: Python, m: &PyModule) -> PyResult<()> {
                 .collect()
         }
 
-        pub fn all_shapes(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        #[pyo3(signature = (as_dicts=false))]
+        pub fn all_shapes(&self, py: Python, as_dicts: bool) -> PyResult<Vec<PyObject>> {
             let mut shapes = Vec::new();
             self.quadtree.all_shapes(&mut shapes);
-            let mut py_shapes = Vec::new();
-            for shape in shapes {
-                let py_shape = if let Some(circle) = shape.as_any().downcast_ref::<Circle>() {
-                    Py::new(
-                        py,
-                        PyCircle {
-                            x: circle.x,
-                            y: circle.y,
-                            radius: circle.radius,
-                        },
-                    )?
-                    .into_py(py)
-                } else if let Some(rect) = shape.as_any().downcast_ref::<Rectangle>() {
-                    Py::new(
-                        py,
-                        PyRectangle {
-                            x: rect.x,
-                            y: rect.y,
-                            width: rect.width,
-                            height: rect.height,
-                        },
-                    )?
-                    .into_py(py)
-                } else {
-                    return Err(PyTypeError::new_err("Unknown shape"));
-                };
-                py_shapes.push(py_shape);
+            shapes
+                .iter()
+                .map(|shape| shape_output(py, shape, as_dicts))
+                .collect()
+        }
+
+        // Generator over (value, shape, entity_type) for every entry
+        #[pyo3(signature = (as_dicts=false))]
+        pub fn iter(slf: PyRef<Self>, as_dicts: bool) -> EntryIterator {
+            EntryIterator {
+                cursor: slf.quadtree.cursor(),
+                tree: slf.into(),
+                buffer: Vec::new(),
+                as_dicts,
+            }
+        }
+
+        // Generator over the entries whose shapes intersect a region
+        #[pyo3(signature = (region, as_dicts=false))]
+        pub fn iter_in_region(
+            slf: PyRef<Self>,
+            region: PyObject,
+            as_dicts: bool,
+        ) -> PyResult<EntryIterator> {
+            let region = object_to_rectangle(slf.py(), &region)?;
+            Ok(EntryIterator {
+                cursor: slf.quadtree.cursor_in_region(region),
+                tree: slf.into(),
+                buffer: Vec::new(),
+                as_dicts,
+            })
+        }
+
+        // Generator over (depth, (x, y, width, height), entity_count, is_leaf) per node
+        pub fn nodes(slf: PyRef<Self>) -> NodeIterator {
+            NodeIterator {
+                cursor: slf.quadtree.cursor(),
+                tree: slf.into(),
             }
-            Ok(py_shapes)
+        }
+    }
+
+    // Walks the tree a node at a time, so entries are only converted as they are consumed
+    #[pyclass]
+    struct EntryIterator {
+        tree: Py<QuadTreeWrapper>,
+        cursor: Cursor,
+        buffer: Vec<(u32, ShapeEnum, Option<u32>)>,
+        as_dicts: bool,
+    }
+
+    #[pymethods]
+    impl EntryIterator {
+        fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+            slf
+        }
+
+        fn __next__(&mut self, py: Python) -> PyResult<Option<(u32, PyObject, Option<u32>)>> {
+            while self.buffer.is_empty() {
+                let tree = self.tree.borrow(py);
+                if tree
+                    .quadtree
+                    .advance_cursor(&mut self.cursor, Some(&mut self.buffer))
+                    .is_none()
+                {
+                    return Ok(None);
+                }
+            }
+            match self.buffer.pop() {
+                Some((value, shape, entity_type)) => {
+                    let shape = shape_output(py, &shape, self.as_dicts)?;
+                    Ok(Some((value, shape, entity_type)))
+                }
+                None => Ok(None),
+            }
+        }
+    }
+
+    #[pyclass]
+    struct NodeIterator {
+        tree: Py<QuadTreeWrapper>,
+        cursor: Cursor,
+    }
+
+    #[pymethods]
+    impl NodeIterator {
+        fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+            slf
+        }
+
+        fn __next__(&mut self, py: Python) -> Option<NodeTuple> {
+            let tree = self.tree.borrow(py);
+            tree.quadtree
+                .advance_cursor(&mut self.cursor, None)
+                .map(|node| {
+                    let rect = node.bounding_box;
+                    (
+                        node.depth,
+                        (rect.x, rect.y, rect.width, rect.height),
+                        node.entity_count,
+                        node.is_leaf,
+                    )
+                })
         }
     }
 
     impl QuadTreeWrapper {
-        fn extract_shape(&self, py: Python, shape: PyObject) -> PyResult<ShapeEnum> {
-            if let Ok(py_rectangle) = shape.extract::<PyRectangle>(py) {
-                Ok(ShapeEnum::Rectangle(Rectangle {
-                    x: py_rectangle.x,
-                    y: py_rectangle.y,
-                    width: py_rectangle.width,
-                    height: py_rectangle.height,
-                }))
-            } else if let Ok(py_circle) = shape.extract::<PyCircle>(py) {
-                Ok(ShapeEnum::Circle(Circle::new(
-                    py_circle.x,
-                    py_circle.y,
-                    py_circle.radius,
-                )))
-            } else {
-                Err(PyTypeError::new_err(
-                    "Expected a Rectangle or Circle object",
-                ))
+        fn check_writable(&self) -> PyResult<()> {
+            if self.read_only {
+                return Err(PyTypeError::new_err("QuadTree views are read-only"));
             }
+            Ok(())
+        }
+
+        fn tree_mut(&mut self) -> &mut QuadTree {
+            QuadTree::make_mut(&mut self.quadtree)
+        }
+
+        fn extract_shape(&self, py: Python, shape: PyObject) -> PyResult<ShapeEnum> {
+            object_to_shape(py, &shape)
+        }
+
+        fn root_heavy_counts_dict(&self, counts: &RootHeavyCounts) -> HashMap<&'static str, usize> {
+            HashMap::from([
+                ("total", counts.total),
+                ("too_large", counts.too_large),
+                ("straddling", counts.straddling),
+                ("out_of_bounds", counts.out_of_bounds),
+                ("unsubdivided", counts.unsubdivided),
+            ])
         }
 
         fn extract_entity_types(
@@ -294,9 +1462,17 @@ fn pyquadtree(_py: Python, m: &PyModule) -> PyResult<()> {
         }
     }
 
+    // Number of bits in the coordinate type this module was built with (32 or 64)
+    m.add("COORDINATE_BITS", std::mem::size_of::<Float>() * 8)?;
     m.add_class::<QuadTreeWrapper>()?;
     m.add_class::<PyCircle>()?;
     m.add_class::<PyRectangle>()?;
+    m.add_class::<PyPoint>()?;
     m.add_class::<PyConfig>()?;
+    m.add_class::<PyCancellationToken>()?;
+    m.add_class::<PyRegionLock>()?;
+    m.add("QueryCancelled", _py.get_type::<QueryCancelled>())?;
+    m.add("RegionLockedError", _py.get_type::<RegionLockedError>())?;
+    m.add_function(wrap_pyfunction!(lerp, m)?)?;
     Ok(())
 }
@@ -1,5 +1,5 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use quadtree::quadtree::{QuadTree, RelocationRequest};
+use quadtree::quadtree::{Config, QuadTree, RelocationRequest};
 use quadtree::shapes::{Rectangle, ShapeEnum};
 use rand::prelude::*;
 
@@ -72,7 +72,11 @@ fn relocate_benchmark(c: &mut Criterion) {
         });
         let value = rng.gen();
         quadtree.insert(value, shape.clone(), None);
-        relocation_requests.push(RelocationRequest { value, shape, entity_type: None });
+        relocation_requests.push(RelocationRequest {
+            value,
+            shape,
+            entity_type: None,
+        });
     }
 
     c.bench_function("quadtree_relocate", |b| {
@@ -82,6 +86,53 @@ fn relocate_benchmark(c: &mut Criterion) {
     });
 }
 
+fn relocate_loose_benchmark(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let bounding_box = Rectangle {
+        x: 0.0,
+        y: 0.0,
+        width: 100.0,
+        height: 100.0,
+    };
+    let config = Config {
+        looseness: 2.0,
+        ..Default::default()
+    };
+    let mut quadtree = QuadTree::new_with_config(bounding_box, config);
+    let mut positions = Vec::new();
+    for _ in 0..1000 {
+        let x = rng.gen_range(0.0..100.0);
+        let y = rng.gen_range(0.0..100.0);
+        let value = rng.gen();
+        quadtree.insert(
+            value,
+            ShapeEnum::Rectangle(Rectangle::new(x, y, 5.0, 5.0)),
+            None,
+        );
+        positions.push((value, x, y));
+    }
+
+    c.bench_function("quadtree_relocate_loose", |b| {
+        b.iter(|| {
+            // Move every entity a small step, as happens each frame in a simulation
+            let relocation_requests = positions
+                .iter()
+                .map(|&(value, x, y)| RelocationRequest {
+                    value,
+                    shape: ShapeEnum::Rectangle(Rectangle::new(
+                        x + rng.gen_range(-1.0..1.0),
+                        y + rng.gen_range(-1.0..1.0),
+                        5.0,
+                        5.0,
+                    )),
+                    entity_type: None,
+                })
+                .collect();
+            quadtree.relocate_batch(black_box(relocation_requests));
+        })
+    });
+}
+
 fn collisions_benchmark(c: &mut Criterion) {
     let mut rng = rand::thread_rng();
     let mut quadtree = QuadTree::new(Rectangle {
@@ -117,11 +168,50 @@ fn collisions_benchmark(c: &mut Criterion) {
     });
 }
 
+fn collisions_batch_benchmark(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let mut quadtree = QuadTree::new(Rectangle {
+        x: 0.0,
+        y: 0.0,
+        width: 1000.0,
+        height: 1000.0,
+    });
+    for _ in 0..10000 {
+        let shape = ShapeEnum::Rectangle(Rectangle {
+            x: rng.gen_range(0.0..1000.0),
+            y: rng.gen_range(0.0..1000.0),
+            width: 5.0,
+            height: 5.0,
+        });
+        quadtree.insert(rng.gen(), shape, None);
+    }
+
+    // Define a batch of query rectangles
+    let query_shapes: Vec<ShapeEnum> = (0..10000)
+        .map(|_| {
+            ShapeEnum::Rectangle(Rectangle {
+                x: rng.gen_range(0.0..1000.0),
+                y: rng.gen_range(0.0..1000.0),
+                width: 20.0,
+                height: 20.0,
+            })
+        })
+        .collect();
+
+    c.bench_function("quadtree_collisions_batch", |b| {
+        b.iter(|| {
+            quadtree.collisions_batch(black_box(query_shapes.clone()));
+        })
+    });
+}
+
 criterion_group!(
     quadtree_benchmarks,
     insert_benchmark,
     delete_benchmark,
     relocate_benchmark,
-    collisions_benchmark
+    relocate_loose_benchmark,
+    collisions_benchmark,
+    collisions_batch_benchmark
 );
 criterion_main!(quadtree_benchmarks);
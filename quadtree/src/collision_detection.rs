@@ -1,4 +1,4 @@
-use crate::shapes::{Circle, Rectangle, ShapeEnum};
+use crate::shapes::{Circle, Float, Point, Rectangle, Shape, ShapeEnum};
 
 // Check that Rectangle inner is fully contained in Rectangle outer, including on the boundary
 pub fn rectangle_contains_rectangle(outer: &Rectangle, inner: &Rectangle) -> bool {
@@ -12,6 +12,11 @@ pub fn rectangle_rectangle(a: &Rectangle, b: &Rectangle) -> bool {
     a.x < b.right() && a.right() > b.x && a.y < b.bottom() && a.bottom() > b.y
 }
 
+// Like rectangle_rectangle, but rectangles that only share an edge also count as touching
+pub fn rectangle_touches_rectangle(a: &Rectangle, b: &Rectangle) -> bool {
+    a.x <= b.right() && a.right() >= b.x && a.y <= b.bottom() && a.bottom() >= b.y
+}
+
 pub fn circle_circle(a: &Circle, b: &Circle) -> bool {
     let dx = a.x - b.x;
     let dy = a.y - b.y;
@@ -44,6 +49,22 @@ pub fn circle_rectangle(circle: &Circle, rectangle: &Rectangle) -> bool {
     corner_distance_sq <= circle.radius * circle.radius
 }
 
+// Points are tested inclusively, so a point on the edge of a shape is inside it
+pub fn point_rectangle(point: &Point, rectangle: &Rectangle) -> bool {
+    rectangle.x <= point.x
+        && point.x <= rectangle.right()
+        && rectangle.y <= point.y
+        && point.y <= rectangle.bottom()
+}
+
+pub fn point_circle(point: &Point, circle: &Circle) -> bool {
+    point.distance_squared(&Point::new(circle.x, circle.y)) <= circle.radius * circle.radius
+}
+
+pub fn point_point(a: &Point, b: &Point) -> bool {
+    a == b
+}
+
 pub fn shape_shape(a: &ShapeEnum, b: &ShapeEnum) -> bool {
     match (a, b) {
         (ShapeEnum::Circle(circle_a), ShapeEnum::Circle(circle_b)) => {
@@ -56,5 +77,299 @@ pub fn shape_shape(a: &ShapeEnum, b: &ShapeEnum) -> bool {
         (ShapeEnum::Rectangle(rectangle_a), ShapeEnum::Rectangle(rectangle_b)) => {
             rectangle_rectangle(rectangle_a, rectangle_b)
         }
+        (ShapeEnum::Point(point), ShapeEnum::Rectangle(rectangle))
+        | (ShapeEnum::Rectangle(rectangle), ShapeEnum::Point(point)) => {
+            point_rectangle(point, rectangle)
+        }
+        (ShapeEnum::Point(point), ShapeEnum::Circle(circle))
+        | (ShapeEnum::Circle(circle), ShapeEnum::Point(point)) => point_circle(point, circle),
+        (ShapeEnum::Point(point_a), ShapeEnum::Point(point_b)) => point_point(point_a, point_b),
+    }
+}
+
+// Distance between the closest points of two shapes, or zero if they overlap
+pub fn rectangle_rectangle_distance(a: &Rectangle, b: &Rectangle) -> Float {
+    let dx = (a.x - b.right()).max(b.x - a.right()).max(0.0);
+    let dy = (a.y - b.bottom()).max(b.y - a.bottom()).max(0.0);
+    (dx * dx + dy * dy).sqrt()
+}
+
+pub fn circle_circle_distance(a: &Circle, b: &Circle) -> Float {
+    let dx = a.x - b.x;
+    let dy = a.y - b.y;
+    ((dx * dx + dy * dy).sqrt() - a.radius - b.radius).max(0.0)
+}
+
+pub fn circle_rectangle_distance(circle: &Circle, rectangle: &Rectangle) -> Float {
+    let center = Rectangle::new(circle.x, circle.y, 0.0, 0.0);
+    (rectangle_rectangle_distance(&center, rectangle) - circle.radius).max(0.0)
+}
+
+pub fn shape_shape_distance(a: &ShapeEnum, b: &ShapeEnum) -> Float {
+    match (a, b) {
+        (ShapeEnum::Circle(circle_a), ShapeEnum::Circle(circle_b)) => {
+            circle_circle_distance(circle_a, circle_b)
+        }
+        (ShapeEnum::Circle(circle), ShapeEnum::Rectangle(rectangle))
+        | (ShapeEnum::Rectangle(rectangle), ShapeEnum::Circle(circle)) => {
+            circle_rectangle_distance(circle, rectangle)
+        }
+        (ShapeEnum::Rectangle(rectangle_a), ShapeEnum::Rectangle(rectangle_b)) => {
+            rectangle_rectangle_distance(rectangle_a, rectangle_b)
+        }
+        (ShapeEnum::Point(point), ShapeEnum::Rectangle(rectangle))
+        | (ShapeEnum::Rectangle(rectangle), ShapeEnum::Point(point)) => {
+            rectangle_rectangle_distance(&point.bounding_box(), rectangle)
+        }
+        (ShapeEnum::Point(point), ShapeEnum::Circle(circle))
+        | (ShapeEnum::Circle(circle), ShapeEnum::Point(point)) => {
+            let center = Point::new(circle.x, circle.y);
+            (point.distance_squared(&center).sqrt() - circle.radius).max(0.0)
+        }
+        (ShapeEnum::Point(point_a), ShapeEnum::Point(point_b)) => {
+            point_a.distance_squared(point_b).sqrt()
+        }
+    }
+}
+
+// Check whether the segment from start to end passes through Rectangle
+pub fn segment_rectangle(start: &Point, end: &Point, rectangle: &Rectangle) -> bool {
+    ray_rectangle(start, end.x - start.x, end.y - start.y, rectangle, false).is_some()
+}
+
+// Like segment_rectangle, but a segment that only touches the boundary counts, so
+// rectangles without area can still be hit
+pub fn segment_touches_rectangle(start: &Point, end: &Point, rectangle: &Rectangle) -> bool {
+    ray_rectangle(start, end.x - start.x, end.y - start.y, rectangle, true).is_some()
+}
+
+// Fraction of the motion from origin by (dx, dy) after which the point enters Rectangle,
+// using the slab method. A point already inside enters at 0. When closed is set, reaching
+// the boundary counts as entering.
+fn ray_rectangle(
+    origin: &Point,
+    dx: Float,
+    dy: Float,
+    rectangle: &Rectangle,
+    closed: bool,
+) -> Option<Float> {
+    let mut t_min: Float = 0.0;
+    let mut t_max: Float = 1.0;
+    let axes = [
+        (origin.x, dx, rectangle.x, rectangle.right()),
+        (origin.y, dy, rectangle.y, rectangle.bottom()),
+    ];
+    for (origin, delta, low, high) in axes {
+        if delta == 0.0 {
+            let outside = if closed {
+                origin < low || origin > high
+            } else {
+                origin <= low || origin >= high
+            };
+            if outside {
+                return None;
+            }
+            continue;
+        }
+        let t1 = (low - origin) / delta;
+        let t2 = (high - origin) / delta;
+        t_min = t_min.max(t1.min(t2));
+        t_max = t_max.min(t1.max(t2));
+        if t_min > t_max || (t_min == t_max && !closed) {
+            return None;
+        }
+    }
+    Some(t_min)
+}
+
+// Like ray_rectangle, for the circle of the given radius around center
+fn ray_circle(
+    origin: &Point,
+    dx: Float,
+    dy: Float,
+    center: &Point,
+    radius: Float,
+    closed: bool,
+) -> Option<Float> {
+    let mx = origin.x - center.x;
+    let my = origin.y - center.y;
+    let c = mx * mx + my * my - radius * radius;
+    if c < 0.0 || (c == 0.0 && closed) {
+        return Some(0.0);
+    }
+    let a = dx * dx + dy * dy;
+    let b = mx * dx + my * dy;
+    let discriminant = b * b - a * c;
+    if a == 0.0 || discriminant < 0.0 || (discriminant == 0.0 && !closed) {
+        return None;
+    }
+    let t = (-b - discriminant.sqrt()) / a;
+    let reached = if closed { t <= 1.0 } else { t < 1.0 };
+    (t >= 0.0 && reached).then_some(t)
+}
+
+// Like ray_rectangle, for Rectangle grown by radius on every side with rounded corners.
+// The rounded shape is the union of two grown rectangles and four corner circles.
+fn ray_rounded_rectangle(
+    origin: &Point,
+    dx: Float,
+    dy: Float,
+    rectangle: &Rectangle,
+    radius: Float,
+    closed: bool,
+) -> Option<Float> {
+    let wide = Rectangle::new(
+        rectangle.x - radius,
+        rectangle.y,
+        rectangle.width + radius * 2.0,
+        rectangle.height,
+    );
+    let tall = Rectangle::new(
+        rectangle.x,
+        rectangle.y - radius,
+        rectangle.width,
+        rectangle.height + radius * 2.0,
+    );
+    let corners = [
+        Point::new(rectangle.x, rectangle.y),
+        Point::new(rectangle.right(), rectangle.y),
+        Point::new(rectangle.x, rectangle.bottom()),
+        Point::new(rectangle.right(), rectangle.bottom()),
+    ];
+    [
+        ray_rectangle(origin, dx, dy, &wide, closed),
+        ray_rectangle(origin, dx, dy, &tall, closed),
+    ]
+    .into_iter()
+    .chain(
+        corners
+            .iter()
+            .map(|corner| ray_circle(origin, dx, dy, corner, radius, closed)),
+    )
+    .flatten()
+    .reduce(Float::min)
+}
+
+// Fraction of the motion by (dx, dy) after which the moving shape first overlaps the
+// static one, or None if they never do. Shapes that already overlap meet at 0. The
+// shapes are reduced to a moving point against their Minkowski sum: a grown rectangle
+// for two rectangles, a grown circle for two circles, and a rounded rectangle for a
+// circle and a rectangle. Points are treated as circles without radius.
+pub fn shape_shape_time_of_impact(
+    moving: &ShapeEnum,
+    dx: Float,
+    dy: Float,
+    other: &ShapeEnum,
+) -> Option<Float> {
+    time_of_impact(moving, dx, dy, other, false)
+}
+
+// Like shape_shape_time_of_impact, but shapes meet as soon as their boundaries touch, so
+// shapes without area can still be hit
+pub fn shape_shape_time_of_touch(
+    moving: &ShapeEnum,
+    dx: Float,
+    dy: Float,
+    other: &ShapeEnum,
+) -> Option<Float> {
+    time_of_impact(moving, dx, dy, other, true)
+}
+
+fn time_of_impact(
+    moving: &ShapeEnum,
+    dx: Float,
+    dy: Float,
+    other: &ShapeEnum,
+    closed: bool,
+) -> Option<Float> {
+    match (moving, other) {
+        (ShapeEnum::Point(point), _) => {
+            let circle = ShapeEnum::Circle(Circle::new(point.x, point.y, 0.0));
+            time_of_impact(&circle, dx, dy, other, closed)
+        }
+        (_, ShapeEnum::Point(point)) => {
+            let circle = ShapeEnum::Circle(Circle::new(point.x, point.y, 0.0));
+            time_of_impact(moving, dx, dy, &circle, closed)
+        }
+        (ShapeEnum::Rectangle(a), ShapeEnum::Rectangle(b)) => {
+            let grown = Rectangle::new(
+                b.x - a.width,
+                b.y - a.height,
+                a.width + b.width,
+                a.height + b.height,
+            );
+            ray_rectangle(&Point::new(a.x, a.y), dx, dy, &grown, closed)
+        }
+        (ShapeEnum::Circle(a), ShapeEnum::Circle(b)) => ray_circle(
+            &Point::new(a.x, a.y),
+            dx,
+            dy,
+            &Point::new(b.x, b.y),
+            a.radius + b.radius,
+            closed,
+        ),
+        (ShapeEnum::Circle(circle), ShapeEnum::Rectangle(rectangle)) => ray_rounded_rectangle(
+            &Point::new(circle.x, circle.y),
+            dx,
+            dy,
+            rectangle,
+            circle.radius,
+            closed,
+        ),
+        // Seen from the rectangle, the circle moves the opposite way
+        (ShapeEnum::Rectangle(rectangle), ShapeEnum::Circle(circle)) => ray_rounded_rectangle(
+            &Point::new(circle.x, circle.y),
+            -dx,
+            -dy,
+            rectangle,
+            circle.radius,
+            closed,
+        ),
+    }
+}
+
+// Squared distance from a point to the closest point of the segment from start to end
+fn segment_distance_squared(start: &Point, end: &Point, point: &Point) -> Float {
+    let dx = end.x - start.x;
+    let dy = end.y - start.y;
+    let length_sq = dx * dx + dy * dy;
+    let t = if length_sq > 0.0 {
+        (((point.x - start.x) * dx + (point.y - start.y) * dy) / length_sq).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let closest = Point::new(start.x + dx * t, start.y + dy * t);
+    closest.distance_squared(point)
+}
+
+// Check whether the segment from start to end passes through Circle
+pub fn segment_circle(start: &Point, end: &Point, circle: &Circle) -> bool {
+    let center = Point::new(circle.x, circle.y);
+    segment_distance_squared(start, end, &center) < circle.radius * circle.radius
+}
+
+// Check whether the segment from start to end passes exactly through Point
+pub fn segment_point(start: &Point, end: &Point, point: &Point) -> bool {
+    segment_distance_squared(start, end, point) == 0.0
+}
+
+pub fn segment_shape(start: &Point, end: &Point, shape: &ShapeEnum) -> bool {
+    match shape {
+        ShapeEnum::Circle(circle) => segment_circle(start, end, circle),
+        ShapeEnum::Rectangle(rectangle) => segment_rectangle(start, end, rectangle),
+        ShapeEnum::Point(point) => segment_point(start, end, point),
+    }
+}
+
+// Like segment_shape, but a segment that only touches the boundary counts, so shapes
+// without area can still block it
+pub fn segment_touches_shape(start: &Point, end: &Point, shape: &ShapeEnum) -> bool {
+    match shape {
+        ShapeEnum::Circle(circle) => {
+            let center = Point::new(circle.x, circle.y);
+            segment_distance_squared(start, end, &center) <= circle.radius * circle.radius
+        }
+        ShapeEnum::Rectangle(rectangle) => segment_touches_rectangle(start, end, rectangle),
+        ShapeEnum::Point(point) => segment_point(start, end, point),
     }
 }
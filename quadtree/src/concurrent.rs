@@ -0,0 +1,40 @@
+use crate::quadtree::QuadTree;
+
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// A QuadTree shared between threads, such as the workers of a server. Any number of
+// readers can run queries at once, while a writer applying relocations between frames
+// waits for them to finish and holds new ones off until it is done. Clones share the
+// same tree, so one can be handed to each worker.
+#[derive(Clone)]
+pub struct ConcurrentQuadTree {
+    tree: Arc<RwLock<QuadTree>>,
+}
+
+impl ConcurrentQuadTree {
+    pub fn new(tree: QuadTree) -> Self {
+        Self {
+            tree: Arc::new(RwLock::new(tree)),
+        }
+    }
+
+    // Shared access for queries, blocking while a writer holds the tree
+    pub fn read(&self) -> RwLockReadGuard<'_, QuadTree> {
+        self.tree
+            .read()
+            .expect("QuadTree lock should not be poisoned by a panicking writer")
+    }
+
+    // Exclusive access for mutations, blocking until every reader is done
+    pub fn write(&self) -> RwLockWriteGuard<'_, QuadTree> {
+        self.tree
+            .write()
+            .expect("QuadTree lock should not be poisoned by a panicking writer")
+    }
+}
+
+impl From<QuadTree> for ConcurrentQuadTree {
+    fn from(tree: QuadTree) -> Self {
+        Self::new(tree)
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Operation counts since the counters were last taken, for graphing the load on the index
+// per frame. Relocations that move an entry to another size class, or that insert an
+// entry that was missing, also count the delete and insert they perform.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperationCounters {
+    pub inserts: u64,
+    pub deletes: u64,
+    pub relocates: u64,
+    // Nodes subdivided into quadrants
+    pub splits: u64,
+    // Subtrees merged back into their parent
+    pub collapses: u64,
+    // Nodes searched by collision, line of sight and swept queries
+    pub nodes_visited: u64,
+}
+
+// Atomic so that queries, which only borrow the tree, can count from several threads
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub inserts: AtomicU64,
+    pub deletes: AtomicU64,
+    pub relocates: AtomicU64,
+    pub splits: AtomicU64,
+    pub collapses: AtomicU64,
+    pub nodes_visited: AtomicU64,
+}
+
+impl Counters {
+    pub fn add(counter: &AtomicU64, count: u64) {
+        counter.fetch_add(count, Ordering::Relaxed);
+    }
+
+    // Read every counter and reset it to zero
+    pub fn take(&self) -> OperationCounters {
+        let take = |counter: &AtomicU64| counter.swap(0, Ordering::Relaxed);
+        OperationCounters {
+            inserts: take(&self.inserts),
+            deletes: take(&self.deletes),
+            relocates: take(&self.relocates),
+            splits: take(&self.splits),
+            collapses: take(&self.collapses),
+            nodes_visited: take(&self.nodes_visited),
+        }
+    }
+
+    // Add counts taken from another tree, such as the one a copy-on-write copy replaces
+    pub fn absorb(&self, counts: OperationCounters) {
+        Self::add(&self.inserts, counts.inserts);
+        Self::add(&self.deletes, counts.deletes);
+        Self::add(&self.relocates, counts.relocates);
+        Self::add(&self.splits, counts.splits);
+        Self::add(&self.collapses, counts.collapses);
+        Self::add(&self.nodes_visited, counts.nodes_visited);
+    }
+}
@@ -0,0 +1,36 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuadTreeError {
+    // The shape given for this value has zero area or radius, and the tree is
+    // configured with DegenerateShapeMode::ErrorOnInsert
+    DegenerateShape(u32),
+    // An entry with this value is already in the tree
+    AlreadyExists(u32),
+    // No template was registered under this id
+    UnknownTemplate(u32),
+    // The entry's current or new shape lies in a region held by a RegionLock
+    RegionLocked(u32),
+}
+
+impl fmt::Display for QuadTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuadTreeError::DegenerateShape(value) => {
+                write!(f, "shape for {} has zero area or radius", value)
+            }
+            QuadTreeError::AlreadyExists(value) => {
+                write!(f, "an entry for {} already exists", value)
+            }
+            QuadTreeError::UnknownTemplate(template) => {
+                write!(f, "no template with id {}", template)
+            }
+            QuadTreeError::RegionLocked(value) => {
+                write!(f, "entry {} is in a locked region", value)
+            }
+        }
+    }
+}
+
+impl Error for QuadTreeError {}
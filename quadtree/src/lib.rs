@@ -1,4 +1,10 @@
+pub mod cancellation;
 pub mod collision_detection;
+pub mod concurrent;
+pub mod counters;
+pub mod error;
 pub mod object_pool;
 pub mod quadtree;
+pub mod region_lock;
 pub mod shapes;
+pub mod trace;
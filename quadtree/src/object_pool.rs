@@ -20,10 +20,7 @@ where
     where
         T: Default,
     {
-        match self.pool.pop() {
-            Some(obj) => obj,
-            None => T::default(),
-        }
+        self.pool.pop().unwrap_or_default()
     }
 
     // Return an object to the pool if the pool is not full, otherwise discard the object
@@ -35,6 +32,19 @@ where
         }
     }
 
+    // Number of objects waiting in the pool to be reused
+    pub fn len(&self) -> usize {
+        self.pool.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pool.is_empty()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
     // Clear all objects from the pool
     pub fn clear(&mut self) {
         self.pool.clear();
@@ -1,45 +1,160 @@
+use crate::cancellation::{CancellationToken, Cancelled};
 use crate::collision_detection;
+use crate::counters::{Counters, OperationCounters};
+use crate::error::QuadTreeError;
 use crate::object_pool::{ObjectPool, Resettable};
-use crate::shapes::{Rectangle, Shape, ShapeEnum};
-
-use std::cell::Ref;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::ops::Deref;
-use std::rc::Rc;
-use std::rc::Weak;
-
+use crate::region_lock::{RegionLock, RegionLocks};
+use crate::shapes::{Circle, Float, Point, Rectangle, Shape, ShapeEnum};
+use crate::trace::{Recorder, Trace, TraceEvent, TraceFilter};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{hash_map, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+// Nodes live in an arena owned by the QuadTree and refer to each other by index.
+// This keeps the tree free of shared ownership, so it can be queried from several threads.
+type NodeId = usize;
+
+// An entry as stored in a node. The shape is kept in a compact form and rebuilt when
+// needed, so entries do not pay for the bounding box Circle caches, and a point entry
+// takes no more room than a rectangle.
 #[derive(Clone)]
 struct Entity {
-    shape: ShapeEnum,
+    shape: StoredShape,
     entity_type: Option<u32>,
 }
 
+impl Entity {
+    fn new(shape: ShapeEnum, entity_type: Option<u32>) -> Self {
+        Entity {
+            shape: StoredShape::new(shape),
+            entity_type,
+        }
+    }
+
+    fn shape(&self) -> ShapeEnum {
+        match self.shape {
+            StoredShape::Circle { x, y, radius } => ShapeEnum::Circle(Circle::new(x, y, radius)),
+            StoredShape::Rectangle(rectangle) => ShapeEnum::Rectangle(rectangle),
+            StoredShape::Point(point) => ShapeEnum::Point(point),
+        }
+    }
+
+    fn bounding_box(&self) -> Rectangle {
+        match self.shape {
+            StoredShape::Circle { x, y, radius } => {
+                Rectangle::new(x - radius, y - radius, radius * 2.0, radius * 2.0)
+            }
+            StoredShape::Rectangle(rectangle) => rectangle,
+            StoredShape::Point(point) => Rectangle::new(point.x, point.y, 0.0, 0.0),
+        }
+    }
+
+    // Bit entity_type of a category mask, or no bits for untyped entities and types
+    // beyond the width of the mask
+    fn category_mask(&self) -> u64 {
+        self.entity_type
+            .and_then(|entity_type| 1u64.checked_shl(entity_type))
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum StoredShape {
+    Circle { x: Float, y: Float, radius: Float },
+    Rectangle(Rectangle),
+    Point(Point),
+}
+
+impl StoredShape {
+    fn new(shape: ShapeEnum) -> Self {
+        match shape {
+            ShapeEnum::Circle(circle) => StoredShape::Circle {
+                x: circle.x,
+                y: circle.y,
+                radius: circle.radius,
+            },
+            ShapeEnum::Rectangle(rectangle) => StoredShape::Rectangle(rectangle),
+            ShapeEnum::Point(point) => StoredShape::Point(point),
+        }
+    }
+}
+
+// Which entity types a query matches
+#[derive(Clone, Copy)]
+enum TypeFilter<'a> {
+    All,
+    Types(&'a [u32]),
+    Mask(u64),
+}
+
+impl<'a> TypeFilter<'a> {
+    // Lists of types that all fit in the mask are checked with a single AND
+    // instead of a search through the list
+    fn new(filter_entity_types: Option<&'a [u32]>) -> Self {
+        match filter_entity_types {
+            None => TypeFilter::All,
+            Some(types) if types.iter().all(|&entity_type| entity_type < u64::BITS) => {
+                TypeFilter::Mask(
+                    types
+                        .iter()
+                        .fold(0, |mask, &entity_type| mask | 1 << entity_type),
+                )
+            }
+            Some(types) => TypeFilter::Types(types),
+        }
+    }
+
+    fn to_trace(self) -> TraceFilter {
+        match self {
+            TypeFilter::All => TraceFilter::All,
+            TypeFilter::Types(types) => TraceFilter::Types(types.to_vec()),
+            TypeFilter::Mask(mask) => TraceFilter::Mask(mask),
+        }
+    }
+
+    fn matches(&self, entity: &Entity) -> bool {
+        match self {
+            TypeFilter::All => true,
+            TypeFilter::Types(types) => entity
+                .entity_type
+                .is_some_and(|entity_type| types.contains(&entity_type)),
+            TypeFilter::Mask(mask) => entity.category_mask() & mask != 0,
+        }
+    }
+}
+
+#[derive(Clone)]
 struct QuadNode {
     entities: HashMap<u32, Entity>,
     bounding_box: Rectangle,
-    nw: Option<Rc<RefCell<QuadNode>>>,
-    ne: Option<Rc<RefCell<QuadNode>>>,
-    sw: Option<Rc<RefCell<QuadNode>>>,
-    se: Option<Rc<RefCell<QuadNode>>>,
-    parent: Option<Weak<RefCell<QuadNode>>>,
-    subdivided: bool,
+    // Bounds entities must fit in to be stored here, which extend past bounding_box
+    // in a loose tree
+    loose_bounding_box: Rectangle,
+    // Child nodes in nw, ne, sw, se order, set when the node is subdivided
+    children: Option<[NodeId; 4]>,
+    parent: Option<NodeId>,
     depth: usize,
-    self_rc: Option<Weak<RefCell<QuadNode>>>,
+    // Deepest level this node's subtree may be split to, which depends on the size class
+    // of the root it descends from
+    max_depth: usize,
 }
 
 // Implement the Resettable trait for QuadNode
 impl Resettable for QuadNode {
     fn reset(&mut self) {
         self.bounding_box = Rectangle::default();
+        self.loose_bounding_box = Rectangle::default();
         self.parent = None;
         self.depth = 0;
+        self.max_depth = 0;
         self.entities.clear();
-        self.nw = None;
-        self.ne = None;
-        self.sw = None;
-        self.se = None;
-        self.subdivided = false;
+        self.children = None;
     }
 }
 
@@ -48,105 +163,38 @@ impl QuadNode {
         Self {
             entities: HashMap::new(),
             bounding_box: Rectangle::default(),
-            nw: None,
-            ne: None,
-            sw: None,
-            se: None,
+            loose_bounding_box: Rectangle::default(),
+            children: None,
             parent: None,
-            subdivided: false,
             depth: 0,
-            self_rc: None,
+            max_depth: 0,
         }
     }
 
     pub fn initialize(
         &mut self,
         bounding_box: Rectangle,
-        parent: Option<Weak<RefCell<QuadNode>>>,
+        loose_bounding_box: Rectangle,
+        parent: Option<NodeId>,
         depth: usize,
+        max_depth: usize,
     ) {
         self.bounding_box = bounding_box;
+        self.loose_bounding_box = loose_bounding_box;
         self.parent = parent;
         self.depth = depth;
+        self.max_depth = max_depth;
         self.entities.clear();
-        self.nw = None;
-        self.ne = None;
-        self.sw = None;
-        self.se = None;
-        self.subdivided = false;
-    }
-
-    // New method to initialize the self_rc field.
-    pub fn set_self_rc(&mut self, self_rc: Weak<RefCell<QuadNode>>) {
-        self.self_rc = Some(self_rc);
-    }
-
-    // Returns an iterator over all items in the QuadNode, including child nodes
-    pub fn all_items(&self) -> Box<dyn Iterator<Item=(u32, Entity)> + '_> {
-        let items = self.entities.iter().map(|(id, entity)| (*id, entity.clone()));
-        if !self.subdivided {
-            return Box::new(items);
-        }
-
-        let child_items = self.child_items();
-        Box::new(items.chain(child_items))
+        self.children = None;
     }
 
-    // Returns an iterator over items in child nodes
-    fn child_items(&self) -> Box<dyn Iterator<Item=(u32, Entity)> + '_> {
-        if !self.subdivided {
-            return Box::new(std::iter::empty());
-        }
-
-        let items: Vec<_> = [
-            self.nw.as_ref(),
-            self.ne.as_ref(),
-            self.sw.as_ref(),
-            self.se.as_ref(),
-        ]
-            .iter()
-            .flat_map(|opt_node| {
-                opt_node
-                    .map(|node_rc| {
-                        node_rc
-                            .borrow()
-                            .all_items()
-                            .map(|(id, entity)| (id, entity.clone()))
-                            .collect::<Vec<_>>()
-                            .into_iter()
-                    })
-                    .into_iter()
-                    .flatten()
-            })
-            .collect();
-
-        Box::new(items.into_iter())
+    pub fn is_subdivided(&self) -> bool {
+        self.children.is_some()
     }
 
-    // Counts all items in the QuadNode, including child nodes
-    pub fn count_all_items(&self) -> usize {
-        let mut count = self.entities.len();
-        if !self.subdivided {
-            return count;
-        }
-
-        count += self
-            .nw
-            .as_ref()
-            .map_or(0, |nw| nw.borrow().count_all_items());
-        count += self
-            .ne
-            .as_ref()
-            .map_or(0, |ne| ne.borrow().count_all_items());
-        count += self
-            .sw
-            .as_ref()
-            .map_or(0, |sw| sw.borrow().count_all_items());
-        count += self
-            .se
-            .as_ref()
-            .map_or(0, |se| se.borrow().count_all_items());
-        return count;
+    // Returns the child nodes, or nothing if the node has not been subdivided
+    pub fn child_ids(&self) -> impl Iterator<Item = NodeId> {
+        self.children.into_iter().flatten()
     }
 }
 
@@ -157,591 +205,2532 @@ impl Default for QuadNode {
 }
 
 pub struct QuadTree {
-    root: Rc<RefCell<QuadNode>>,
-    owner_map: HashMap<u32, Weak<RefCell<QuadNode>>>,
+    nodes: Vec<Option<QuadNode>>,
+    // Arena slots left empty by nodes returned to the pool, reused by the next allocation
+    free_slots: Vec<NodeId>,
+    // One root per size class, indexed by SizeClass, or a single root when
+    // Config::size_classes is not set
+    roots: Vec<NodeId>,
+    owner_map: HashMap<u32, NodeId>,
     quad_node_pool: ObjectPool<QuadNode>,
+    // Most recent root-heavy counts, oldest first
+    root_heavy_history: VecDeque<RootHeavyCounts>,
+    // Registered templates, indexed by template id
+    templates: Vec<Template>,
+    region_locks: RegionLocks,
+    // Nodes subdivided and subtrees collapsed back into their parent since creation
+    splits: u64,
+    merges: u64,
+    counters: Counters,
+    recorder: Recorder,
 
     config: Config,
 }
 
+// A copy of the entries and node layout, for example a snapshot to query from another
+// thread while this tree keeps changing. Pooled nodes, region locks, operation counters
+// and any recording in progress belong to the original and are not carried over.
+impl Clone for QuadTree {
+    fn clone(&self) -> Self {
+        QuadTree {
+            nodes: self.nodes.clone(),
+            free_slots: self.free_slots.clone(),
+            roots: self.roots.clone(),
+            owner_map: self.owner_map.clone(),
+            quad_node_pool: ObjectPool::new(self.config.pool_size),
+            root_heavy_history: self.root_heavy_history.clone(),
+            templates: self.templates.clone(),
+            region_locks: RegionLocks::default(),
+            splits: self.splits,
+            merges: self.merges,
+            counters: Counters::default(),
+            recorder: Recorder::default(),
+            config: self.config.clone(),
+        }
+    }
+}
+
 impl QuadTree {
     pub fn new_with_config(bounding_box: Rectangle, config: Config) -> Self {
-        let mut quad_node_pool = ObjectPool::<QuadNode>::new(config.pool_size);
-        let root = Rc::new(RefCell::new(quad_node_pool.get()));
-        root.borrow_mut().initialize(bounding_box, None, 0);
-        root.borrow_mut().set_self_rc(Rc::downgrade(&root));
-
+        let quad_node_pool = ObjectPool::<QuadNode>::new(config.pool_size);
         let owner_map = HashMap::new();
-        QuadTree {
+        let mut quadtree = QuadTree {
+            nodes: Vec::new(),
+            free_slots: Vec::new(),
+            roots: Vec::new(),
             quad_node_pool,
-            root,
             owner_map,
+            root_heavy_history: VecDeque::new(),
+            templates: Vec::new(),
+            region_locks: RegionLocks::default(),
+            splits: 0,
+            merges: 0,
+            counters: Counters::default(),
+            recorder: Recorder::default(),
             config,
+        };
+
+        let size_classes: &[SizeClass] = match quadtree.config.size_classes {
+            Some(_) => &[SizeClass::Tiny, SizeClass::Medium, SizeClass::Huge],
+            None => &[SizeClass::Tiny],
+        };
+        for size_class in size_classes {
+            // Huge entities are few and overlap most queries, so they are kept in a single
+            // unsplit node that is checked once instead of clogging the top of the tree
+            let max_depth = match size_class {
+                SizeClass::Huge => 0,
+                _ => quadtree.config.max_depth_for(&bounding_box),
+            };
+            let root = quadtree.allocate_node(bounding_box, None, 0, max_depth);
+            quadtree.roots.push(root);
         }
+        quadtree
     }
 
     pub fn new(bounding_box: Rectangle) -> Self {
         Self::new_with_config(bounding_box, Config::default())
     }
 
-    // Insert a shape with a given value into the quadtree
-    pub fn insert(&mut self, value: u32, shape: ShapeEnum, entity_type: Option<u32>) {
-        self.insert_into(self.root.clone(), value, shape, entity_type);
+    // Copy-on-write access to a tree shared through an Arc, so snapshots can be handed out
+    // without copying the tree. The entries are only copied while other handles still share
+    // it, and the region locks, operation counters and any recording in progress then move
+    // to the copy, so they stay with the handle being written to.
+    pub fn make_mut(tree: &mut Arc<QuadTree>) -> &mut QuadTree {
+        if Arc::get_mut(tree).is_none() {
+            let shared: &QuadTree = tree;
+            let copy = QuadTree {
+                region_locks: shared.region_locks.share(),
+                ..shared.clone()
+            };
+            copy.counters.absorb(shared.counters.take());
+            if let Some(trace) = shared.recorder.stop() {
+                copy.recorder.start(trace);
+            }
+            *tree = Arc::new(copy);
+        }
+        Arc::get_mut(tree).expect("Tree should no longer be shared")
     }
 
-    // Insert a shape into a given node or its children
-    fn insert_into(
-        &mut self,
-        mut node: Rc<RefCell<QuadNode>>,
-        value: u32,
-        shape: ShapeEnum,
-        entity_type: Option<u32>,
-    ) -> Rc<RefCell<QuadNode>> {
-        loop {
-            let mut need_subdivide = false;
-            {
-                let node_borrow = node.borrow_mut();
-
-                // Check if node has room or reached max depth
-                if (node_borrow.entities.len() < self.config.node_capacity && !node_borrow.subdivided)
-                    || node_borrow.depth == self.config.max_depth
-                {
-                    drop(node_borrow);
-                    self.add(&node, value, shape, entity_type);
-                    return node.clone();
-                }
+    fn node(&self, node: NodeId) -> &QuadNode {
+        self.nodes[node]
+            .as_ref()
+            .expect("Node id should refer to a live node")
+    }
 
-                // Subdivide node if needed
-                if !node_borrow.subdivided && node_borrow.depth < self.config.max_depth {
-                    need_subdivide = true;
-                } else {
-                    let destination = self.get_destination_node(&node_borrow, shape.clone());
-                    if Rc::ptr_eq(&destination, &node) {
-                        drop(node_borrow);
-                        self.add(&node, value, shape, entity_type);
-                        return node.clone();
-                    }
+    fn node_mut(&mut self, node: NodeId) -> &mut QuadNode {
+        self.nodes[node]
+            .as_mut()
+            .expect("Node id should refer to a live node")
+    }
 
-                    // Move to the next node for insertion
-                    drop(node_borrow);
-                    node = destination;
-                }
+    // Take a node from the pool and store it in a free arena slot
+    fn allocate_node(
+        &mut self,
+        bounding_box: Rectangle,
+        parent: Option<NodeId>,
+        depth: usize,
+        max_depth: usize,
+    ) -> NodeId {
+        let loose_bounding_box = self.loose_bounds(&bounding_box);
+        let mut node = self.quad_node_pool.get();
+        node.initialize(bounding_box, loose_bounding_box, parent, depth, max_depth);
+        match self.free_slots.pop() {
+            Some(slot) => {
+                self.nodes[slot] = Some(node);
+                slot
             }
-
-            // Perform subdivision outside the borrow scope
-            if need_subdivide {
-                self.subdivide(node.clone());
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
             }
         }
     }
 
-    // Determine which child node the shape belongs to
-    fn get_destination_node(&self, node: &QuadNode, shape: ShapeEnum) -> Rc<RefCell<QuadNode>> {
-        if !node.subdivided {
-            return node
-                .self_rc
-                .as_ref()
-                .expect("Failed to upgrade Weak reference to Rc")
-                .upgrade()
-                .expect("Failed to upgrade Weak reference to Rc");
-        }
-
-        assert!(
-            node.nw.is_some(),
-            "nw should be set when subdivided is true"
-        );
-        assert!(
-            node.ne.is_some(),
-            "ne should be set when subdivided is true"
-        );
-        assert!(
-            node.sw.is_some(),
-            "sw should be set when subdivided is true"
-        );
-        assert!(
-            node.se.is_some(),
-            "se should be set when subdivided is true"
-        );
+    // Grow node bounds by the looseness factor, keeping them centered on the node
+    fn loose_bounds(&self, bounding_box: &Rectangle) -> Rectangle {
+        let looseness = self.config.looseness.max(1.0);
+        Rectangle::new(
+            bounding_box.x - bounding_box.width * (looseness - 1.0) / 2.0,
+            bounding_box.y - bounding_box.height * (looseness - 1.0) / 2.0,
+            bounding_box.width * looseness,
+            bounding_box.height * looseness,
+        )
+    }
 
-        let bounding_box = shape.bounding_box();
-        // Extract child nodes as references to avoid moving the values
-        let (nw, ne, sw, se) = (&node.nw, &node.ne, &node.sw, &node.se);
-
-        // Iterate over references to child nodes
-        for child in &[nw, ne, sw, se] {
-            if let Some(child_rc) = child.as_ref() {
-                if collision_detection::rectangle_contains_rectangle(
-                    &child_rc.borrow().bounding_box,
-                    &bounding_box,
-                ) {
-                    return child_rc.clone();
-                }
-            }
+    // Return a node and all of its descendants to the pool
+    fn release_node(&mut self, node: NodeId) {
+        let released = self.nodes[node]
+            .take()
+            .expect("Node id should refer to a live node");
+        for child in released.child_ids() {
+            self.release_node(child);
         }
+        self.quad_node_pool.return_object(released);
+        self.free_slots.push(node);
+    }
 
-        node.self_rc
-            .as_ref()
-            .expect("Failed to upgrade Weak reference to Rc")
-            .upgrade()
-            .expect("Failed to upgrade Weak reference to Rc")
+    // Build a tree over static data in one pass, with bounds fitted to the items
+    pub fn bulk_load(items: Vec<(u32, ShapeEnum, Option<u32>)>) -> Self {
+        let bounding_box = items
+            .iter()
+            .map(|(_, shape, _)| shape.bounding_box())
+            .reduce(|a, b| union_rectangles(&a, &b))
+            .unwrap_or_default();
+        Self::bulk_load_with_config(bounding_box, Config::default(), items)
     }
 
-    fn add(
-        &mut self,
-        node: &Rc<RefCell<QuadNode>>,
-        value: u32,
-        shape: ShapeEnum,
-        entity_type: Option<u32>,
-    ) {
-        {
-            // Limit the scope of the mutable borrow using a block
-            let mut node_borrow_mut = node.borrow_mut();
-            node_borrow_mut.entities.insert(value, Entity { shape, entity_type });
-        }
-        // The mutable borrow is released here
-        self.owner_map.insert(value, Rc::downgrade(&node));
+    pub fn bulk_load_with_config(
+        bounding_box: Rectangle,
+        config: Config,
+        items: Vec<(u32, ShapeEnum, Option<u32>)>,
+    ) -> Self {
+        let mut quadtree = Self::new_with_config(bounding_box, config);
+        quadtree.insert_batch(items);
+        quadtree
     }
 
-    pub fn delete(&mut self, value: u32) {
-        if let Some(node_weak) = self.owner_map.remove(&value) {
-            let node_rc = node_weak
-                .upgrade()
-                .expect("Failed to upgrade Weak reference to Rc");
-            self.delete_from(node_rc.clone(), value);
-            // Clean up the node and its ancestors after deleting an item
-            self.clean_upwards(node_rc);
+    // Insert many shapes at once. An empty tree is built top-down, partitioning the items
+    // between quadrants so every node is split at most once; otherwise items are inserted
+    // one by one.
+    // Panics if the configuration rejects any of the shapes, see try_insert_batch
+    pub fn insert_batch(&mut self, items: Vec<(u32, ShapeEnum, Option<u32>)>) {
+        // Reject the whole batch before anything is inserted
+        for (value, shape, _) in items.iter() {
+            if let Err(err) = self.validate_shape(*value, shape) {
+                panic!("{}", err);
+            }
         }
+        self.insert_batch_validated(items);
     }
 
-    fn delete_from(&mut self, node: Rc<RefCell<QuadNode>>, value: u32) {
-        // Remove the item from the QuadNode's items
-        let mut node_borrow = node.borrow_mut();
-        node_borrow.entities.remove(&value);
+    // Like insert_batch, returning an error instead, with nothing inserted, if the
+    // configuration rejects any of the shapes or a value is already in the tree or
+    // repeated within the batch
+    pub fn try_insert_batch(
+        &mut self,
+        items: Vec<(u32, ShapeEnum, Option<u32>)>,
+    ) -> Result<(), QuadTreeError> {
+        let mut values = HashSet::with_capacity(items.len());
+        for (value, shape, _) in items.iter() {
+            if self.owner_map.contains_key(value) || !values.insert(*value) {
+                return Err(QuadTreeError::AlreadyExists(*value));
+            }
+            self.validate_shape(*value, shape)?;
+        }
+        self.insert_batch_validated(items);
+        Ok(())
     }
 
-    // Subdivide a node into quadrants
-    fn subdivide(&mut self, node: Rc<RefCell<QuadNode>>) {
-        let mut node_borrow = node.borrow_mut();
-
-        let half_width = node_borrow.bounding_box.width / 2.0;
-        let half_height = node_borrow.bounding_box.height / 2.0;
+    fn insert_batch_validated(&mut self, items: Vec<(u32, ShapeEnum, Option<u32>)>) {
+        if !self.owner_map.is_empty() {
+            for (value, shape, entity_type) in items {
+                self.insert(value, shape, entity_type);
+            }
+            return;
+        }
 
-        // Compute coordinates for the new quadrants
-        let nw_x = node_borrow.bounding_box.x;
-        let nw_y = node_borrow.bounding_box.y;
-        let ne_x = nw_x + half_width;
-        let sw_y = nw_y + half_height;
+        if self.config.auto_expand {
+            for (_, shape, _) in items.iter() {
+                self.grow_to(&shape.bounding_box());
+            }
+        }
 
-        // Create a weak reference to the parent node
-        let parent_weak = Rc::downgrade(&node);
+        self.recorder
+            .record(|| TraceEvent::InsertBatch(items.clone()));
 
-        // Create new quadrants
-        node_borrow.nw = Some(Rc::new(RefCell::new(self.quad_node_pool.get())));
-        node_borrow.nw.as_ref().unwrap().borrow_mut().initialize(
-            Rectangle {
-                x: nw_x,
-                y: nw_y,
-                width: half_width,
-                height: half_height,
-            },
-            Some(parent_weak.clone()),
-            node_borrow.depth + 1,
-        );
-        node_borrow
-            .nw
-            .as_ref()
-            .unwrap()
-            .borrow_mut()
-            .set_self_rc(Rc::downgrade(&node_borrow.nw.as_ref().unwrap()));
-
-        node_borrow.ne = Some(Rc::new(RefCell::new(self.quad_node_pool.get())));
-        node_borrow.ne.as_ref().unwrap().borrow_mut().initialize(
-            Rectangle {
-                x: ne_x,
-                y: nw_y,
-                width: half_width,
-                height: half_height,
-            },
-            Some(parent_weak.clone()),
-            node_borrow.depth + 1,
-        );
-        node_borrow
-            .ne
-            .as_ref()
-            .unwrap()
-            .borrow_mut()
-            .set_self_rc(Rc::downgrade(&node_borrow.ne.as_ref().unwrap()));
-
-        node_borrow.sw = Some(Rc::new(RefCell::new(self.quad_node_pool.get())));
-        node_borrow.sw.as_ref().unwrap().borrow_mut().initialize(
-            Rectangle {
-                x: nw_x,
-                y: sw_y,
-                width: half_width,
-                height: half_height,
-            },
-            Some(parent_weak.clone()),
-            node_borrow.depth + 1,
-        );
-        node_borrow
-            .sw
-            .as_ref()
-            .unwrap()
-            .borrow_mut()
-            .set_self_rc(Rc::downgrade(&node_borrow.sw.as_ref().unwrap()));
-
-        node_borrow.se = Some(Rc::new(RefCell::new(self.quad_node_pool.get())));
-        node_borrow.se.as_ref().unwrap().borrow_mut().initialize(
-            Rectangle {
-                x: ne_x,
-                y: sw_y,
-                width: half_width,
-                height: half_height,
-            },
-            Some(parent_weak.clone()),
-            node_borrow.depth + 1,
-        );
-        node_borrow
-            .se
-            .as_ref()
-            .unwrap()
-            .borrow_mut()
-            .set_self_rc(Rc::downgrade(&node_borrow.se.as_ref().unwrap()));
+        // Later items replace earlier ones with the same value, as with repeated inserts
+        let mut positions = HashMap::with_capacity(items.len());
+        for (position, (value, _, _)) in items.iter().enumerate() {
+            positions.insert(*value, position);
+        }
+        let entries: Vec<(u32, Entity)> = items
+            .into_iter()
+            .enumerate()
+            .filter(|(position, (value, _, _))| positions[value] == *position)
+            .map(|(_, (value, shape, entity_type))| (value, Entity::new(shape, entity_type)))
+            .collect();
+        Counters::add(&self.counters.inserts, entries.len() as u64);
 
-        node_borrow.subdivided = true;
+        // Drop any empty quadrants left over from earlier deletions
+        for root in self.roots.clone() {
+            if let Some(children) = self.node_mut(root).children.take() {
+                for child in children {
+                    self.release_node(child);
+                }
+            }
+        }
 
-        // Redistribute the items to the appropriate quadrants
-        let old_items = node_borrow.entities.drain().collect::<Vec<(u32, Entity)>>();
-        drop(node_borrow);
-        for (value, entity) in old_items {
-            self.owner_map.remove(&value);
-            self.insert_into(node.clone(), value, entity.shape, entity.entity_type);
+        let mut root_entries: HashMap<NodeId, Vec<(u32, Entity)>> = HashMap::new();
+        for (value, entity) in entries {
+            let root = self.root_for(&entity.shape());
+            root_entries.entry(root).or_default().push((value, entity));
+        }
+        for (root, entries) in root_entries {
+            self.build_from(root, entries);
         }
     }
 
-    pub fn collisions_batch(&self, shapes: Vec<ShapeEnum>) -> Vec<Vec<u32>> {
-        shapes
-            .into_iter()
-            .map(|shape| {
-                let mut collisions = Vec::new();
-                self.collisions(shape, &mut collisions);
-                collisions
-            })
-            .collect()
+    // Recursively distribute entries into a node and the quadrants it is split into
+    fn build_from(&mut self, node: NodeId, entries: Vec<(u32, Entity)>) {
+        let node_ref = self.node(node);
+        if entries.len() <= self.config.node_capacity || node_ref.depth >= node_ref.max_depth {
+            for (value, entity) in entries {
+                self.add(node, value, entity.shape(), entity.entity_type);
+            }
+            return;
+        }
+
+        self.subdivide(node);
+        let children = self
+            .node(node)
+            .children
+            .expect("Node should have children after subdividing");
+        let mut quadrant_entries: [Vec<(u32, Entity)>; 4] = Default::default();
+        for (value, entity) in entries {
+            let destination = self.get_destination_node(node, &entity.shape());
+            match children.iter().position(|&child| child == destination) {
+                Some(quadrant) => quadrant_entries[quadrant].push((value, entity)),
+                // Entities straddling the quadrants stay in this node
+                None => self.add(node, value, entity.shape(), entity.entity_type),
+            }
+        }
+
+        for (child, entries) in children.into_iter().zip(quadrant_entries) {
+            self.build_from(child, entries);
+        }
     }
 
-    pub fn collisions_batch_filter(
-        &self,
-        shapes: Vec<ShapeEnum>,
-        filter_entity_types: Option<Vec<u32>>,
-    ) -> Vec<Vec<u32>> {
-        shapes
-            .into_iter()
-            .map(|shape| {
-                let mut collisions = Vec::new();
-                self.collisions_filter(shape, filter_entity_types.clone(), &mut collisions);
-                collisions
-            })
-            .collect()
+    // Insert a shape with a given value into the quadtree, replacing any entry that
+    // already has this value
+    // Panics if the configuration rejects the shape, see try_insert
+    pub fn insert(&mut self, value: u32, shape: ShapeEnum, entity_type: Option<u32>) {
+        self.upsert(value, shape, entity_type);
     }
 
-    pub fn collisions(&self, shape: ShapeEnum, collisions: &mut Vec<u32>) {
-        self.collisions_from(&self.root, &shape, None, collisions);
+    // Like insert, returning whether an existing entry was replaced
+    // Panics if the configuration rejects the shape, see try_upsert
+    pub fn upsert(&mut self, value: u32, shape: ShapeEnum, entity_type: Option<u32>) -> bool {
+        match self.try_upsert(value, shape, entity_type) {
+            Ok(replaced) => replaced,
+            Err(err) => panic!("{}", err),
+        }
     }
 
-    pub fn collisions_filter(
-        &self,
+    // Like upsert, returning an error instead if the configuration rejects the shape
+    pub fn try_upsert(
+        &mut self,
+        value: u32,
         shape: ShapeEnum,
-        filter_entity_types: Option<Vec<u32>>,
-        collisions: &mut Vec<u32>,
-    ) {
-        self.collisions_from(&self.root, &shape, filter_entity_types, collisions);
+        entity_type: Option<u32>,
+    ) -> Result<bool, QuadTreeError> {
+        // Check the shape first, so a rejected shape leaves the existing entry in place
+        self.validate_shape(value, &shape)?;
+        let replaced = self.delete_unlocked(value);
+        self.insert_validated(value, shape, entity_type)?;
+        Ok(replaced)
     }
 
-    // Find collisions with a given shape in the QuadTree
-    // Helper method to recursively find collisions in the tree
-    fn collisions_from(
-        &self,
-        node: &Rc<RefCell<QuadNode>>,
-        query_shape: &ShapeEnum,
-        filter_entity_types: Option<Vec<u32>>,
-        collisions: &mut Vec<u32>,
-    ) {
-        // Compute the bounding box of the query shape
-        let query_shape_bounding_box = query_shape.bounding_box();
+    // Insert a shape, or return an error if an entry already has this value or the
+    // configuration rejects the shape
+    pub fn try_insert(
+        &mut self,
+        value: u32,
+        shape: ShapeEnum,
+        entity_type: Option<u32>,
+    ) -> Result<(), QuadTreeError> {
+        self.validate_shape(value, &shape)?;
+        self.insert_validated(value, shape, entity_type)
+    }
 
-        // Check for collisions with shapes in the current node
-        let node_borrow = node.borrow();
-        for (&value, entity) in node_borrow.entities.iter() {
-            // Apply the entity type filter, if provided
-            if let Some(filter) = &filter_entity_types {
-                if let Some(entity_type) = entity.entity_type {
-                    if !filter.contains(&entity_type) {
-                        continue; // Skip items not matching the filter
-                    }
-                } else {
-                    continue; // Skip items not found in the entity_types map
-                }
-            }
+    // Register a shape and entity type shared by many entries, such as every bullet of a
+    // kind, and return its template id. The shape is checked once here rather than on
+    // every insert_from_template.
+    pub fn register_template(&mut self, shape: ShapeEnum, entity_type: Option<u32>) -> u32 {
+        self.templates.push(Template {
+            degenerate: shape.is_degenerate(),
+            shape,
+            entity_type,
+        });
+        (self.templates.len() - 1) as u32
+    }
 
-            if collision_detection::shape_shape(&query_shape, &entity.shape) {
-                collisions.push(value);
-            }
+    // Insert an entry with the shape and entity type of a template, placed at (x, y) as
+    // described in ShapeEnum::moved_to
+    pub fn insert_from_template(
+        &mut self,
+        value: u32,
+        template: u32,
+        x: Float,
+        y: Float,
+    ) -> Result<(), QuadTreeError> {
+        let template_ref = self
+            .templates
+            .get(template as usize)
+            .ok_or(QuadTreeError::UnknownTemplate(template))?;
+        if template_ref.degenerate
+            && self.config.degenerate_shapes == DegenerateShapeMode::ErrorOnInsert
+        {
+            return Err(QuadTreeError::DegenerateShape(value));
         }
+        let shape = template_ref.shape.moved_to(x, y);
+        let entity_type = template_ref.entity_type;
+        self.check_unlocked(value, Some(&shape))?;
+        self.insert_validated(value, shape, entity_type)
+    }
 
-        // Extract child nodes to avoid multiple borrows in pattern matching
-        let (nw, ne, sw, se) = {
-            let node_ref = node.borrow();
-            (
-                node_ref.nw.as_ref().map(|rc| rc.clone()),
-                node_ref.ne.as_ref().map(|rc| rc.clone()),
-                node_ref.sw.as_ref().map(|rc| rc.clone()),
-                node_ref.se.as_ref().map(|rc| rc.clone()),
-            )
-        };
+    fn insert_validated(
+        &mut self,
+        value: u32,
+        shape: ShapeEnum,
+        entity_type: Option<u32>,
+    ) -> Result<(), QuadTreeError> {
+        if self.owner_map.contains_key(&value) {
+            return Err(QuadTreeError::AlreadyExists(value));
+        }
+        if self.config.auto_expand {
+            self.grow_to(&shape.bounding_box());
+        }
+        self.recorder.record(|| TraceEvent::Insert {
+            value,
+            shape: shape.clone(),
+            entity_type,
+        });
+        let root = self.root_for(&shape);
+        self.insert_into(root, value, shape, entity_type);
+        Counters::add(&self.counters.inserts, 1);
+        Ok(())
+    }
 
-        // Continue with child nodes if the node has been subdivided
-        if let (Some(nw), Some(ne), Some(sw), Some(se)) = (nw, ne, sw, se) {
-            if collision_detection::rectangle_rectangle(
-                &nw.borrow().bounding_box,
-                &query_shape_bounding_box,
-            ) {
-                self.collisions_from(&nw, query_shape, filter_entity_types.clone(), collisions);
-            }
-            if collision_detection::rectangle_rectangle(
-                &ne.borrow().bounding_box,
-                &query_shape_bounding_box,
-            ) {
-                self.collisions_from(&ne, query_shape, filter_entity_types.clone(), collisions);
-            }
-            if collision_detection::rectangle_rectangle(
-                &sw.borrow().bounding_box,
-                &query_shape_bounding_box,
-            ) {
-                self.collisions_from(&sw, query_shape, filter_entity_types.clone(), collisions);
-            }
-            if collision_detection::rectangle_rectangle(
-                &se.borrow().bounding_box,
-                &query_shape_bounding_box,
-            ) {
-                self.collisions_from(&se, query_shape, filter_entity_types, collisions);
+    // Check that an entry can be stored with a shape: the configuration accepts the shape,
+    // and neither the shape nor the entry's current one is in a locked region
+    pub fn validate_shape(&self, value: u32, shape: &ShapeEnum) -> Result<(), QuadTreeError> {
+        if let DegenerateShapeMode::ErrorOnInsert = self.config.degenerate_shapes {
+            if shape.is_degenerate() {
+                return Err(QuadTreeError::DegenerateShape(value));
             }
         }
+        self.check_unlocked(value, Some(shape))
     }
 
-    pub fn relocate_batch(&mut self, relocation_requests: Vec<RelocationRequest>) {
-        for request in relocation_requests {
-            self.relocate(request.value, request.shape, request.entity_type);
+    // Fail if the entry's current shape, or its new shape when given, is in a locked region
+    fn check_unlocked(&self, value: u32, shape: Option<&ShapeEnum>) -> Result<(), QuadTreeError> {
+        let current = self.entity(value).map(|entity| entity.shape());
+        let locked = self.region_locks.any(|region| {
+            current
+                .iter()
+                .chain(shape)
+                .any(|shape| self.shapes_collide(region, shape))
+        });
+        if locked {
+            return Err(QuadTreeError::RegionLocked(value));
         }
+        Ok(())
     }
 
-    pub fn relocate(&mut self, value: u32, shape: ShapeEnum, entity_type: Option<u32>) {
-        if let Some(node_weak) = self.owner_map.get(&value) {
-            let node = node_weak
-                .upgrade()
-                .expect("Failed to upgrade Weak reference to node");
+    // Lock a region against mutation until the returned guard is released or dropped, so
+    // a job working on that area, for instance through a shared lock on the tree, can
+    // rely on it not changing mid-computation. Only the entries are protected: nodes may
+    // still split or merge.
+    pub fn lock_region(&self, region: ShapeEnum) -> RegionLock {
+        self.region_locks.lock(region)
+    }
 
-            // Check if the item still fits in the current node
-            let bounding_box = shape.bounding_box();
-            if collision_detection::rectangle_contains_rectangle(
-                &node.borrow().bounding_box,
-                &bounding_box,
-            ) {
-                // Item is still in the correct node, no need to relocate
-                self.add(&node, value, shape, entity_type);
-                return;
+    // Collision test that applies the configured handling of degenerate shapes
+    fn shapes_collide(&self, a: &ShapeEnum, b: &ShapeEnum) -> bool {
+        if !a.is_degenerate() && !b.is_degenerate() {
+            return collision_detection::shape_shape(a, b);
+        }
+        match self.config.degenerate_shapes {
+            DegenerateShapeMode::AlwaysMiss => false,
+            // Degenerate shapes are closed sets like points, so touching counts as a hit
+            DegenerateShapeMode::PointSemantics | DegenerateShapeMode::ErrorOnInsert => {
+                collision_detection::shape_shape_distance(a, b) <= 0.0
             }
+        }
+    }
 
-            // Delete the item from the current node and relocate to the appropriate node
-            self.delete_from(node.clone(), value);
-            self.relocate_in(node, value, shape, entity_type);
-        } else {
-            // If the object is not found in the owner_map, insert it into the quadtree
-            self.insert(value, shape, entity_type);
+    // Time of impact of a moving shape with a static one, applying the configured handling
+    // of degenerate shapes as shapes_collide does
+    fn time_of_impact(
+        &self,
+        moving: &ShapeEnum,
+        dx: Float,
+        dy: Float,
+        other: &ShapeEnum,
+    ) -> Option<Float> {
+        if !moving.is_degenerate() && !other.is_degenerate() {
+            return collision_detection::shape_shape_time_of_impact(moving, dx, dy, other);
+        }
+        match self.config.degenerate_shapes {
+            DegenerateShapeMode::AlwaysMiss => None,
+            DegenerateShapeMode::PointSemantics | DegenerateShapeMode::ErrorOnInsert => {
+                collision_detection::shape_shape_time_of_touch(moving, dx, dy, other)
+            }
         }
     }
 
-    fn relocate_in(
-        &mut self,
-        mut node: Rc<RefCell<QuadNode>>,
+    // Whether a shape blocks the segment from start to end, applying the configured
+    // handling of degenerate shapes as shapes_collide does
+    fn segment_blocked(&self, start: &Point, end: &Point, shape: &ShapeEnum) -> bool {
+        if !shape.is_degenerate() {
+            return collision_detection::segment_shape(start, end, shape);
+        }
+        match self.config.degenerate_shapes {
+            DegenerateShapeMode::AlwaysMiss => false,
+            DegenerateShapeMode::PointSemantics | DegenerateShapeMode::ErrorOnInsert => {
+                collision_detection::segment_touches_shape(start, end, shape)
+            }
+        }
+    }
+
+    // Distance between two shapes for the distance queries, or None when the configured
+    // handling of degenerate shapes makes one of them miss everything
+    fn shape_distance(&self, a: &ShapeEnum, b: &ShapeEnum) -> Option<Float> {
+        let misses = self.config.degenerate_shapes == DegenerateShapeMode::AlwaysMiss
+            && (a.is_degenerate() || b.is_degenerate());
+        (!misses).then(|| collision_detection::shape_shape_distance(a, b))
+    }
+
+    // The root of the tree holding shapes of this size
+    fn root_for(&self, shape: &ShapeEnum) -> NodeId {
+        match &self.config.size_classes {
+            Some(size_classes) => self.roots[size_classes.classify(shape) as usize],
+            None => self.roots[0],
+        }
+    }
+
+    // Grow the tree until its bounds contain the given rectangle. Each step re-roots the
+    // tree upward, making the old root a quadrant of a new root twice its size, so the
+    // existing nodes and the entities in them stay where they are.
+    pub fn expand_to(&mut self, rectangle: &Rectangle) {
+        self.recorder.record(|| TraceEvent::ExpandTo(*rectangle));
+        self.grow_to(rectangle);
+    }
+
+    fn grow_to(&mut self, rectangle: &Rectangle) {
+        let is_finite = [
+            rectangle.x,
+            rectangle.y,
+            rectangle.right(),
+            rectangle.bottom(),
+        ]
+        .iter()
+        .all(|coordinate| coordinate.is_finite());
+        if !is_finite {
+            return;
+        }
+
+        for index in 0..self.roots.len() {
+            while !collision_detection::rectangle_contains_rectangle(
+                &self.node(self.roots[index]).bounding_box,
+                rectangle,
+            ) {
+                if !self.grow_root(index, rectangle) {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Double the root at the given index towards a target, returning false if the
+    // root has no area to grow from
+    fn grow_root(&mut self, index: usize, target: &Rectangle) -> bool {
+        let root = self.roots[index];
+        let bounding_box = self.node(root).bounding_box;
+        if bounding_box.width <= 0.0 || bounding_box.height <= 0.0 {
+            return false;
+        }
+
+        let west = target.x < bounding_box.x;
+        let north = target.y < bounding_box.y;
+        let grown = Rectangle::new(
+            if west {
+                bounding_box.x - bounding_box.width
+            } else {
+                bounding_box.x
+            },
+            if north {
+                bounding_box.y - bounding_box.height
+            } else {
+                bounding_box.y
+            },
+            bounding_box.width * 2.0,
+            bounding_box.height * 2.0,
+        );
+
+        // Both paths give the root the same depth limit, so the tree ends up the same
+        // whatever order entries arrived in
+        let max_depth = self.grown_max_depth(index, &grown);
+
+        // A root that was never split can simply be resized in place
+        if !self.node(root).is_subdivided() {
+            let loose_bounding_box = self.loose_bounds(&grown);
+            let root_ref = self.node_mut(root);
+            root_ref.bounding_box = grown;
+            root_ref.loose_bounding_box = loose_bounding_box;
+            root_ref.max_depth = max_depth;
+            return true;
+        }
+
+        let new_root = self.allocate_node(grown, None, 0, max_depth);
+        // The old root becomes the quadrant on the side away from the growth
+        let old_quadrant = west as usize + 2 * north as usize;
+        let children: [NodeId; 4] = std::array::from_fn(|quadrant| {
+            if quadrant == old_quadrant {
+                return root;
+            }
+            let x = grown.x + bounding_box.width * (quadrant % 2) as Float;
+            let y = grown.y + bounding_box.height * (quadrant / 2) as Float;
+            self.allocate_node(
+                Rectangle::new(x, y, bounding_box.width, bounding_box.height),
+                Some(new_root),
+                1,
+                max_depth,
+            )
+        });
+        self.node_mut(new_root).children = Some(children);
+        self.node_mut(root).parent = Some(new_root);
+        self.roots[index] = new_root;
+
+        // Everything below the old root moved one level down
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            let node_ref = self.node_mut(node);
+            node_ref.depth += 1;
+            node_ref.max_depth = max_depth;
+            stack.extend(self.node(node).child_ids());
+        }
+
+        // Entities the old root held only because they were out of bounds are placed again
+        let old_bounds = self.node(root).loose_bounding_box;
+        let outside: Vec<u32> = self
+            .node(root)
+            .entities
+            .iter()
+            .filter(|(_, entity)| {
+                !collision_detection::rectangle_contains_rectangle(
+                    &old_bounds,
+                    &entity.bounding_box(),
+                )
+            })
+            .map(|(&value, _)| value)
+            .collect();
+        for value in outside {
+            if let Some(entity) = self.node_mut(root).entities.remove(&value) {
+                self.owner_map.remove(&value);
+                self.insert_into(new_root, value, entity.shape(), entity.entity_type);
+            }
+        }
+        true
+    }
+
+    // Depth limit of the root at the given index once it has grown to the given bounds.
+    // The old root becomes a quadrant, so one more level keeps the smallest cells the same
+    // size, unless min_cell_size is set and the depth is derived from the new bounds. Huge
+    // size-class roots are never split.
+    fn grown_max_depth(&self, index: usize, grown: &Rectangle) -> usize {
+        if self.config.size_classes.is_some() && index == SizeClass::Huge as usize {
+            return 0;
+        }
+        match self.config.min_cell_size {
+            Some(min_cell_size) if min_cell_size > 0.0 => self.config.max_depth_for(grown),
+            _ => self.node(self.roots[index]).max_depth + 1,
+        }
+    }
+
+    // The root a node descends from
+    fn root_of(&self, mut node: NodeId) -> NodeId {
+        while let Some(parent) = self.node(node).parent {
+            node = parent;
+        }
+        node
+    }
+
+    // Insert a shape into a given node or its children
+    fn insert_into(
+        &mut self,
+        mut node: NodeId,
+        value: u32,
+        shape: ShapeEnum,
+        entity_type: Option<u32>,
+    ) -> NodeId {
+        loop {
+            let node_ref = self.node(node);
+
+            // Check if node has room or reached max depth
+            if (node_ref.entities.len() < self.config.node_capacity && !node_ref.is_subdivided())
+                || node_ref.depth == node_ref.max_depth
+            {
+                self.add(node, value, shape, entity_type);
+                return node;
+            }
+
+            // Subdivide node if needed
+            if !node_ref.is_subdivided() && node_ref.depth < node_ref.max_depth {
+                self.subdivide(node);
+                continue;
+            }
+
+            let destination = self.get_destination_node(node, &shape);
+            if destination == node {
+                self.add(node, value, shape, entity_type);
+                return node;
+            }
+
+            // Move to the next node for insertion
+            node = destination;
+        }
+    }
+
+    // Determine which child node the shape belongs to
+    fn get_destination_node(&self, node: NodeId, shape: &ShapeEnum) -> NodeId {
+        // Points never straddle quadrants, so their quadrant follows from the node center
+        if let ShapeEnum::Point(point) = shape {
+            let node_ref = self.node(node);
+            return match node_ref.children {
+                Some(children)
+                    if collision_detection::point_rectangle(point, &node_ref.bounding_box) =>
+                {
+                    // The south-east quadrant starts at the split point
+                    let split = self.node(children[3]).bounding_box;
+                    let east = point.x >= split.x;
+                    let south = point.y >= split.y;
+                    children[east as usize + 2 * south as usize]
+                }
+                _ => node,
+            };
+        }
+
+        let bounding_box = shape.bounding_box();
+        self.node(node)
+            .child_ids()
+            .find(|&child| {
+                collision_detection::rectangle_contains_rectangle(
+                    &self.node(child).loose_bounding_box,
+                    &bounding_box,
+                )
+            })
+            .unwrap_or(node)
+    }
+
+    fn add(&mut self, node: NodeId, value: u32, shape: ShapeEnum, entity_type: Option<u32>) {
+        self.node_mut(node)
+            .entities
+            .insert(value, Entity::new(shape, entity_type));
+        self.owner_map.insert(value, node);
+    }
+
+    // Remove an entry, returning whether there was one with this value
+    // Panics if the entry is in a locked region, see try_delete
+    pub fn delete(&mut self, value: u32) -> bool {
+        match self.try_delete(value) {
+            Ok(existed) => existed,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Like delete, returning an error instead if the entry is in a locked region
+    pub fn try_delete(&mut self, value: u32) -> Result<bool, QuadTreeError> {
+        self.check_unlocked(value, None)?;
+        Ok(self.delete_unlocked(value))
+    }
+
+    fn delete_unlocked(&mut self, value: u32) -> bool {
+        match self.owner_map.remove(&value) {
+            Some(node) => {
+                self.recorder.record(|| TraceEvent::Delete(value));
+                Counters::add(&self.counters.deletes, 1);
+                self.delete_from(node, value);
+                // Clean up the node and its ancestors after deleting an item
+                self.clean_upwards(node);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Hand an entry over to another tree, such as the tree of a neighbouring zone, keeping
+    // its shape and entity type. Either both trees change or neither does: the entry stays
+    // here if it is locked in this tree or the target rejects it. Returns false when this
+    // tree has no entry with the value.
+    pub fn move_entity(
+        &mut self,
+        value: u32,
+        target: &mut QuadTree,
+    ) -> Result<bool, QuadTreeError> {
+        let (shape, entity_type) = match self.entity(value) {
+            Some(entity) => (entity.shape(), entity.entity_type),
+            None => return Ok(false),
+        };
+        self.check_unlocked(value, None)?;
+        target.try_insert(value, shape, entity_type)?;
+        self.delete_unlocked(value);
+        Ok(true)
+    }
+
+    fn delete_from(&mut self, node: NodeId, value: u32) {
+        // Remove the item from the QuadNode's items
+        self.node_mut(node).entities.remove(&value);
+    }
+
+    // Subdivide a node into quadrants
+    fn subdivide(&mut self, node: NodeId) {
+        let (bounding_box, depth, max_depth, is_root) = {
+            let node_ref = self.node(node);
+            (
+                node_ref.bounding_box,
+                node_ref.depth,
+                node_ref.max_depth,
+                node_ref.parent.is_none(),
+            )
+        };
+
+        // Nodes are halved, except roots split at the configured point
+        let split = match self.config.root_split {
+            Some(split)
+                if is_root
+                    && split.x > bounding_box.x
+                    && split.x < bounding_box.right()
+                    && split.y > bounding_box.y
+                    && split.y < bounding_box.bottom() =>
+            {
+                split
+            }
+            _ => Point::new(
+                bounding_box.x + bounding_box.width / 2.0,
+                bounding_box.y + bounding_box.height / 2.0,
+            ),
+        };
+
+        // Compute coordinates for the new quadrants
+        let (west, east) = (
+            (bounding_box.x, split.x - bounding_box.x),
+            (split.x, bounding_box.right() - split.x),
+        );
+        let (north, south) = (
+            (bounding_box.y, split.y - bounding_box.y),
+            (split.y, bounding_box.bottom() - split.y),
+        );
+
+        // Create new quadrants
+        let quadrants = [(west, north), (east, north), (west, south), (east, south)];
+        let children = quadrants.map(|((x, width), (y, height))| {
+            self.allocate_node(
+                Rectangle {
+                    x,
+                    y,
+                    width,
+                    height,
+                },
+                Some(node),
+                depth + 1,
+                max_depth,
+            )
+        });
+        self.node_mut(node).children = Some(children);
+        self.splits += 1;
+        Counters::add(&self.counters.splits, 1);
+
+        // Redistribute the items to the appropriate quadrants
+        let old_items = self
+            .node_mut(node)
+            .entities
+            .drain()
+            .collect::<Vec<(u32, Entity)>>();
+        for (value, entity) in old_items {
+            self.owner_map.remove(&value);
+            self.insert_into(node, value, entity.shape(), entity.entity_type);
+        }
+    }
+
+    pub fn collisions_batch(&self, shapes: Vec<ShapeEnum>) -> Vec<Vec<u32>> {
+        self.collisions_batch_filter(shapes, None)
+    }
+
+    // Run a collision query per shape. With the `parallel` feature the queries are spread
+    // across the rayon thread pool, since they only read the tree.
+    pub fn collisions_batch_filter(
+        &self,
+        shapes: Vec<ShapeEnum>,
+        filter_entity_types: Option<Vec<u32>>,
+    ) -> Vec<Vec<u32>> {
+        self.collisions_batch_cancellable(shapes, filter_entity_types, &CancellationToken::new())
+            .unwrap_or_default()
+    }
+
+    // Like collisions_batch_filter, but gives up with Cancelled once the token is
+    // cancelled. Queries still pending at that point are skipped.
+    pub fn collisions_batch_cancellable(
+        &self,
+        shapes: Vec<ShapeEnum>,
+        filter_entity_types: Option<Vec<u32>>,
+        token: &CancellationToken,
+    ) -> Result<Vec<Vec<u32>>, Cancelled> {
+        let filter = TypeFilter::new(filter_entity_types.as_deref());
+
+        #[cfg(feature = "parallel")]
+        let shapes = shapes.into_par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let shapes = shapes.into_iter();
+
+        let collisions = shapes
+            .map(|shape| {
+                let mut collisions = Vec::new();
+                if !token.is_cancelled() {
+                    self.record_collisions(&shape, filter);
+                    self.collisions_into(&shape, filter, &mut collisions);
+                }
+                collisions
+            })
+            .collect();
+        if token.is_cancelled() {
+            return Err(Cancelled);
+        }
+        Ok(collisions)
+    }
+
+    pub fn collisions(&self, shape: ShapeEnum, collisions: &mut Vec<u32>) {
+        self.collisions_filter(shape, None, collisions);
+    }
+
+    pub fn collisions_filter(
+        &self,
+        shape: ShapeEnum,
+        filter_entity_types: Option<Vec<u32>>,
+        collisions: &mut Vec<u32>,
+    ) {
+        let filter = TypeFilter::new(filter_entity_types.as_deref());
+        self.record_collisions(&shape, filter);
+        self.collisions_into(&shape, filter, collisions);
+    }
+
+    // Visit each entity colliding with a shape without collecting them into a Vec.
+    // Returning ControlFlow::Break from the callback stops the search, and the break is
+    // passed back to the caller.
+    pub fn collisions_with<F>(
+        &self,
+        shape: &ShapeEnum,
+        filter_entity_types: Option<&[u32]>,
+        visit: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(u32) -> ControlFlow<()>,
+    {
+        let filter = TypeFilter::new(filter_entity_types);
+        self.record_collisions(shape, filter);
+        self.collisions_with_filter(shape, filter, visit)
+    }
+
+    // Find collisions with entities whose type bit is set in category_mask, in the way
+    // physics engines express collision layers. Entity type n maps to bit n, so only
+    // types below 64 can be matched.
+    pub fn collisions_mask(&self, shape: ShapeEnum, category_mask: u64, collisions: &mut Vec<u32>) {
+        let filter = TypeFilter::Mask(category_mask);
+        self.record_collisions(&shape, filter);
+        self.collisions_into(&shape, filter, collisions);
+    }
+
+    pub fn collisions_with_mask<F>(
+        &self,
+        shape: &ShapeEnum,
+        category_mask: u64,
+        visit: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(u32) -> ControlFlow<()>,
+    {
+        let filter = TypeFilter::Mask(category_mask);
+        self.record_collisions(shape, filter);
+        self.collisions_with_filter(shape, filter, visit)
+    }
+
+    fn collisions_with_filter<F>(
+        &self,
+        shape: &ShapeEnum,
+        filter: TypeFilter,
+        mut visit: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(u32) -> ControlFlow<()>,
+    {
+        // Results from every size class are merged into the same walk. Visited nodes are
+        // tallied locally and added once, so parallel queries rarely touch the shared counter.
+        let mut nodes_visited = 0;
+        let mut flow = ControlFlow::Continue(());
+        for &root in &self.roots {
+            flow = self.collisions_with_from(root, shape, filter, &mut visit, &mut nodes_visited);
+            if flow.is_break() {
+                break;
+            }
+        }
+        Counters::add(&self.counters.nodes_visited, nodes_visited);
+        flow
+    }
+
+    // Check whether anything collides with a shape, stopping at the first hit
+    pub fn any_collision(&self, shape: &ShapeEnum, filter_entity_types: Option<&[u32]>) -> bool {
+        let filter = TypeFilter::new(filter_entity_types);
+        self.record_collisions(shape, filter);
+        self.has_collision(shape, filter)
+    }
+
+    // any_collision without recording, for queries that probe the tree many times
+    fn has_collision(&self, shape: &ShapeEnum, filter: TypeFilter) -> bool {
+        self.collisions_with_filter(shape, filter, |_| ControlFlow::Break(()))
+            .is_break()
+    }
+
+    // Queries are recorded once at the public entry point, not per internal probe
+    fn record_collisions(&self, shape: &ShapeEnum, filter: TypeFilter) {
+        self.recorder.record(|| TraceEvent::Collisions {
+            shape: shape.clone(),
+            filter: filter.to_trace(),
+        });
+    }
+
+    fn collisions_into(&self, shape: &ShapeEnum, filter: TypeFilter, collisions: &mut Vec<u32>) {
+        let _ = self.collisions_with_filter(shape, filter, |value| {
+            collisions.push(value);
+            ControlFlow::Continue(())
+        });
+    }
+
+    // Find collisions with a given shape in the QuadTree
+    // Helper method to recursively find collisions in the tree
+    fn collisions_with_from(
+        &self,
+        node: NodeId,
+        query_shape: &ShapeEnum,
+        filter: TypeFilter,
+        visit: &mut dyn FnMut(u32) -> ControlFlow<()>,
+        nodes_visited: &mut u64,
+    ) -> ControlFlow<()> {
+        *nodes_visited += 1;
+
+        // Compute the bounding box of the query shape
+        let query_shape_bounding_box = query_shape.bounding_box();
+
+        // Check for collisions with shapes in the current node
+        let node_ref = self.node(node);
+        for (&value, entity) in node_ref.entities.iter() {
+            // Apply the entity type filter, skipping untyped entities unless it matches all
+            if !filter.matches(entity) {
+                continue;
+            }
+
+            if self.shapes_collide(query_shape, &entity.shape()) {
+                visit(value)?;
+            }
+        }
+
+        // Continue with child nodes if the node has been subdivided. Nodes that only touch
+        // the query are still visited, since points on their edge can collide with it.
+        for child in node_ref.child_ids() {
+            if collision_detection::rectangle_touches_rectangle(
+                &self.node(child).loose_bounding_box,
+                &query_shape_bounding_box,
+            ) {
+                self.collisions_with_from(child, query_shape, filter, visit, nodes_visited)?;
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    // Coarse collision query for planning, where exact membership is not needed.
+    // Entities above max_depth are tested exactly and land in collisions, while nodes at
+    // max_depth that touch the shape are reported whole in summaries without being searched.
+    pub fn collisions_coarse(
+        &self,
+        shape: ShapeEnum,
+        max_depth: usize,
+        collisions: &mut Vec<u32>,
+        summaries: &mut Vec<NodeSummary>,
+    ) {
+        self.recorder.record(|| TraceEvent::CollisionsCoarse {
+            shape: shape.clone(),
+            max_depth,
+        });
+        let query_shape_bounding_box = shape.bounding_box();
+        let mut stack = self.roots.clone();
+        let mut nodes_visited = 0;
+        while let Some(node) = stack.pop() {
+            nodes_visited += 1;
+            let node_ref = self.node(node);
+            if node_ref.depth >= max_depth {
+                let entity_count = self.count_all_items(node);
+                if entity_count > 0 {
+                    summaries.push(NodeSummary {
+                        depth: node_ref.depth,
+                        bounding_box: node_ref.bounding_box,
+                        entity_count,
+                    });
+                }
+                continue;
+            }
+
+            for (&value, entity) in node_ref.entities.iter() {
+                if self.shapes_collide(&shape, &entity.shape()) {
+                    collisions.push(value);
+                }
+            }
+            stack.extend(node_ref.child_ids().filter(|&child| {
+                collision_detection::rectangle_touches_rectangle(
+                    &self.node(child).loose_bounding_box,
+                    &query_shape_bounding_box,
+                )
+            }));
+        }
+        Counters::add(&self.counters.nodes_visited, nodes_visited);
+    }
+
+    // Find the closest location to a point where a circle of the given radius fits
+    // inside the tree bounds without intersecting any entity.
+    // Nodes are visited closest-first and a node is skipped as soon as it cannot beat the
+    // best location found so far, so empty regions near the point resolve almost immediately.
+    pub fn nearest_free_point(&self, point: Point, clearance_radius: Float) -> Option<Point> {
+        self.recorder.record(|| TraceEvent::NearestFreePoint {
+            point,
+            clearance_radius,
+        });
+        self.find_free_point(point, clearance_radius)
+    }
+
+    fn find_free_point(&self, point: Point, clearance_radius: Float) -> Option<Point> {
+        // Only centers at least clearance_radius away from the tree edges are valid
+        let root = self.roots[0];
+        let domain = shrink_rectangle(&self.node(root).bounding_box, clearance_radius)?;
+
+        let mut best: Option<(Float, Point)> = None;
+        let mut budget = MAX_FREE_POINT_SAMPLES;
+        let mut queue = BinaryHeap::new();
+        queue.push(SearchCandidate {
+            distance_sq: rectangle_distance_squared(&domain, &point),
+            item: root,
+        });
+
+        while let Some(SearchCandidate {
+            distance_sq,
+            item: node,
+        }) = queue.pop()
+        {
+            if best.is_some_and(|(best_sq, _)| distance_sq >= best_sq) {
+                break;
+            }
+
+            let node_ref = self.node(node);
+            if node_ref.is_subdivided() {
+                for child in node_ref.child_ids() {
+                    if let Some(region) =
+                        intersect_rectangles(&self.node(child).bounding_box, &domain)
+                    {
+                        queue.push(SearchCandidate {
+                            distance_sq: rectangle_distance_squared(&region, &point),
+                            item: child,
+                        });
+                    }
+                }
+                continue;
+            }
+
+            let Some(region) = intersect_rectangles(&node_ref.bounding_box, &domain) else {
+                continue;
+            };
+            if let Some(found) =
+                self.nearest_free_point_in(&region, point, clearance_radius, best, &mut budget)
+            {
+                best = Some(found);
+            }
+            // Give up rather than return a point that may not be the nearest one
+            if budget == 0 {
+                return None;
+            }
+        }
+
+        best.map(|(_, found)| found)
+    }
+
+    // Pick a random location in a region where a circle of radius min_clearance fits
+    // inside the region without touching any entity of the given types (or any entity when
+    // no types are given). rng must return uniform values in [0, 1).
+    // Leaves are drawn in proportion to their area, discounted by the entries they hold, so
+    // crowded cells are tried less. If every sample is blocked, the nearest free location to
+    // a random point is used, and None is returned when that also fails.
+    pub fn spawn_position(
+        &self,
+        region: &Rectangle,
+        min_clearance: Float,
+        filter_entity_types: Option<&[u32]>,
+        mut rng: impl FnMut() -> Float,
+    ) -> Option<Point> {
+        let domain = shrink_rectangle(region, min_clearance)?;
+        let is_free = |candidate: &Point| {
+            let clearance = ShapeEnum::Circle(Circle::new(candidate.x, candidate.y, min_clearance));
+            !self.has_collision(&clearance, TypeFilter::new(filter_entity_types))
+        };
+
+        let mut cells = Vec::new();
+        let mut stack = vec![self.roots[0]];
+        while let Some(node) = stack.pop() {
+            let node_ref = self.node(node);
+            let Some(cell) = intersect_rectangles(&node_ref.bounding_box, &domain) else {
+                continue;
+            };
+            if node_ref.is_subdivided() {
+                stack.extend(node_ref.child_ids());
+            } else {
+                let weight = cell.width * cell.height / (1 + node_ref.entities.len()) as Float;
+                cells.push((cell, weight));
+            }
+        }
+        // Regions outside the tree bounds hold no nodes and are sampled directly
+        if cells.is_empty() {
+            cells.push((domain, 1.0));
+        }
+        let total_weight: Float = cells.iter().map(|(_, weight)| weight).sum();
+
+        for _ in 0..SPAWN_ATTEMPTS {
+            let mut pick = rng() * total_weight;
+            let (cell, _) = cells
+                .iter()
+                .find(|(_, weight)| {
+                    pick -= weight;
+                    pick < 0.0
+                })
+                .unwrap_or(&cells[cells.len() - 1]);
+            let candidate = random_point_in(cell, &mut rng);
+            if is_free(&candidate) {
+                return Some(candidate);
+            }
+        }
+
+        let found = self.find_free_point(random_point_in(&domain, &mut rng), min_clearance)?;
+        let in_domain = (domain.x..=domain.right()).contains(&found.x)
+            && (domain.y..=domain.bottom()).contains(&found.y);
+        (in_domain && is_free(&found)).then_some(found)
+    }
+
+    // Search a leaf region for a free location closer than the current best. Every probe
+    // is taken from budget, and the search stops early once the budget runs out.
+    fn nearest_free_point_in(
+        &self,
+        region: &Rectangle,
+        point: Point,
+        clearance_radius: Float,
+        best: Option<(Float, Point)>,
+        budget: &mut usize,
+    ) -> Option<(Float, Point)> {
+        let budget = Cell::from_mut(budget);
+        let is_free = |candidate: &Point| {
+            budget.set(budget.get().saturating_sub(1));
+            let clearance =
+                ShapeEnum::Circle(Circle::new(candidate.x, candidate.y, clearance_radius));
+            !self.has_collision(&clearance, TypeFilter::All)
+        };
+
+        // The closest point of the region is the answer whenever it is free
+        let closest = Point::new(
+            point.x.clamp(region.x, region.right()),
+            point.y.clamp(region.y, region.bottom()),
+        );
+        if is_free(&closest) {
+            return Some((closest.distance_squared(&point), closest));
+        }
+
+        // Otherwise sample the region on a grid fine enough not to skip gaps wider than
+        // the clearance circle. Large leaves are split into tiles of at most
+        // MAX_SAMPLES_PER_AXIS samples per axis, generated lazily nearest tile first, so the
+        // sample spacing never has to grow with the leaf size and huge leaves cost nothing
+        // beyond the tiles actually searched.
+        let step = if clearance_radius > 0.0 {
+            clearance_radius / 2.0
+        } else {
+            region.width.max(region.height) / (MAX_SAMPLES_PER_AXIS - 1) as Float
+        };
+        let tile_length = step * (MAX_SAMPLES_PER_AXIS - 1) as Float;
+        let tile_count = |length: Float| {
+            if tile_length > 0.0 {
+                ((length / tile_length).ceil() as usize).max(1)
+            } else {
+                1
+            }
+        };
+        let tile_index = |offset: Float, count: usize| {
+            if tile_length > 0.0 {
+                ((offset / tile_length) as usize).min(count - 1)
+            } else {
+                0
+            }
+        };
+        let (tile_columns, tile_rows) = (tile_count(region.width), tile_count(region.height));
+        let tile = |column: usize, row: usize| {
+            let x = region.x + tile_length * column as Float;
+            let y = region.y + tile_length * row as Float;
+            Rectangle::new(
+                x,
+                y,
+                (region.right() - x).min(tile_length),
+                (region.bottom() - y).min(tile_length),
+            )
+        };
+
+        // Tiles are flooded outwards from the one holding the closest point. A tile's
+        // distance never drops below that of the neighbour it was reached from, so popping
+        // the queue visits tiles in nearest-first order.
+        let start = (
+            tile_index(closest.x - region.x, tile_columns),
+            tile_index(closest.y - region.y, tile_rows),
+        );
+        let mut tiles = BinaryHeap::new();
+        let mut queued = HashSet::new();
+        queued.insert(start);
+        tiles.push(SearchCandidate {
+            distance_sq: rectangle_distance_squared(&tile(start.0, start.1), &point),
+            item: start,
+        });
+
+        let samples = |length: Float| {
+            if step > 0.0 {
+                ((length / step).ceil() as usize + 1).min(MAX_SAMPLES_PER_AXIS)
+            } else {
+                1
+            }
+        };
+        let axis = |start: Float, length: Float, count: usize, i: usize| {
+            if count > 1 {
+                start + length * i as Float / (count - 1) as Float
+            } else {
+                start + length / 2.0
+            }
+        };
+
+        let mut limit = best.map(|(best_sq, _)| best_sq);
+        let mut found: Option<Point> = None;
+        while let Some(SearchCandidate {
+            distance_sq: tile_distance_sq,
+            item: (tile_column, tile_row),
+        }) = tiles.pop()
+        {
+            if budget.get() == 0 || limit.is_some_and(|limit_sq| tile_distance_sq >= limit_sq) {
+                break;
+            }
+            let neighbours = [
+                (tile_column.wrapping_sub(1), tile_row),
+                (tile_column + 1, tile_row),
+                (tile_column, tile_row.wrapping_sub(1)),
+                (tile_column, tile_row + 1),
+            ];
+            for (column, row) in neighbours {
+                if column < tile_columns && row < tile_rows && queued.insert((column, row)) {
+                    tiles.push(SearchCandidate {
+                        distance_sq: rectangle_distance_squared(&tile(column, row), &point),
+                        item: (column, row),
+                    });
+                }
+            }
+
+            let tile = tile(tile_column, tile_row);
+            let (columns, rows) = (samples(tile.width), samples(tile.height));
+            let mut candidates: Vec<(Float, Point)> = (0..columns)
+                .flat_map(|column| (0..rows).map(move |row| (column, row)))
+                .map(|(column, row)| {
+                    let candidate = Point::new(
+                        axis(tile.x, tile.width, columns, column),
+                        axis(tile.y, tile.height, rows, row),
+                    );
+                    (candidate.distance_squared(&point), candidate)
+                })
+                .collect();
+            candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+            if let Some((distance_sq, candidate)) = candidates
+                .into_iter()
+                .take_while(|(distance_sq, _)| limit.is_none_or(|limit_sq| *distance_sq < limit_sq))
+                .find(|(_, candidate)| budget.get() > 0 && is_free(candidate))
+            {
+                limit = Some(distance_sq);
+                found = Some(candidate);
+            }
+        }
+        let mut free = found?;
+
+        // Refine the sample by bisecting towards the blocked closest point, which stays inside
+        // the region and only ever moves the result closer to the query point
+        let mut blocked = closest;
+        for _ in 0..REFINEMENT_STEPS {
+            let middle = Point::new((blocked.x + free.x) / 2.0, (blocked.y + free.y) / 2.0);
+            if is_free(&middle) {
+                free = middle;
+            } else {
+                blocked = middle;
+            }
+        }
+        Some((free.distance_squared(&point), free))
+    }
+
+    // Find every entry the shape touches while moving at velocity for dt, along with the
+    // time of impact in [0, dt]. Hits are sorted by time of impact, so the first one is
+    // where the motion should stop to avoid tunneling through thin obstacles.
+    pub fn collisions_swept(
+        &self,
+        shape: &ShapeEnum,
+        velocity: (Float, Float),
+        dt: Float,
+    ) -> Vec<SweptHit> {
+        self.recorder.record(|| TraceEvent::CollisionsSwept {
+            shape: shape.clone(),
+            velocity,
+            dt,
+        });
+        let dx = velocity.0 * dt;
+        let dy = velocity.1 * dt;
+        let start = shape.bounding_box();
+        let swept_box = Rectangle::new(
+            start.x + dx.min(0.0),
+            start.y + dy.min(0.0),
+            start.width + dx.abs(),
+            start.height + dy.abs(),
+        );
+
+        let mut hits = Vec::new();
+        let mut stack = self.roots.clone();
+        let mut nodes_visited = 0;
+        while let Some(node) = stack.pop() {
+            nodes_visited += 1;
+            let node_ref = self.node(node);
+            for (&value, entity) in &node_ref.entities {
+                if !collision_detection::rectangle_touches_rectangle(
+                    &swept_box,
+                    &entity.bounding_box(),
+                ) {
+                    continue;
+                }
+                if let Some(t) = self.time_of_impact(shape, dx, dy, &entity.shape()) {
+                    hits.push(SweptHit {
+                        value,
+                        time_of_impact: t * dt,
+                    });
+                }
+            }
+
+            // Zero-area swept boxes, such as a point moving along an axis, only touch
+            for child in node_ref.child_ids() {
+                if collision_detection::rectangle_touches_rectangle(
+                    &swept_box,
+                    &self.node(child).loose_bounding_box,
+                ) {
+                    stack.push(child);
+                }
+            }
+        }
+        Counters::add(&self.counters.nodes_visited, nodes_visited);
+        hits.sort_by(|a, b| {
+            a.time_of_impact
+                .total_cmp(&b.time_of_impact)
+                .then(a.value.cmp(&b.value))
+        });
+        hits
+    }
+
+    // For each pair of points, check that the segment between them is not blocked by any
+    // entity of the given types (or any entity at all when no types are given)
+    pub fn line_of_sight_batch(
+        &self,
+        pairs: &[(Point, Point)],
+        blocker_types: Option<Vec<u32>>,
+    ) -> Vec<bool> {
+        let blocker_types = TypeFilter::new(blocker_types.as_deref());
+        for (start, end) in pairs {
+            self.recorder.record(|| TraceEvent::LineOfSight {
+                start: *start,
+                end: *end,
+                filter: blocker_types.to_trace(),
+            });
+        }
+
+        // The traversal stack is reused across pairs (per worker thread when parallel)
+        // to avoid an allocation per query
+        #[cfg(feature = "parallel")]
+        return pairs
+            .par_iter()
+            .map_init(Vec::new, |stack, (start, end)| {
+                self.line_of_sight(start, end, blocker_types, stack)
+            })
+            .collect();
+
+        #[cfg(not(feature = "parallel"))]
+        {
+            let mut stack = Vec::new();
+            pairs
+                .iter()
+                .map(|(start, end)| self.line_of_sight(start, end, blocker_types, &mut stack))
+                .collect()
+        }
+    }
+
+    fn line_of_sight(
+        &self,
+        start: &Point,
+        end: &Point,
+        blocker_types: TypeFilter,
+        stack: &mut Vec<NodeId>,
+    ) -> bool {
+        stack.clear();
+        stack.extend(&self.roots);
+        let mut nodes_visited = 0;
+        let mut clear = true;
+        'search: while let Some(node) = stack.pop() {
+            nodes_visited += 1;
+            let node_ref = self.node(node);
+            for entity in node_ref.entities.values() {
+                if !blocker_types.matches(entity) {
+                    continue;
+                }
+                if self.segment_blocked(start, end, &entity.shape()) {
+                    clear = false;
+                    break 'search;
+                }
+            }
+
+            // Degenerate blockers can lie on a child's edge, so touching the edge is enough
+            for child in node_ref.child_ids() {
+                if collision_detection::segment_touches_rectangle(
+                    start,
+                    end,
+                    &self.node(child).loose_bounding_box,
+                ) {
+                    stack.push(child);
+                }
+            }
+        }
+        Counters::add(&self.counters.nodes_visited, nodes_visited);
+        clear
+    }
+
+    // Find every pair of entries whose shapes are at most max_distance apart, returned as
+    // (smaller id, larger id, distance) edges sorted by id
+    pub fn proximity_graph(&self, max_distance: Float) -> Vec<(u32, u32, Float)> {
+        self.proximity_graph_cancellable(max_distance, &CancellationToken::new())
+            .unwrap_or_default()
+    }
+
+    // Like proximity_graph, but gives up with Cancelled once the token is cancelled
+    pub fn proximity_graph_cancellable(
+        &self,
+        max_distance: Float,
+        token: &CancellationToken,
+    ) -> Result<Vec<(u32, u32, Float)>, Cancelled> {
+        self.recorder
+            .record(|| TraceEvent::ProximityGraph(max_distance));
+        let entries = self.entries();
+
+        #[cfg(feature = "parallel")]
+        let entries_iter = entries.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let entries_iter = entries.iter();
+
+        let mut edges: Vec<(u32, u32, Float)> = entries_iter
+            .flat_map(|&(value, entity)| {
+                let mut neighbors = Vec::new();
+                if token.is_cancelled() {
+                    return neighbors;
+                }
+                let mut visit = |other: u32, _: &Entity, distance: Float| {
+                    // Each pair is reported once, from the entry with the smaller id
+                    if other > value {
+                        neighbors.push((value, other, distance));
+                    }
+                };
+                self.within_distance(&entity.shape(), max_distance, &mut visit);
+                neighbors
+            })
+            .collect();
+        if token.is_cancelled() {
+            return Err(Cancelled);
+        }
+        edges.sort_by_key(|&(a, b, _)| (a, b));
+        Ok(edges)
+    }
+
+    // Match every entry of this tree with the entries of another tree satisfying the
+    // predicate, returned as (id in this tree, id in other tree) pairs sorted by id.
+    // With the `parallel` feature the entries of this tree are split across threads.
+    pub fn spatial_join(&self, other: &QuadTree, predicate: JoinPredicate) -> Vec<(u32, u32)> {
+        self.spatial_join_cancellable(other, predicate, &CancellationToken::new())
+            .unwrap_or_default()
+    }
+
+    // Like spatial_join, but gives up with Cancelled once the token is cancelled
+    pub fn spatial_join_cancellable(
+        &self,
+        other: &QuadTree,
+        predicate: JoinPredicate,
+        token: &CancellationToken,
+    ) -> Result<Vec<(u32, u32)>, Cancelled> {
+        let entries = self.entries();
+
+        #[cfg(feature = "parallel")]
+        let entries_iter = entries.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let entries_iter = entries.iter();
+
+        let mut pairs: Vec<(u32, u32)> = entries_iter
+            .flat_map(|&(value, entity)| {
+                let mut matches = Vec::new();
+                if token.is_cancelled() {
+                    return Vec::new();
+                }
+                match predicate {
+                    JoinPredicate::Intersects => {
+                        other.collisions_into(&entity.shape(), TypeFilter::All, &mut matches)
+                    }
+                    JoinPredicate::WithinDistance(max_distance) => other.within_distance(
+                        &entity.shape(),
+                        max_distance,
+                        &mut |other_value, _, _| matches.push(other_value),
+                    ),
+                }
+                matches
+                    .into_iter()
+                    .map(|other_value| (value, other_value))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        if token.is_cancelled() {
+            return Err(Cancelled);
+        }
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+
+    // Group entries with DBSCAN: entries with at least min_points entries (themselves
+    // included) within eps are core points, and clusters grow through chains of core points.
+    // Each entry is labelled with its cluster, or None for noise. Entries are visited in id
+    // order so labels are stable between calls.
+    pub fn cluster(&self, eps: Float, min_points: usize) -> HashMap<u32, Option<usize>> {
+        self.recorder
+            .record(|| TraceEvent::Cluster { eps, min_points });
+        let mut entries = self.entries();
+        entries.sort_by_key(|&(value, _)| value);
+
+        let neighbors_of = |shape: &ShapeEnum| {
+            let mut neighbors = Vec::new();
+            self.within_distance(shape, eps, &mut |other, _, _| neighbors.push(other));
+            neighbors
+        };
+
+        let mut labels: HashMap<u32, Option<usize>> = HashMap::with_capacity(entries.len());
+        let mut next_cluster = 0;
+        for (value, entity) in entries {
+            if labels.contains_key(&value) {
+                continue;
+            }
+            let neighbors = neighbors_of(&entity.shape());
+            if neighbors.len() < min_points {
+                labels.insert(value, None);
+                continue;
+            }
+
+            let cluster = next_cluster;
+            next_cluster += 1;
+            labels.insert(value, Some(cluster));
+
+            // Expand the cluster from the core point
+            let mut pending = neighbors;
+            while let Some(neighbor) = pending.pop() {
+                match labels.get(&neighbor) {
+                    Some(Some(_)) => continue,
+                    // Noise reachable from a core point becomes a border point
+                    Some(None) => {
+                        labels.insert(neighbor, Some(cluster));
+                        continue;
+                    }
+                    None => {
+                        labels.insert(neighbor, Some(cluster));
+                    }
+                }
+                let neighbor_shape = self
+                    .entity(neighbor)
+                    .expect("Neighbor should be stored in the tree")
+                    .shape();
+                let neighbor_neighbors = neighbors_of(&neighbor_shape);
+                if neighbor_neighbors.len() >= min_points {
+                    pending.extend(neighbor_neighbors);
+                }
+            }
+        }
+        labels
+    }
+
+    // Look up the stored entry for a value
+    fn entity(&self, value: u32) -> Option<&Entity> {
+        let node = *self.owner_map.get(&value)?;
+        self.node(node).entities.get(&value)
+    }
+
+    // Visit every entry whose shape is at most max_distance away from the query shape
+    fn within_distance(
+        &self,
+        query_shape: &ShapeEnum,
+        max_distance: Float,
+        visit: &mut dyn FnMut(u32, &Entity, Float),
+    ) {
+        for &root in &self.roots {
+            self.within_distance_from(root, query_shape, max_distance, visit);
+        }
+    }
+
+    fn within_distance_from(
+        &self,
+        node: NodeId,
+        query_shape: &ShapeEnum,
+        max_distance: Float,
+        visit: &mut dyn FnMut(u32, &Entity, Float),
+    ) {
+        let node_ref = self.node(node);
+        for (&value, entity) in node_ref.entities.iter() {
+            let Some(distance) = self.shape_distance(query_shape, &entity.shape()) else {
+                continue;
+            };
+            if distance <= max_distance {
+                visit(value, entity, distance);
+            }
+        }
+
+        let search_box = expand_rectangle(&query_shape.bounding_box(), max_distance);
+        for child in node_ref.child_ids() {
+            if collision_detection::rectangle_touches_rectangle(
+                &self.node(child).loose_bounding_box,
+                &search_box,
+            ) {
+                self.within_distance_from(child, query_shape, max_distance, visit);
+            }
+        }
+    }
+
+    // Collect references to every entry in the tree
+    fn entries(&self) -> Vec<(u32, &Entity)> {
+        let mut entries = Vec::with_capacity(self.owner_map.len());
+        let mut stack = self.roots.clone();
+        while let Some(node) = stack.pop() {
+            let node_ref = self.node(node);
+            entries.extend(
+                node_ref
+                    .entities
+                    .iter()
+                    .map(|(&value, entity)| (value, entity)),
+            );
+            stack.extend(node_ref.child_ids());
+        }
+        entries
+    }
+
+    // Panics if the configuration rejects any of the shapes, see try_relocate_batch
+    pub fn relocate_batch(&mut self, relocation_requests: Vec<RelocationRequest>) {
+        if let Err(err) = self.try_relocate_batch(relocation_requests) {
+            panic!("{}", err);
+        }
+    }
+
+    // Like relocate_batch, returning an error instead, with nothing moved, if the
+    // configuration rejects any of the shapes
+    pub fn try_relocate_batch(
+        &mut self,
+        relocation_requests: Vec<RelocationRequest>,
+    ) -> Result<(), QuadTreeError> {
+        // Reject the whole batch before anything is moved
+        for request in relocation_requests.iter() {
+            self.validate_shape(request.value, &request.shape)?;
+        }
+        for request in relocation_requests {
+            self.try_relocate(request.value, request.shape, request.entity_type)?;
+        }
+        Ok(())
+    }
+
+    // Change the shape of an entry, keeping its entity type. While the new shape still fits
+    // the entry's node the stored shape is overwritten in place, otherwise the entry moves
+    // as with relocate. Returns false, without inserting, when no entry has this value.
+    // Panics if the configuration rejects the shape, see try_update
+    pub fn update(&mut self, value: u32, shape: ShapeEnum) -> bool {
+        match self.try_update(value, shape) {
+            Ok(existed) => existed,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Like update, returning an error instead if the configuration rejects the shape
+    pub fn try_update(&mut self, value: u32, shape: ShapeEnum) -> Result<bool, QuadTreeError> {
+        let node = match self.owner_map.get(&value) {
+            Some(&node) => node,
+            None => return Ok(false),
+        };
+        self.validate_shape(value, &shape)?;
+
+        let same_class = self.roots.len() == 1 || self.root_of(node) == self.root_for(&shape);
+        if same_class
+            && collision_detection::rectangle_contains_rectangle(
+                &self.node(node).loose_bounding_box,
+                &shape.bounding_box(),
+            )
+        {
+            self.recorder.record(|| TraceEvent::Update {
+                value,
+                shape: shape.clone(),
+            });
+            Counters::add(&self.counters.relocates, 1);
+            if let Some(entity) = self.node_mut(node).entities.get_mut(&value) {
+                entity.shape = StoredShape::new(shape);
+            }
+            return Ok(true);
+        }
+
+        let entity_type = self.node(node).entities[&value].entity_type;
+        self.try_relocate(value, shape, entity_type).map(|_| true)
+    }
+
+    // Move an entry to a new shape. Returns whether an entry with this value existed; when
+    // none did, the shape is inserted as a new entry instead.
+    // Panics if the configuration rejects the shape, see try_relocate
+    pub fn relocate(&mut self, value: u32, shape: ShapeEnum, entity_type: Option<u32>) -> bool {
+        match self.try_relocate(value, shape, entity_type) {
+            Ok(existed) => existed,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // Like relocate, returning an error instead if the configuration rejects the shape
+    pub fn try_relocate(
+        &mut self,
+        value: u32,
+        shape: ShapeEnum,
+        entity_type: Option<u32>,
+    ) -> Result<bool, QuadTreeError> {
+        self.validate_shape(value, &shape)?;
+        Counters::add(&self.counters.relocates, 1);
+        if self.config.auto_expand {
+            self.grow_to(&shape.bounding_box());
+        }
+        if let Some(&node) = self.owner_map.get(&value) {
+            // An entity that changed size class moves to the tree for its new class
+            if self.roots.len() > 1 && self.root_of(node) != self.root_for(&shape) {
+                self.delete_unlocked(value);
+                return self.try_insert(value, shape, entity_type).map(|_| true);
+            }
+
+            self.recorder.record(|| TraceEvent::Relocate {
+                value,
+                shape: shape.clone(),
+                entity_type,
+            });
+
+            // Check if the item still fits in the current node. In a loose tree small moves
+            // rarely leave the expanded node bounds, so this fast path is the common case.
+            let bounding_box = shape.bounding_box();
+            if collision_detection::rectangle_contains_rectangle(
+                &self.node(node).loose_bounding_box,
+                &bounding_box,
+            ) {
+                // Item is still in the correct node, no need to relocate
+                self.add(node, value, shape, entity_type);
+                return Ok(true);
+            }
+
+            // Delete the item from the current node and relocate to the appropriate node
+            self.delete_from(node, value);
+            self.relocate_in(node, value, shape, entity_type);
+            Ok(true)
+        } else {
+            // If the object is not found in the owner_map, insert it into the quadtree
+            self.try_insert(value, shape, entity_type).map(|_| false)
+        }
+    }
+
+    fn relocate_in(
+        &mut self,
+        mut node: NodeId,
         value: u32,
         shape: ShapeEnum,
         entity_type: Option<u32>,
     ) {
         let bounding_box = shape.bounding_box();
-        let root_node = self.root.clone();
         loop {
             // Check if the shape fits within the current node's bounding box
-            let node_bounding_box = node.borrow().bounding_box.clone();
-            if collision_detection::rectangle_contains_rectangle(&node_bounding_box, &bounding_box)
-            {
+            let node_ref = self.node(node);
+            if collision_detection::rectangle_contains_rectangle(
+                &node_ref.loose_bounding_box,
+                &bounding_box,
+            ) {
                 // Find the appropriate child node or keep the current node
-                let destination = self.get_destination_node(&node.borrow(), shape.clone());
-                if Rc::ptr_eq(&destination, &node) {
-                    self.add(&node, value, shape, entity_type);
+                let destination = self.get_destination_node(node, &shape);
+                if destination == node {
+                    self.add(node, value, shape, entity_type);
                     return;
                 }
                 node = destination;
-            } else {
+            } else if let Some(parent) = node_ref.parent {
                 // Move up to the parent node
-                let next_node = node
-                    .borrow()
-                    .parent
-                    .as_ref()
-                    .and_then(|weak| weak.upgrade());
-                if let Some(parent) = next_node {
-                    node = parent;
-                } else {
-                    // Item is outside the bounds of the QuadTree, add it to the root
-                    self.add(&root_node, value, shape, entity_type);
-                    // Clean up the root node and its ancestors
-                    self.clean_upwards(root_node);
-                    return;
-                }
+                node = parent;
+            } else {
+                // Item is outside the bounds of the QuadTree, add it to the root
+                self.add(node, value, shape, entity_type);
+                // Clean up the root node and its ancestors
+                self.clean_upwards(node);
+                return;
             }
         }
     }
 
-    fn clean(&mut self, node: Rc<RefCell<QuadNode>>) {
-        let should_collect_items = {
-            let node_borrow = node.borrow();
-            node_borrow.count_all_items() <= self.config.node_capacity
-        };
+    // Counts all items in the node, including child nodes
+    fn count_all_items(&self, node: NodeId) -> usize {
+        let node_ref = self.node(node);
+        node_ref.entities.len()
+            + node_ref
+                .child_ids()
+                .map(|child| self.count_all_items(child))
+                .sum::<usize>()
+    }
 
-        let child_items: Vec<_> = if should_collect_items {
-            let node_borrow = node.borrow();
-            node_borrow
-                .child_items()
-                .map(|(id, shape)| (id, shape.clone()))
-                .collect()
-        } else {
-            Vec::new()
+    // Collects the items stored in the descendants of a node
+    fn child_items(&self, node: NodeId, items: &mut Vec<(u32, Entity)>) {
+        for child in self.node(node).child_ids() {
+            let child_ref = self.node(child);
+            items.extend(
+                child_ref
+                    .entities
+                    .iter()
+                    .map(|(&id, entity)| (id, entity.clone())),
+            );
+            self.child_items(child, items);
+        }
+    }
+
+    // Merge the subtree below a node back into it once it holds no more than node_capacity
+    // entries. Empty subtrees are merged too, so mass deletions do not leave them behind.
+    fn clean(&mut self, node: NodeId) {
+        if !self.node(node).is_subdivided()
+            || self.count_all_items(node) > self.config.node_capacity
+        {
+            return;
+        }
+
+        let mut child_items = Vec::new();
+        self.child_items(node, &mut child_items);
+        for (value, entity) in child_items {
+            self.owner_map.insert(value, node);
+            self.node_mut(node).entities.insert(value, entity);
+        }
+
+        // Return the child nodes to the object pool, which also marks the node as a leaf
+        if let Some(children) = self.node_mut(node).children.take() {
+            for child in children {
+                self.release_node(child);
+            }
+            self.merges += 1;
+            Counters::add(&self.counters.collapses, 1);
+        }
+    }
+
+    // Clean up the QuadNode and its ancestors
+    fn clean_upwards(&mut self, mut node: NodeId) {
+        loop {
+            self.clean(node);
+            match self.node(node).parent {
+                // Move to the parent node for the next iteration
+                Some(parent) => node = parent,
+                // No more parent nodes, break out of the loop
+                None => break,
+            }
+        }
+    }
+
+    // Merge every underfull subtree into its parent. Relocations only clean up when an entry
+    // leaves the tree bounds, so a long-running session can leave sparse subtrees behind.
+    pub fn rebalance(&mut self) {
+        self.recorder.record(|| TraceEvent::Rebalance);
+        self.merge_underfull();
+    }
+
+    fn merge_underfull(&mut self) {
+        // Deepest nodes first, so a merge can cascade up to the root in one pass
+        let mut subdivided: Vec<(usize, NodeId)> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(id, node)| node.as_ref().map(|node_ref| (node_ref, id)))
+            .filter(|(node_ref, _)| node_ref.is_subdivided())
+            .map(|(node_ref, id)| (node_ref.depth, id))
+            .collect();
+        subdivided.sort_unstable_by(|a, b| b.cmp(a));
+        for (_, node) in subdivided {
+            self.clean(node);
+        }
+    }
+
+    // Rebalance, then give memory held for reuse back to the allocator: pooled nodes,
+    // empty arena slots at the end of the arena and spare capacity in the maps
+    pub fn shrink_to_fit(&mut self) {
+        self.recorder.record(|| TraceEvent::ShrinkToFit);
+        self.merge_underfull();
+        self.quad_node_pool.clear();
+        while matches!(self.nodes.last(), Some(None)) {
+            self.nodes.pop();
+        }
+        let live_slots = self.nodes.len();
+        self.free_slots.retain(|&slot| slot < live_slots);
+        self.nodes.shrink_to_fit();
+        self.free_slots.shrink_to_fit();
+        self.owner_map.shrink_to_fit();
+        for node_ref in self.nodes.iter_mut().flatten() {
+            node_ref.entities.shrink_to_fit();
+        }
+    }
+
+    // Measure how much churn has scattered the tree: holes in the node arena, subdivided
+    // nodes holding too few entries to justify their children, and unused leaf capacity
+    pub fn fragmentation_report(&self) -> FragmentationReport {
+        let live_nodes = self.nodes.iter().flatten().count();
+        let mut report = FragmentationReport {
+            arena_slots: self.nodes.len(),
+            live_nodes,
+            arena_holes: self.nodes.len() - live_nodes,
+            pooled_nodes: self.quad_node_pool.len(),
+            ..Default::default()
         };
 
-        if !child_items.is_empty() {
-            let mut node_borrow_mut = node.borrow_mut();
-            for (value, shape) in child_items {
-                self.owner_map.insert(value, Rc::downgrade(&node));
-                node_borrow_mut.entities.insert(value, shape.clone());
+        let mut leaf_entries = 0;
+        let mut stack = self.roots.clone();
+        while let Some(node) = stack.pop() {
+            let node_ref = self.node(node);
+            if !node_ref.is_subdivided() {
+                report.leaf_count += 1;
+                report.empty_leaves += node_ref.entities.is_empty() as usize;
+                leaf_entries += node_ref.entities.len();
+                continue;
             }
+            // Subtrees clean would merge are counted whole, without looking further down
+            let entries = self.count_all_items(node);
+            if entries <= self.config.node_capacity {
+                report.mergeable_subtrees += 1;
+                report.empty_subtrees += (entries == 0) as usize;
+                report.reclaimable_nodes += self.count_nodes(node) - 1;
+                continue;
+            }
+            stack.extend(node_ref.child_ids());
+        }
 
-            // Helper function to return the child node to the object pool
-            fn return_child_to_pool(
-                pool: &mut ObjectPool<QuadNode>,
-                child: Option<Rc<RefCell<QuadNode>>>,
-            ) {
-                if let Some(child_rc) = child {
-                    if let Ok(node) = Rc::try_unwrap(child_rc) {
-                        pool.return_object(node.into_inner());
+        let leaf_capacity = report.leaf_count * self.config.node_capacity;
+        if leaf_capacity > 0 {
+            report.wasted_capacity =
+                1.0 - leaf_entries.min(leaf_capacity) as f64 / leaf_capacity as f64;
+        }
+        if report.arena_slots > 0 {
+            report.hole_ratio = report.arena_holes as f64 / report.arena_slots as f64;
+        }
+        // Holes and merged-away nodes are what optimize gives back
+        let freed = report.arena_holes + report.reclaimable_nodes;
+        report.optimize_recommended = report.arena_slots > 0
+            && freed as f64 / report.arena_slots as f64 >= OPTIMIZE_THRESHOLD;
+        report
+    }
+
+    // Nodes in the subtree below and including a node
+    fn count_nodes(&self, node: NodeId) -> usize {
+        1 + self
+            .node(node)
+            .child_ids()
+            .map(|child| self.count_nodes(child))
+            .sum::<usize>()
+    }
+
+    // Rebalance, then renumber the nodes so they fill the arena without holes, in
+    // breadth-first order so siblings sit next to each other. Meant for maintenance windows
+    // of long-running servers, see fragmentation_report. Cursors taken before the call
+    // are invalidated.
+    pub fn optimize(&mut self) {
+        self.recorder.record(|| TraceEvent::Optimize);
+        self.merge_underfull();
+
+        let mut new_ids = vec![NodeId::MAX; self.nodes.len()];
+        let mut order = Vec::new();
+        let mut queue: VecDeque<NodeId> = self.roots.iter().copied().collect();
+        while let Some(node) = queue.pop_front() {
+            new_ids[node] = order.len();
+            order.push(node);
+            queue.extend(self.node(node).child_ids());
+        }
+
+        let mut nodes: Vec<Option<QuadNode>> =
+            order.iter().map(|&node| self.nodes[node].take()).collect();
+        for node_ref in nodes.iter_mut().flatten() {
+            node_ref.parent = node_ref.parent.map(|parent| new_ids[parent]);
+            if let Some(children) = node_ref.children.as_mut() {
+                for child in children {
+                    *child = new_ids[*child];
+                }
+            }
+        }
+        self.nodes = nodes;
+        self.free_slots.clear();
+        for root in &mut self.roots {
+            *root = new_ids[*root];
+        }
+        for node in self.owner_map.values_mut() {
+            *node = new_ids[*node];
+        }
+    }
+
+    // Occupancy grids over the tree bounds, one per level with 2^level cells per side, like
+    // the levels of a mipmap. Entries are counted in the cell holding their bounding box
+    // center. Nodes at or below a level's depth are counted whole from their subtree totals
+    // when they fit in one cell. Nodes that straddle cells, as a root split off the center
+    // produces, are searched further and their entries counted one by one.
+    pub fn density_pyramid(&self, levels: usize) -> Vec<DensityGrid> {
+        let bounding_box = self.node(self.roots[0]).bounding_box;
+        (0..levels)
+            .map(|level| {
+                let resolution = 1 << level;
+                let mut grid = DensityGrid {
+                    resolution,
+                    bounding_box,
+                    counts: vec![0; resolution * resolution],
+                };
+                let mut stack = self.roots.clone();
+                while let Some(node) = stack.pop() {
+                    let node_ref = self.node(node);
+                    if node_ref.depth >= level {
+                        let node_box = node_ref.loose_bounding_box;
+                        let cell = grid.cell_index(&Point::new(node_box.x, node_box.y));
+                        let last_cell =
+                            grid.cell_index(&Point::new(node_box.right(), node_box.bottom()));
+                        if cell == last_cell {
+                            grid.counts[cell] += self.count_all_items(node);
+                            continue;
+                        }
                     }
+
+                    for entity in node_ref.entities.values() {
+                        let entity_box = entity.bounding_box();
+                        let center = Point::new(entity_box.center_x(), entity_box.center_y());
+                        let cell = grid.cell_index(&center);
+                        grid.counts[cell] += 1;
+                    }
+                    stack.extend(node_ref.child_ids());
                 }
+                grid
+            })
+            .collect()
+    }
+
+    // Start recording mutations and queries into a trace that replay can run against a
+    // fresh tree. The trace starts with an insert for each entry already in the tree.
+    pub fn start_recording(&mut self) {
+        let mut entries: Vec<(u32, &Entity)> = self
+            .owner_map
+            .keys()
+            .filter_map(|&value| self.entity(value).map(|entity| (value, entity)))
+            .collect();
+        entries.sort_unstable_by_key(|(value, _)| *value);
+        let events = entries
+            .into_iter()
+            .map(|(value, entity)| TraceEvent::Insert {
+                value,
+                shape: entity.shape(),
+                entity_type: entity.entity_type,
+            })
+            .collect();
+        self.recorder.start(Trace {
+            bounding_box: self.node(self.roots[0]).bounding_box,
+            config: self.config.clone(),
+            events,
+        });
+    }
+
+    // Stop recording and return the trace, or None if the tree was not recording
+    pub fn stop_recording(&mut self) -> Option<Trace> {
+        self.recorder.stop()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_active()
+    }
+
+    // Return the operation counts since the previous call and reset them, so each frame
+    // can be graphed on its own
+    pub fn take_counters(&self) -> OperationCounters {
+        self.counters.take()
+    }
+
+    // Summarize the shape of the tree and its node pool, for tuning node_capacity,
+    // max_depth and pool_size
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            pool_size: self.quad_node_pool.max_size(),
+            pooled_nodes: self.quad_node_pool.len(),
+            splits: self.splits,
+            merges: self.merges,
+            ..Default::default()
+        };
+        if stats.pool_size > 0 {
+            stats.pool_utilization = stats.pooled_nodes as f64 / stats.pool_size as f64;
+        }
+
+        let mut depth_sum = 0;
+        for node_ref in self.nodes.iter().flatten() {
+            let entity_count = node_ref.entities.len();
+            stats.node_count += 1;
+            stats.leaf_count += !node_ref.is_subdivided() as usize;
+            stats.entry_count += entity_count;
+            stats.max_depth = stats.max_depth.max(node_ref.depth);
+            depth_sum += node_ref.depth * entity_count;
+            if stats.entries_per_node.len() <= entity_count {
+                stats.entries_per_node.resize(entity_count + 1, 0);
             }
+            stats.entries_per_node[entity_count] += 1;
+        }
+        if stats.entry_count > 0 {
+            stats.average_depth = depth_sum as f64 / stats.entry_count as f64;
+        }
+        stats
+    }
 
-            // Return the child nodes to the object pool
-            return_child_to_pool(&mut self.quad_node_pool, node_borrow_mut.nw.take());
-            return_child_to_pool(&mut self.quad_node_pool, node_borrow_mut.ne.take());
-            return_child_to_pool(&mut self.quad_node_pool, node_borrow_mut.sw.take());
-            return_child_to_pool(&mut self.quad_node_pool, node_borrow_mut.se.take());
+    // List the entities stored in nodes shallower than threshold_depth, with the reason
+    // each one could not be pushed further down. Many of these usually point at bad data,
+    // such as oversized shapes or coordinates outside the world, that slows every query.
+    pub fn root_heavy_entities(&self, threshold_depth: usize) -> Vec<RootHeavyEntity> {
+        let mut root_heavy = Vec::new();
+        let mut stack = self.roots.clone();
+        while let Some(node) = stack.pop() {
+            let node_ref = self.node(node);
+            if node_ref.depth >= threshold_depth {
+                continue;
+            }
 
-            // Set subdivided flag to false as all child nodes are removed
-            node_borrow_mut.subdivided = false;
+            for (&value, entity) in node_ref.entities.iter() {
+                root_heavy.push(RootHeavyEntity {
+                    value,
+                    depth: node_ref.depth,
+                    reason: self.root_heavy_reason(node, &entity.shape()),
+                });
+            }
+            stack.extend(node_ref.child_ids());
         }
+        root_heavy.sort_by_key(|entity| entity.value);
+        root_heavy
     }
 
-    // Clean up the QuadNode and its ancestors
-    fn clean_upwards(&mut self, mut node: Rc<RefCell<QuadNode>>) {
-        loop {
-            self.clean(node.clone());
-            let next_node = {
-                let node_borrow = node.borrow();
-                if let Some(parent_weak) = node_borrow.parent.as_ref() {
-                    // Move to the parent node for the next iteration
-                    parent_weak.upgrade()
+    // Why a shape stays in the given node rather than one of its quadrants
+    fn root_heavy_reason(&self, node: NodeId, shape: &ShapeEnum) -> RootHeavyReason {
+        let node_ref = self.node(node);
+        let bounding_box = shape.bounding_box();
+        if !collision_detection::rectangle_contains_rectangle(
+            &node_ref.loose_bounding_box,
+            &bounding_box,
+        ) {
+            return RootHeavyReason::OutOfBounds;
+        }
+
+        match node_ref.child_ids().next() {
+            Some(child) => {
+                let quadrant = self.node(child).loose_bounding_box;
+                if bounding_box.width > quadrant.width || bounding_box.height > quadrant.height {
+                    RootHeavyReason::TooLarge
                 } else {
-                    // No more parent nodes, break out of the loop
-                    None
+                    RootHeavyReason::Straddling
                 }
-            };
-            if let Some(parent) = next_node {
-                node = parent;
-            } else {
-                break;
             }
+            None => RootHeavyReason::Unsubdivided,
+        }
+    }
+
+    // Count the root-heavy entities and keep the counts so trends can be inspected
+    // with root_heavy_history
+    pub fn record_root_heavy_counts(&mut self, threshold_depth: usize) -> RootHeavyCounts {
+        let mut counts = RootHeavyCounts::default();
+        for entity in self.root_heavy_entities(threshold_depth) {
+            counts.total += 1;
+            match entity.reason {
+                RootHeavyReason::TooLarge => counts.too_large += 1,
+                RootHeavyReason::Straddling => counts.straddling += 1,
+                RootHeavyReason::OutOfBounds => counts.out_of_bounds += 1,
+                RootHeavyReason::Unsubdivided => counts.unsubdivided += 1,
+            }
+        }
+
+        if self.root_heavy_history.len() == ROOT_HEAVY_HISTORY_LENGTH {
+            self.root_heavy_history.pop_front();
         }
+        self.root_heavy_history.push_back(counts);
+        counts
+    }
+
+    pub fn root_heavy_history(&self) -> Vec<RootHeavyCounts> {
+        self.root_heavy_history.iter().copied().collect()
     }
 
     // Retrieve all node bounding boxes from the QuadTree
     pub fn all_node_bounding_boxes(&self, bounding_boxes: &mut Vec<Rectangle>) {
-        self.node_bounding_boxes(&self.root, bounding_boxes);
+        for &root in &self.roots {
+            self.node_bounding_boxes(root, bounding_boxes);
+        }
     }
 
     // Helper method to recursively retrieve node bounding boxes
-    fn node_bounding_boxes(
-        &self,
-        node: &Rc<RefCell<QuadNode>>,
-        bounding_boxes: &mut Vec<Rectangle>,
-    ) {
+    fn node_bounding_boxes(&self, node: NodeId, bounding_boxes: &mut Vec<Rectangle>) {
         // Add the bounding box of the current node to the list
-        bounding_boxes.push(node.borrow().bounding_box);
-
-        // Extract child nodes to avoid multiple borrows in pattern matching
-        let (nw, ne, sw, se) = {
-            let node_ref = node.borrow();
-            (
-                node_ref.nw.as_ref().map(|rc| rc.clone()),
-                node_ref.ne.as_ref().map(|rc| rc.clone()),
-                node_ref.sw.as_ref().map(|rc| rc.clone()),
-                node_ref.se.as_ref().map(|rc| rc.clone()),
-            )
-        };
+        let node_ref = self.node(node);
+        bounding_boxes.push(node_ref.bounding_box);
 
         // Continue with child nodes if the node has been subdivided
-        if let (Some(nw), Some(ne), Some(sw), Some(se)) = (nw, ne, sw, se) {
-            self.node_bounding_boxes(&nw, bounding_boxes);
-            self.node_bounding_boxes(&ne, bounding_boxes);
-            self.node_bounding_boxes(&sw, bounding_boxes);
-            self.node_bounding_boxes(&se, bounding_boxes);
+        for child in node_ref.child_ids() {
+            self.node_bounding_boxes(child, bounding_boxes);
         }
     }
 
     // Retrieve all shapes from the QuadTree
     pub fn all_shapes(&self, shapes: &mut Vec<ShapeEnum>) {
-        self.shapes(&self.root, shapes);
+        for &root in &self.roots {
+            self.shapes(root, shapes);
+        }
     }
 
     // Helper method to recursively retrieve shapes
-    fn shapes(&self, node: &Rc<RefCell<QuadNode>>, shapes: &mut Vec<ShapeEnum>) {
-        let node_ref: Ref<QuadNode> = node.as_ref().borrow();
+    fn shapes(&self, node: NodeId, shapes: &mut Vec<ShapeEnum>) {
+        let node_ref = self.node(node);
         // Add the shapes in the current node to the list
-        for (_, entities) in node_ref.deref().entities.iter() {
-            shapes.push(entities.shape.clone());
-        }
-        // Extract child nodes to avoid multiple borrows in pattern matching
-        let (nw, ne, sw, se) = (
-            node_ref.nw.clone(),
-            node_ref.ne.clone(),
-            node_ref.sw.clone(),
-            node_ref.se.clone(),
-        );
-
-        // Explicitly drop the borrow so that we can borrow again in the recursive calls
-        drop(node_ref);
+        for entity in node_ref.entities.values() {
+            shapes.push(entity.shape());
+        }
 
         // Continue with child nodes if the node has been subdivided
-        if let (Some(nw), Some(ne), Some(sw), Some(se)) = (nw, ne, sw, se) {
-            self.shapes(&nw, shapes);
-            self.shapes(&ne, shapes);
-            self.shapes(&sw, shapes);
-            self.shapes(&se, shapes);
+        for child in node_ref.child_ids() {
+            self.shapes(child, shapes);
+        }
+    }
+
+    // Copy the shape of every entry, to be kept as the previous state for
+    // interpolated_shapes after the next simulation tick
+    pub fn shape_snapshot(&self) -> HashMap<u32, ShapeEnum> {
+        self.iter()
+            .map(|(value, shape, _)| (value, shape.clone()))
+            .collect()
+    }
+
+    // Shapes blended between a snapshot taken at the previous tick and the current tree,
+    // sorted by value. Alpha is the fraction of the tick elapsed, as in fixed-timestep
+    // rendering. Entries missing from the snapshot use their current shape.
+    pub fn interpolated_shapes(
+        &self,
+        previous: &HashMap<u32, ShapeEnum>,
+        alpha: Float,
+    ) -> Vec<(u32, ShapeEnum)> {
+        let mut shapes: Vec<(u32, ShapeEnum)> = self
+            .iter()
+            .map(|(value, shape, _)| match previous.get(&value) {
+                Some(previous_shape) => (value, ShapeEnum::lerp(previous_shape, &shape, alpha)),
+                None => (value, shape),
+            })
+            .collect();
+        shapes.sort_by_key(|(value, _)| *value);
+        shapes
+    }
+
+    // Iterate over every entry as (value, shape, entity_type), without copying shapes
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            tree: self,
+            cursor: self.cursor(),
+            entities: None,
+        }
+    }
+
+    // Iterate over the entries whose shapes intersect a region, skipping subtrees
+    // that lie outside it
+    pub fn iter_in_region(&self, region: Rectangle) -> Iter<'_> {
+        Iter {
+            tree: self,
+            cursor: self.cursor_in_region(region),
+            entities: None,
+        }
+    }
+
+    // Iterate over the nodes of the tree, parents before their children
+    pub fn nodes(&self) -> Nodes<'_> {
+        Nodes {
+            tree: self,
+            cursor: self.cursor(),
+        }
+    }
+
+    // Start a walk over the whole tree that can be advanced with advance_cursor
+    pub fn cursor(&self) -> Cursor {
+        Cursor {
+            stack: self.roots.clone(),
+            region: None,
+        }
+    }
+
+    // Start a walk over the nodes and entries touching a region
+    pub fn cursor_in_region(&self, region: Rectangle) -> Cursor {
+        Cursor {
+            region: Some(ShapeEnum::Rectangle(region)),
+            ..self.cursor()
+        }
+    }
+
+    // Visit the next node of a walk, appending its entries that match the cursor region.
+    // Unlike iter this holds no borrow between steps, which suits bindings that hand
+    // out iterators. Changing the tree mid-walk may skip or repeat entries.
+    pub fn advance_cursor(
+        &self,
+        cursor: &mut Cursor,
+        entries: Option<&mut Vec<(u32, ShapeEnum, Option<u32>)>>,
+    ) -> Option<NodeInfo> {
+        let node = self.next_cursor_node(cursor)?;
+        let node_ref = self.node(node);
+        if let Some(entries) = entries {
+            entries.extend(
+                node_ref
+                    .entities
+                    .iter()
+                    .filter(|(_, entity)| cursor.matches(&entity.shape()))
+                    .map(|(&value, entity)| (value, entity.shape(), entity.entity_type)),
+            );
+        }
+        Some(NodeInfo {
+            depth: node_ref.depth,
+            bounding_box: node_ref.bounding_box,
+            entity_count: node_ref.entities.len(),
+            is_leaf: !node_ref.is_subdivided(),
+        })
+    }
+
+    // Pop the next live node of a walk and queue its children that touch the region
+    fn next_cursor_node(&self, cursor: &mut Cursor) -> Option<NodeId> {
+        while let Some(node) = cursor.stack.pop() {
+            let Some(node_ref) = self.nodes.get(node).and_then(Option::as_ref) else {
+                continue;
+            };
+            let children = node_ref.child_ids().filter(|&child| {
+                cursor.region.as_ref().is_none_or(|region| {
+                    collision_detection::rectangle_touches_rectangle(
+                        &self.node(child).loose_bounding_box,
+                        &region.bounding_box(),
+                    )
+                })
+            });
+            cursor.stack.extend(children);
+            return Some(node);
+        }
+        None
+    }
+}
+
+// Position in a walk over the tree, which holds no borrow of it between steps
+#[derive(Debug, Clone)]
+pub struct Cursor {
+    stack: Vec<NodeId>,
+    region: Option<ShapeEnum>,
+}
+
+impl Cursor {
+    fn matches(&self, shape: &ShapeEnum) -> bool {
+        self.region
+            .as_ref()
+            .is_none_or(|region| collision_detection::shape_shape(region, shape))
+    }
+}
+
+pub struct Iter<'a> {
+    tree: &'a QuadTree,
+    cursor: Cursor,
+    entities: Option<hash_map::Iter<'a, u32, Entity>>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (u32, ShapeEnum, Option<u32>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entities) = self.entities.as_mut() {
+                for (&value, entity) in entities.by_ref() {
+                    let shape = entity.shape();
+                    if self.cursor.matches(&shape) {
+                        return Some((value, shape, entity.entity_type));
+                    }
+                }
+            }
+            let node = self.tree.next_cursor_node(&mut self.cursor)?;
+            self.entities = Some(self.tree.node(node).entities.iter());
         }
     }
 }
 
+pub struct Nodes<'a> {
+    tree: &'a QuadTree,
+    cursor: Cursor,
+}
+
+impl Iterator for Nodes<'_> {
+    type Item = NodeInfo;
+
+    fn next(&mut self) -> Option<NodeInfo> {
+        self.tree.advance_cursor(&mut self.cursor, None)
+    }
+}
+
+// A subtree reported whole by a coarse query
+#[derive(Debug, Clone, Copy)]
+pub struct NodeSummary {
+    pub depth: usize,
+    pub bounding_box: Rectangle,
+    // Entries in the node and all of its descendants
+    pub entity_count: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NodeInfo {
+    pub depth: usize,
+    pub bounding_box: Rectangle,
+    pub entity_count: usize,
+    pub is_leaf: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub pool_size: usize,
     pub node_capacity: usize,
     pub max_depth: usize,
+    // Smallest size, in world units, a leaf may be split down to. When set, the depth limit
+    // is derived from the root bounds instead of taken from max_depth, and derived again
+    // whenever the root grows.
+    pub min_cell_size: Option<Float>,
+    // Where roots are split into quadrants instead of their center, for worlds with a known
+    // dense area such as a city center. Ignored when it is not strictly inside the root.
+    pub root_split: Option<Point>,
+    // Route entities into separate trees by size, merged at query time
+    pub size_classes: Option<SizeClasses>,
+    // Factor node bounds are scaled by when deciding which node an entity fits in.
+    // Values above 1.0 build a loose quadtree, where moving entities rarely change node.
+    pub looseness: Float,
+    // Grow the tree bounds when an insert or relocate falls outside them
+    pub auto_expand: bool,
+    // How rectangles without area and circles without radius are handled by collision,
+    // swept, line of sight and distance queries
+    pub degenerate_shapes: DegenerateShapeMode,
+}
+
+impl Config {
+    // Depth limit of a tree with these bounds: the deepest level whose cells are still at
+    // least min_cell_size on both sides, or max_depth when no cell size is set
+    pub fn max_depth_for(&self, bounding_box: &Rectangle) -> usize {
+        match self.min_cell_size {
+            Some(min_cell_size) if min_cell_size > 0.0 => {
+                let side = bounding_box.width.min(bounding_box.height);
+                (side / min_cell_size).log2().floor().max(0.0) as usize
+            }
+            _ => self.max_depth,
+        }
+    }
 }
 
 // Implement Default trait for Config
@@ -753,13 +2742,294 @@ impl Default for Config {
             pool_size: 4000,
             node_capacity: 4,
             max_depth: 6,
+            min_cell_size: None,
+            root_split: None,
+            size_classes: None,
+            looseness: 1.0,
+            auto_expand: false,
+            degenerate_shapes: DegenerateShapeMode::PointSemantics,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DegenerateShapeMode {
+    // Degenerate shapes never collide with anything
+    AlwaysMiss,
+    // Degenerate shapes collide with whatever touches them, boundaries included, the way
+    // points do
+    PointSemantics,
+    // Inserting or relocating to a degenerate shape fails, and degenerate query shapes
+    // use point semantics
+    ErrorOnInsert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeClass {
+    Tiny,
+    Medium,
+    Huge,
+}
+
+// Size thresholds measured on the longer side of an entity's bounding box
+#[derive(Debug, Clone, Copy)]
+pub struct SizeClasses {
+    // Entities up to this size are tiny
+    pub tiny_max: Float,
+    // Entities up to this size are medium, and anything larger is huge
+    pub medium_max: Float,
+}
+
+impl SizeClasses {
+    pub fn classify(&self, shape: &ShapeEnum) -> SizeClass {
+        let bounding_box = shape.bounding_box();
+        let size = bounding_box.width.max(bounding_box.height);
+        if size <= self.tiny_max {
+            SizeClass::Tiny
+        } else if size <= self.medium_max {
+            SizeClass::Medium
+        } else {
+            SizeClass::Huge
         }
     }
 }
 
+// How entries are matched by spatial_join
+#[derive(Debug, Clone, Copy)]
+pub enum JoinPredicate {
+    // Shapes overlap
+    Intersects,
+    // Shapes are at most this far apart
+    WithinDistance(Float),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SweptHit {
+    pub value: u32,
+    // Time from the start of the sweep at which the shapes first overlap
+    pub time_of_impact: Float,
+}
+
+#[derive(Clone)]
+struct Template {
+    shape: ShapeEnum,
+    entity_type: Option<u32>,
+    // Whether the shape has zero area or radius, which moving it cannot change
+    degenerate: bool,
+}
+
 #[derive(Clone)]
 pub struct RelocationRequest {
     pub value: u32,
     pub shape: ShapeEnum,
     pub entity_type: Option<u32>,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootHeavyReason {
+    // Larger than the quadrants of its node
+    TooLarge,
+    // Small enough for a quadrant but crossing the split lines of its node
+    Straddling,
+    // Extends past the tree bounds
+    OutOfBounds,
+    // Its node has not been split, because it holds few entities or hit its max depth
+    Unsubdivided,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootHeavyEntity {
+    pub value: u32,
+    pub depth: usize,
+    pub reason: RootHeavyReason,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RootHeavyCounts {
+    pub total: usize,
+    pub too_large: usize,
+    pub straddling: usize,
+    pub out_of_bounds: usize,
+    pub unsubdivided: usize,
+}
+
+// Entry counts over a square grid of cells covering the tree bounds
+#[derive(Debug, Clone)]
+pub struct DensityGrid {
+    // Cells per side
+    pub resolution: usize,
+    pub bounding_box: Rectangle,
+    // Counts in row-major order, starting from the cell at the tree origin
+    pub counts: Vec<usize>,
+}
+
+impl DensityGrid {
+    pub fn count(&self, column: usize, row: usize) -> usize {
+        self.counts[row * self.resolution + column]
+    }
+
+    // Cell holding a point, with points outside the grid clamped to its edge
+    fn cell_index(&self, point: &Point) -> usize {
+        let cell = |offset: Float, size: Float| {
+            let position = (offset / size * self.resolution as Float).floor();
+            (position.max(0.0) as usize).min(self.resolution - 1)
+        };
+        let column = cell(point.x - self.bounding_box.x, self.bounding_box.width);
+        let row = cell(point.y - self.bounding_box.y, self.bounding_box.height);
+        row * self.resolution + column
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TreeStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub entry_count: usize,
+    // Depth of the deepest node
+    pub max_depth: usize,
+    // Mean depth of the nodes entries are stored in
+    pub average_depth: f64,
+    // entries_per_node[n] is the number of nodes holding exactly n entries
+    pub entries_per_node: Vec<usize>,
+    pub pool_size: usize,
+    // Released nodes waiting in the pool for reuse, and their share of pool_size
+    pub pooled_nodes: usize,
+    pub pool_utilization: f64,
+    pub splits: u64,
+    pub merges: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FragmentationReport {
+    // Slots in the node arena, and how many hold a node or are holes left by released nodes
+    pub arena_slots: usize,
+    pub live_nodes: usize,
+    pub arena_holes: usize,
+    pub hole_ratio: f64,
+    // Released nodes waiting in the pool for reuse
+    pub pooled_nodes: usize,
+    pub leaf_count: usize,
+    pub empty_leaves: usize,
+    // Subdivided nodes whose subtree holds no more than node_capacity entries, which
+    // rebalance would merge, the ones among them holding nothing at all, and the nodes
+    // below them that merging frees
+    pub mergeable_subtrees: usize,
+    pub empty_subtrees: usize,
+    pub reclaimable_nodes: usize,
+    // Share of the entry capacity of leaves that is unused
+    pub wasted_capacity: f64,
+    // Whether optimize would free at least OPTIMIZE_THRESHOLD of the arena
+    pub optimize_recommended: bool,
+}
+
+// Share of the node arena that optimize must be able to free to be worth running
+const OPTIMIZE_THRESHOLD: f64 = 0.25;
+
+// Number of root-heavy count records kept before the oldest are dropped
+const ROOT_HEAVY_HISTORY_LENGTH: usize = 1024;
+
+// Grid samples per axis in each tile when searching a node for free space
+const MAX_SAMPLES_PER_AXIS: usize = 32;
+
+// Probes a single nearest free point search may make before it gives up and returns None
+const MAX_FREE_POINT_SAMPLES: usize = 1 << 20;
+
+// Random samples spawn_position tries before falling back to a nearest free point search
+const SPAWN_ATTEMPTS: usize = 64;
+
+// Bisection steps used to move a sampled free location up against the nearest obstacle
+const REFINEMENT_STEPS: usize = 16;
+
+// A node or tile queued for a nearest-first search, ordered so the closest is popped first
+struct SearchCandidate<T> {
+    distance_sq: Float,
+    item: T,
+}
+
+impl<T> PartialEq for SearchCandidate<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance_sq == other.distance_sq
+    }
+}
+
+impl<T> Eq for SearchCandidate<T> {}
+
+impl<T> PartialOrd for SearchCandidate<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for SearchCandidate<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance_sq.total_cmp(&self.distance_sq)
+    }
+}
+
+// Grow a rectangle by a margin on every side
+fn expand_rectangle(rectangle: &Rectangle, margin: Float) -> Rectangle {
+    Rectangle::new(
+        rectangle.x - margin,
+        rectangle.y - margin,
+        rectangle.width + margin * 2.0,
+        rectangle.height + margin * 2.0,
+    )
+}
+
+// Shrink a rectangle by a margin on every side, or None if nothing is left
+fn shrink_rectangle(rectangle: &Rectangle, margin: Float) -> Option<Rectangle> {
+    let width = rectangle.width - margin * 2.0;
+    let height = rectangle.height - margin * 2.0;
+    if width < 0.0 || height < 0.0 {
+        return None;
+    }
+    Some(Rectangle::new(
+        rectangle.x + margin,
+        rectangle.y + margin,
+        width,
+        height,
+    ))
+}
+
+// Uniformly distributed point in a rectangle, given a source of uniform values in [0, 1)
+fn random_point_in(rectangle: &Rectangle, rng: &mut impl FnMut() -> Float) -> Point {
+    Point::new(
+        rectangle.x + rng() * rectangle.width,
+        rectangle.y + rng() * rectangle.height,
+    )
+}
+
+// Smallest rectangle containing both rectangles
+fn union_rectangles(a: &Rectangle, b: &Rectangle) -> Rectangle {
+    let x = a.x.min(b.x);
+    let y = a.y.min(b.y);
+    Rectangle::new(
+        x,
+        y,
+        a.right().max(b.right()) - x,
+        a.bottom().max(b.bottom()) - y,
+    )
+}
+
+// Intersection of two rectangles including their boundaries, or None if they are disjoint
+fn intersect_rectangles(a: &Rectangle, b: &Rectangle) -> Option<Rectangle> {
+    let x = a.x.max(b.x);
+    let y = a.y.max(b.y);
+    let right = a.right().min(b.right());
+    let bottom = a.bottom().min(b.bottom());
+    if right < x || bottom < y {
+        return None;
+    }
+    Some(Rectangle::new(x, y, right - x, bottom - y))
+}
+
+// Squared distance from a point to the closest point of a rectangle
+fn rectangle_distance_squared(rectangle: &Rectangle, point: &Point) -> Float {
+    let dx = (rectangle.x - point.x)
+        .max(point.x - rectangle.right())
+        .max(0.0);
+    let dy = (rectangle.y - point.y)
+        .max(point.y - rectangle.bottom())
+        .max(0.0);
+    dx * dx + dy * dy
+}
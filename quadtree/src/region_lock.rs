@@ -0,0 +1,67 @@
+use crate::shapes::ShapeEnum;
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Regions currently locked against mutation, shared between a tree and its guards
+#[derive(Default)]
+pub(crate) struct RegionLocks {
+    shared: Arc<SharedRegions>,
+}
+
+#[derive(Default)]
+struct SharedRegions {
+    next_id: AtomicU64,
+    // Number of held locks, so mutations skip the mutex while nothing is locked
+    active: AtomicUsize,
+    regions: Mutex<Vec<(u64, ShapeEnum)>>,
+}
+
+impl RegionLocks {
+    // Another handle on the same locked regions
+    pub fn share(&self) -> RegionLocks {
+        RegionLocks {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    pub fn lock(&self, region: ShapeEnum) -> RegionLock {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut regions = self.shared.regions.lock().unwrap();
+        regions.push((id, region));
+        self.shared.active.store(regions.len(), Ordering::Release);
+        RegionLock {
+            id,
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    // Check whether any locked region satisfies the predicate
+    pub fn any(&self, predicate: impl Fn(&ShapeEnum) -> bool) -> bool {
+        if self.shared.active.load(Ordering::Acquire) == 0 {
+            return false;
+        }
+        let regions = self.shared.regions.lock().unwrap();
+        regions.iter().any(|(_, region)| predicate(region))
+    }
+}
+
+// Keeps a region of the tree from changing while held. Inserting, relocating or deleting
+// an entry whose shape, before or after the change, collides with the region fails with
+// QuadTreeError::RegionLocked. The lock is released when the guard is dropped.
+pub struct RegionLock {
+    id: u64,
+    shared: Arc<SharedRegions>,
+}
+
+impl RegionLock {
+    pub fn release(self) {}
+}
+
+impl Drop for RegionLock {
+    fn drop(&mut self) {
+        let mut regions = self.shared.regions.lock().unwrap();
+        regions.retain(|(id, _)| *id != self.id);
+        self.shared.active.store(regions.len(), Ordering::Release);
+    }
+}
@@ -1,5 +1,12 @@
 use std::fmt::Debug;
 
+// Coordinate type used by every shape. Enable the `f64` feature for worlds where
+// single precision loses detail, such as geographic coordinates in the millions.
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
 pub trait Shape: Debug {
     fn bounding_box(&self) -> Rectangle;
     fn as_any(&self) -> &dyn std::any::Any;
@@ -7,14 +14,14 @@ pub trait Shape: Debug {
 
 #[derive(Debug, Copy, Clone)]
 pub struct Circle {
-    pub x: f32,
-    pub y: f32,
-    pub radius: f32,
+    pub x: Float,
+    pub y: Float,
+    pub radius: Float,
     pub bounding_box: Rectangle,
 }
 
 impl Circle {
-    pub fn new(x: f32, y: f32, radius: f32) -> Self {
+    pub fn new(x: Float, y: Float, radius: Float) -> Self {
         let bounding_box = Rectangle {
             x: x - radius,
             y: y - radius,
@@ -29,25 +36,25 @@ impl Circle {
         }
     }
 
-    pub fn x(&self) -> f32 {
+    pub fn x(&self) -> Float {
         self.x
     }
 
-    pub fn y(&self) -> f32 {
+    pub fn y(&self) -> Float {
         self.y
     }
 
-    pub fn radius(&self) -> f32 {
+    pub fn radius(&self) -> Float {
         self.radius
     }
 
-    pub fn update(&mut self, x: f32, y: f32) {
+    pub fn update(&mut self, x: Float, y: Float) {
         self.x = x;
         self.y = y;
         self.update_bounding_box();
     }
 
-    pub fn update_with_radius(&mut self, x: f32, y: f32, radius: f32) {
+    pub fn update_with_radius(&mut self, x: Float, y: Float, radius: Float) {
         self.x = x;
         self.y = y;
         self.radius = radius;
@@ -89,14 +96,14 @@ impl Shape for Circle {
 
 #[derive(Debug, Copy, Clone)]
 pub struct Rectangle {
-    pub x: f32,
-    pub y: f32,
-    pub width: f32,
-    pub height: f32,
+    pub x: Float,
+    pub y: Float,
+    pub width: Float,
+    pub height: Float,
 }
 
 impl Rectangle {
-    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+    pub fn new(x: Float, y: Float, width: Float, height: Float) -> Self {
         Self {
             x,
             y,
@@ -105,35 +112,35 @@ impl Rectangle {
         }
     }
 
-    pub fn x(&self) -> f32 {
+    pub fn x(&self) -> Float {
         self.x
     }
 
-    pub fn y(&self) -> f32 {
+    pub fn y(&self) -> Float {
         self.y
     }
 
-    pub fn width(&self) -> f32 {
+    pub fn width(&self) -> Float {
         self.width
     }
 
-    pub fn height(&self) -> f32 {
+    pub fn height(&self) -> Float {
         self.height
     }
 
-    pub fn right(&self) -> f32 {
+    pub fn right(&self) -> Float {
         self.x + self.width
     }
 
-    pub fn bottom(&self) -> f32 {
+    pub fn bottom(&self) -> Float {
         self.y + self.height
     }
 
-    pub fn center_x(&self) -> f32 {
+    pub fn center_x(&self) -> Float {
         self.x + self.width / 2.0
     }
 
-    pub fn center_y(&self) -> f32 {
+    pub fn center_y(&self) -> Float {
         self.y + self.height / 2.0
     }
 }
@@ -159,10 +166,96 @@ impl Shape for Rectangle {
     }
 }
 
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Point {
+    pub x: Float,
+    pub y: Float,
+}
+
+impl Point {
+    pub fn new(x: Float, y: Float) -> Self {
+        Self { x, y }
+    }
+
+    pub fn x(&self) -> Float {
+        self.x
+    }
+
+    pub fn y(&self) -> Float {
+        self.y
+    }
+
+    pub fn distance_squared(&self, other: &Point) -> Float {
+        let dx = self.x - other.x;
+        let dy = self.y - other.y;
+        dx * dx + dy * dy
+    }
+}
+
+impl Shape for Point {
+    fn bounding_box(&self) -> Rectangle {
+        Rectangle::new(self.x, self.y, 0.0, 0.0)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum ShapeEnum {
     Circle(Circle),
     Rectangle(Rectangle),
+    Point(Point),
+}
+
+impl ShapeEnum {
+    // Rectangles without area and circles without radius. Points are never degenerate,
+    // since having no extent is what they are for.
+    pub fn is_degenerate(&self) -> bool {
+        match self {
+            ShapeEnum::Circle(circle) => circle.radius <= 0.0,
+            ShapeEnum::Rectangle(rectangle) => rectangle.width <= 0.0 || rectangle.height <= 0.0,
+            ShapeEnum::Point(_) => false,
+        }
+    }
+
+    // The same shape placed at a new position: the center of a circle, the top-left
+    // corner of a rectangle or the point itself
+    pub fn moved_to(&self, x: Float, y: Float) -> ShapeEnum {
+        match self {
+            ShapeEnum::Circle(circle) => ShapeEnum::Circle(Circle::new(x, y, circle.radius)),
+            ShapeEnum::Rectangle(rectangle) => {
+                ShapeEnum::Rectangle(Rectangle::new(x, y, rectangle.width, rectangle.height))
+            }
+            ShapeEnum::Point(_) => ShapeEnum::Point(Point::new(x, y)),
+        }
+    }
+
+    // Blend between two shapes, where t = 0 gives a and t = 1 gives b. Shapes of
+    // different kinds cannot be blended, so b is returned for those.
+    pub fn lerp(a: &ShapeEnum, b: &ShapeEnum, t: Float) -> ShapeEnum {
+        let mix = |from: Float, to: Float| from + (to - from) * t;
+        match (a, b) {
+            (ShapeEnum::Circle(a), ShapeEnum::Circle(b)) => ShapeEnum::Circle(Circle::new(
+                mix(a.x, b.x),
+                mix(a.y, b.y),
+                mix(a.radius, b.radius),
+            )),
+            (ShapeEnum::Rectangle(a), ShapeEnum::Rectangle(b)) => {
+                ShapeEnum::Rectangle(Rectangle::new(
+                    mix(a.x, b.x),
+                    mix(a.y, b.y),
+                    mix(a.width, b.width),
+                    mix(a.height, b.height),
+                ))
+            }
+            (ShapeEnum::Point(a), ShapeEnum::Point(b)) => {
+                ShapeEnum::Point(Point::new(mix(a.x, b.x), mix(a.y, b.y)))
+            }
+            _ => b.clone(),
+        }
+    }
 }
 
 impl Shape for ShapeEnum {
@@ -170,6 +263,7 @@ impl Shape for ShapeEnum {
         match self {
             ShapeEnum::Circle(circle) => circle.bounding_box(),
             ShapeEnum::Rectangle(rectangle) => rectangle.bounding_box(),
+            ShapeEnum::Point(point) => point.bounding_box(),
         }
     }
 
@@ -177,6 +271,7 @@ impl Shape for ShapeEnum {
         match self {
             ShapeEnum::Circle(circle) => circle.as_any(),
             ShapeEnum::Rectangle(rectangle) => rectangle.as_any(),
+            ShapeEnum::Point(point) => point.as_any(),
         }
     }
 }
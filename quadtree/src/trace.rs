@@ -0,0 +1,631 @@
+use crate::quadtree::{Config, DegenerateShapeMode, QuadTree, SizeClasses};
+use crate::shapes::{Circle, Float, Point, Rectangle, ShapeEnum};
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const MAGIC: &[u8; 4] = b"QTTR";
+const VERSION: u8 = 1;
+
+// Mutations and queries made on a tree while it was recording, along with the bounds and
+// configuration needed to rebuild it. Events can be removed to cut a trace down to a
+// minimal reproduction before it is written out.
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub bounding_box: Rectangle,
+    pub config: Config,
+    pub events: Vec<TraceEvent>,
+}
+
+// Events are recorded at the public entry points only, so queries built from other queries
+// appear once. spatial_join and spawn_position are not recorded, since they depend on a
+// second tree and a caller's random source that a trace cannot hold.
+#[derive(Debug, Clone)]
+pub enum TraceEvent {
+    Insert {
+        value: u32,
+        shape: ShapeEnum,
+        entity_type: Option<u32>,
+    },
+    // An insert_batch into an empty tree, which builds the tree in one pass
+    InsertBatch(Vec<(u32, ShapeEnum, Option<u32>)>),
+    Delete(u32),
+    Relocate {
+        value: u32,
+        shape: ShapeEnum,
+        entity_type: Option<u32>,
+    },
+    // An update that changed the stored shape in place
+    Update {
+        value: u32,
+        shape: ShapeEnum,
+    },
+    ExpandTo(Rectangle),
+    Rebalance,
+    ShrinkToFit,
+    Optimize,
+    Collisions {
+        shape: ShapeEnum,
+        filter: TraceFilter,
+    },
+    CollisionsSwept {
+        shape: ShapeEnum,
+        velocity: (Float, Float),
+        dt: Float,
+    },
+    LineOfSight {
+        start: Point,
+        end: Point,
+        filter: TraceFilter,
+    },
+    CollisionsCoarse {
+        shape: ShapeEnum,
+        max_depth: usize,
+    },
+    ProximityGraph(Float),
+    Cluster {
+        eps: Float,
+        min_points: usize,
+    },
+    NearestFreePoint {
+        point: Point,
+        clearance_radius: Float,
+    },
+}
+
+// Entity type filter of a recorded query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceFilter {
+    All,
+    Types(Vec<u32>),
+    Mask(u64),
+}
+
+impl TraceFilter {
+    // The filter as a list of entity types, where mask bit n stands for type n
+    fn types(&self) -> Option<Vec<u32>> {
+        match self {
+            TraceFilter::All => None,
+            TraceFilter::Types(types) => Some(types.clone()),
+            TraceFilter::Mask(mask) => {
+                Some((0..u64::BITS).filter(|bit| mask >> bit & 1 == 1).collect())
+            }
+        }
+    }
+}
+
+impl Trace {
+    // Write the trace in a compact binary format. Coordinates are stored as f64, so traces
+    // can be read whichever precision the library was built with.
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut encoder = Encoder { writer };
+        encoder.bytes(MAGIC)?;
+        encoder.u8(VERSION)?;
+        encoder.rectangle(&self.bounding_box)?;
+        encoder.config(&self.config)?;
+        encoder.u64(self.events.len() as u64)?;
+        for event in &self.events {
+            encoder.event(event)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Trace> {
+        let mut decoder = Decoder { reader };
+        let mut magic = [0; 4];
+        decoder.reader.read_exact(&mut magic)?;
+        if &magic != MAGIC || decoder.u8()? != VERSION {
+            return Err(invalid_data("not a quadtree trace of a supported version"));
+        }
+        let bounding_box = decoder.rectangle()?;
+        let config = decoder.config()?;
+        let count = decoder.u64()?;
+        let events = (0..count)
+            .map(|_| decoder.event())
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Trace {
+            bounding_box,
+            config,
+            events,
+        })
+    }
+}
+
+// Rebuild a tree from a trace by applying its events in order against a fresh tree.
+// Queries are run again for their cost and their results dropped, and mutations that
+// fail are skipped, as they left the recorded tree unchanged.
+pub fn replay(trace: &Trace) -> QuadTree {
+    let mut tree = QuadTree::new_with_config(trace.bounding_box, trace.config.clone());
+    for event in &trace.events {
+        match event.clone() {
+            TraceEvent::Insert {
+                value,
+                shape,
+                entity_type,
+            } => {
+                let _ = tree.try_insert(value, shape, entity_type);
+            }
+            TraceEvent::InsertBatch(items) => tree.insert_batch(items),
+            TraceEvent::Delete(value) => {
+                let _ = tree.try_delete(value);
+            }
+            TraceEvent::Relocate {
+                value,
+                shape,
+                entity_type,
+            } => {
+                let _ = tree.try_relocate(value, shape, entity_type);
+            }
+            TraceEvent::Update { value, shape } => {
+                tree.update(value, shape);
+            }
+            TraceEvent::ExpandTo(rectangle) => tree.expand_to(&rectangle),
+            TraceEvent::Rebalance => tree.rebalance(),
+            TraceEvent::ShrinkToFit => tree.shrink_to_fit(),
+            TraceEvent::Optimize => tree.optimize(),
+            TraceEvent::Collisions { shape, filter } => {
+                let mut collisions = Vec::new();
+                match filter {
+                    TraceFilter::Mask(mask) => tree.collisions_mask(shape, mask, &mut collisions),
+                    filter => tree.collisions_filter(shape, filter.types(), &mut collisions),
+                }
+            }
+            TraceEvent::CollisionsSwept {
+                shape,
+                velocity,
+                dt,
+            } => {
+                tree.collisions_swept(&shape, velocity, dt);
+            }
+            TraceEvent::LineOfSight { start, end, filter } => {
+                tree.line_of_sight_batch(&[(start, end)], filter.types());
+            }
+            TraceEvent::CollisionsCoarse { shape, max_depth } => {
+                tree.collisions_coarse(shape, max_depth, &mut Vec::new(), &mut Vec::new());
+            }
+            TraceEvent::ProximityGraph(max_distance) => {
+                tree.proximity_graph(max_distance);
+            }
+            TraceEvent::Cluster { eps, min_points } => {
+                tree.cluster(eps, min_points);
+            }
+            TraceEvent::NearestFreePoint {
+                point,
+                clearance_radius,
+            } => {
+                tree.nearest_free_point(point, clearance_radius);
+            }
+        }
+    }
+    tree
+}
+
+// Collects events while recording is on. Queries record through a shared reference, so
+// the trace goes behind a mutex, and the flag keeps that cost away while recording is off.
+#[derive(Default)]
+pub(crate) struct Recorder {
+    active: AtomicBool,
+    trace: Mutex<Option<Trace>>,
+}
+
+impl Recorder {
+    pub fn start(&self, trace: Trace) {
+        *self.trace.lock().unwrap() = Some(trace);
+        self.active.store(true, Ordering::Release);
+    }
+
+    pub fn stop(&self) -> Option<Trace> {
+        self.active.store(false, Ordering::Release);
+        self.trace.lock().unwrap().take()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    // The event is only built while recording
+    pub fn record(&self, event: impl FnOnce() -> TraceEvent) {
+        if self.active.load(Ordering::Acquire) {
+            if let Some(trace) = self.trace.lock().unwrap().as_mut() {
+                trace.events.push(event());
+            }
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct Encoder<'a, W: Write> {
+    writer: &'a mut W,
+}
+
+impl<W: Write> Encoder<'_, W> {
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)
+    }
+
+    fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.bytes(&[value])
+    }
+
+    fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    // Floats are always stored as f64, so traces load under either precision. The cast is a
+    // no-op when Float is already f64.
+    #[cfg_attr(feature = "f64", allow(clippy::unnecessary_cast))]
+    fn float(&mut self, value: Float) -> io::Result<()> {
+        self.bytes(&(value as f64).to_le_bytes())
+    }
+
+    fn optional_u32(&mut self, value: Option<u32>) -> io::Result<()> {
+        match value {
+            Some(value) => {
+                self.u8(1)?;
+                self.u32(value)
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn point(&mut self, point: &Point) -> io::Result<()> {
+        self.float(point.x)?;
+        self.float(point.y)
+    }
+
+    fn rectangle(&mut self, rectangle: &Rectangle) -> io::Result<()> {
+        self.float(rectangle.x)?;
+        self.float(rectangle.y)?;
+        self.float(rectangle.width)?;
+        self.float(rectangle.height)
+    }
+
+    fn shape(&mut self, shape: &ShapeEnum) -> io::Result<()> {
+        match shape {
+            ShapeEnum::Circle(circle) => {
+                self.u8(0)?;
+                self.float(circle.x)?;
+                self.float(circle.y)?;
+                self.float(circle.radius)
+            }
+            ShapeEnum::Rectangle(rectangle) => {
+                self.u8(1)?;
+                self.rectangle(rectangle)
+            }
+            ShapeEnum::Point(point) => {
+                self.u8(2)?;
+                self.point(point)
+            }
+        }
+    }
+
+    fn filter(&mut self, filter: &TraceFilter) -> io::Result<()> {
+        match filter {
+            TraceFilter::All => self.u8(0),
+            TraceFilter::Types(types) => {
+                self.u8(1)?;
+                self.u32(types.len() as u32)?;
+                types
+                    .iter()
+                    .try_for_each(|&entity_type| self.u32(entity_type))
+            }
+            TraceFilter::Mask(mask) => {
+                self.u8(2)?;
+                self.u64(*mask)
+            }
+        }
+    }
+
+    fn config(&mut self, config: &Config) -> io::Result<()> {
+        self.u64(config.pool_size as u64)?;
+        self.u64(config.node_capacity as u64)?;
+        self.u64(config.max_depth as u64)?;
+        match config.min_cell_size {
+            Some(min_cell_size) => {
+                self.u8(1)?;
+                self.float(min_cell_size)?;
+            }
+            None => self.u8(0)?,
+        }
+        match config.root_split {
+            Some(root_split) => {
+                self.u8(1)?;
+                self.point(&root_split)?;
+            }
+            None => self.u8(0)?,
+        }
+        match config.size_classes {
+            Some(size_classes) => {
+                self.u8(1)?;
+                self.float(size_classes.tiny_max)?;
+                self.float(size_classes.medium_max)?;
+            }
+            None => self.u8(0)?,
+        }
+        self.float(config.looseness)?;
+        self.u8(config.auto_expand as u8)?;
+        self.u8(match config.degenerate_shapes {
+            DegenerateShapeMode::AlwaysMiss => 0,
+            DegenerateShapeMode::PointSemantics => 1,
+            DegenerateShapeMode::ErrorOnInsert => 2,
+        })
+    }
+
+    fn event(&mut self, event: &TraceEvent) -> io::Result<()> {
+        match event {
+            TraceEvent::Insert {
+                value,
+                shape,
+                entity_type,
+            } => {
+                self.u8(0)?;
+                self.u32(*value)?;
+                self.shape(shape)?;
+                self.optional_u32(*entity_type)
+            }
+            TraceEvent::InsertBatch(items) => {
+                self.u8(1)?;
+                self.u64(items.len() as u64)?;
+                for (value, shape, entity_type) in items {
+                    self.u32(*value)?;
+                    self.shape(shape)?;
+                    self.optional_u32(*entity_type)?;
+                }
+                Ok(())
+            }
+            TraceEvent::Delete(value) => {
+                self.u8(2)?;
+                self.u32(*value)
+            }
+            TraceEvent::Relocate {
+                value,
+                shape,
+                entity_type,
+            } => {
+                self.u8(3)?;
+                self.u32(*value)?;
+                self.shape(shape)?;
+                self.optional_u32(*entity_type)
+            }
+            TraceEvent::Update { value, shape } => {
+                self.u8(4)?;
+                self.u32(*value)?;
+                self.shape(shape)
+            }
+            TraceEvent::ExpandTo(rectangle) => {
+                self.u8(5)?;
+                self.rectangle(rectangle)
+            }
+            TraceEvent::Rebalance => self.u8(6),
+            TraceEvent::ShrinkToFit => self.u8(7),
+            TraceEvent::Optimize => self.u8(11),
+            TraceEvent::Collisions { shape, filter } => {
+                self.u8(8)?;
+                self.shape(shape)?;
+                self.filter(filter)
+            }
+            TraceEvent::CollisionsSwept {
+                shape,
+                velocity,
+                dt,
+            } => {
+                self.u8(9)?;
+                self.shape(shape)?;
+                self.float(velocity.0)?;
+                self.float(velocity.1)?;
+                self.float(*dt)
+            }
+            TraceEvent::LineOfSight { start, end, filter } => {
+                self.u8(10)?;
+                self.point(start)?;
+                self.point(end)?;
+                self.filter(filter)
+            }
+            TraceEvent::CollisionsCoarse { shape, max_depth } => {
+                self.u8(12)?;
+                self.shape(shape)?;
+                self.u64(*max_depth as u64)
+            }
+            TraceEvent::ProximityGraph(max_distance) => {
+                self.u8(13)?;
+                self.float(*max_distance)
+            }
+            TraceEvent::Cluster { eps, min_points } => {
+                self.u8(14)?;
+                self.float(*eps)?;
+                self.u64(*min_points as u64)
+            }
+            TraceEvent::NearestFreePoint {
+                point,
+                clearance_radius,
+            } => {
+                self.u8(15)?;
+                self.point(point)?;
+                self.float(*clearance_radius)
+            }
+        }
+    }
+}
+
+struct Decoder<'a, R: Read> {
+    reader: &'a mut R,
+}
+
+impl<R: Read> Decoder<'_, R> {
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn float(&mut self) -> io::Result<Float> {
+        Ok(f64::from_le_bytes(self.array()?) as Float)
+    }
+
+    fn optional_u32(&mut self) -> io::Result<Option<u32>> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.u32()?)),
+        }
+    }
+
+    fn point(&mut self) -> io::Result<Point> {
+        Ok(Point::new(self.float()?, self.float()?))
+    }
+
+    fn rectangle(&mut self) -> io::Result<Rectangle> {
+        Ok(Rectangle::new(
+            self.float()?,
+            self.float()?,
+            self.float()?,
+            self.float()?,
+        ))
+    }
+
+    fn shape(&mut self) -> io::Result<ShapeEnum> {
+        match self.u8()? {
+            0 => Ok(ShapeEnum::Circle(Circle::new(
+                self.float()?,
+                self.float()?,
+                self.float()?,
+            ))),
+            1 => Ok(ShapeEnum::Rectangle(self.rectangle()?)),
+            2 => Ok(ShapeEnum::Point(self.point()?)),
+            _ => Err(invalid_data("unknown shape tag")),
+        }
+    }
+
+    fn filter(&mut self) -> io::Result<TraceFilter> {
+        match self.u8()? {
+            0 => Ok(TraceFilter::All),
+            1 => {
+                let count = self.u32()?;
+                let types = (0..count)
+                    .map(|_| self.u32())
+                    .collect::<io::Result<Vec<_>>>()?;
+                Ok(TraceFilter::Types(types))
+            }
+            2 => Ok(TraceFilter::Mask(self.u64()?)),
+            _ => Err(invalid_data("unknown filter tag")),
+        }
+    }
+
+    fn config(&mut self) -> io::Result<Config> {
+        let pool_size = self.u64()? as usize;
+        let node_capacity = self.u64()? as usize;
+        let max_depth = self.u64()? as usize;
+        let min_cell_size = match self.u8()? {
+            0 => None,
+            _ => Some(self.float()?),
+        };
+        let root_split = match self.u8()? {
+            0 => None,
+            _ => Some(self.point()?),
+        };
+        let size_classes = match self.u8()? {
+            0 => None,
+            _ => Some(SizeClasses {
+                tiny_max: self.float()?,
+                medium_max: self.float()?,
+            }),
+        };
+        let looseness = self.float()?;
+        let auto_expand = self.u8()? != 0;
+        let degenerate_shapes = match self.u8()? {
+            0 => DegenerateShapeMode::AlwaysMiss,
+            1 => DegenerateShapeMode::PointSemantics,
+            2 => DegenerateShapeMode::ErrorOnInsert,
+            _ => return Err(invalid_data("unknown degenerate shape mode")),
+        };
+        Ok(Config {
+            pool_size,
+            node_capacity,
+            max_depth,
+            min_cell_size,
+            root_split,
+            size_classes,
+            looseness,
+            auto_expand,
+            degenerate_shapes,
+        })
+    }
+
+    fn event(&mut self) -> io::Result<TraceEvent> {
+        match self.u8()? {
+            0 => Ok(TraceEvent::Insert {
+                value: self.u32()?,
+                shape: self.shape()?,
+                entity_type: self.optional_u32()?,
+            }),
+            1 => {
+                let count = self.u64()?;
+                let items = (0..count)
+                    .map(|_| Ok((self.u32()?, self.shape()?, self.optional_u32()?)))
+                    .collect::<io::Result<Vec<_>>>()?;
+                Ok(TraceEvent::InsertBatch(items))
+            }
+            2 => Ok(TraceEvent::Delete(self.u32()?)),
+            3 => Ok(TraceEvent::Relocate {
+                value: self.u32()?,
+                shape: self.shape()?,
+                entity_type: self.optional_u32()?,
+            }),
+            4 => Ok(TraceEvent::Update {
+                value: self.u32()?,
+                shape: self.shape()?,
+            }),
+            5 => Ok(TraceEvent::ExpandTo(self.rectangle()?)),
+            6 => Ok(TraceEvent::Rebalance),
+            7 => Ok(TraceEvent::ShrinkToFit),
+            11 => Ok(TraceEvent::Optimize),
+            8 => Ok(TraceEvent::Collisions {
+                shape: self.shape()?,
+                filter: self.filter()?,
+            }),
+            9 => Ok(TraceEvent::CollisionsSwept {
+                shape: self.shape()?,
+                velocity: (self.float()?, self.float()?),
+                dt: self.float()?,
+            }),
+            10 => Ok(TraceEvent::LineOfSight {
+                start: self.point()?,
+                end: self.point()?,
+                filter: self.filter()?,
+            }),
+            12 => Ok(TraceEvent::CollisionsCoarse {
+                shape: self.shape()?,
+                max_depth: self.u64()? as usize,
+            }),
+            13 => Ok(TraceEvent::ProximityGraph(self.float()?)),
+            14 => Ok(TraceEvent::Cluster {
+                eps: self.float()?,
+                min_points: self.u64()? as usize,
+            }),
+            15 => Ok(TraceEvent::NearestFreePoint {
+                point: self.point()?,
+                clearance_radius: self.float()?,
+            }),
+            _ => Err(invalid_data("unknown event tag")),
+        }
+    }
+}
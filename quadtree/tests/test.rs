@@ -1,8 +1,18 @@
-use quadtree::quadtree::{Config, QuadTree};
-use quadtree::shapes::{Circle, Rectangle, ShapeEnum};
+use quadtree::cancellation::{CancellationToken, Cancelled};
+use quadtree::concurrent::ConcurrentQuadTree;
+use quadtree::counters::OperationCounters;
+use quadtree::error::QuadTreeError;
+use quadtree::quadtree::{
+    Config, DegenerateShapeMode, JoinPredicate, QuadTree, RelocationRequest, RootHeavyReason,
+    SizeClasses,
+};
+use quadtree::shapes::{Circle, Float, Point, Rectangle, ShapeEnum};
+use quadtree::trace::{replay, Trace, TraceEvent, TraceFilter};
 
 use rand::Rng;
 use std::collections::HashSet;
+use std::ops::ControlFlow;
+use std::sync::Arc;
 
 #[test]
 fn test_single_collision() {
@@ -422,6 +432,7 @@ fn test_no_multiple_subdivision() {
         pool_size: 4000,
         node_capacity: 4,
         max_depth: 2,
+        ..Default::default()
     };
 
     // Create a QuadTree with the custom config
@@ -647,3 +658,1785 @@ fn test_collisions_with_entity_type_filter() {
     qt.collisions_filter(query_shape.clone(), Some(vec![2]), &mut collisions);
     assert_eq!(collisions, vec![2]);
 }
+
+#[test]
+fn test_nearest_free_point() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    qt.insert(
+        0,
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 50.0, 50.0)),
+        None,
+    );
+    for i in 1..10 {
+        qt.insert(
+            i,
+            ShapeEnum::Circle(Circle::new(70.0 + i as Float * 2.0, 80.0, 1.0)),
+            None,
+        );
+    }
+
+    // A point that is already free is returned unchanged
+    let free = qt.nearest_free_point(Point::new(75.0, 25.0), 5.0).unwrap();
+    assert_eq!(free, Point::new(75.0, 25.0));
+
+    // A blocked point moves just outside the blocking rectangle
+    let free = qt.nearest_free_point(Point::new(25.0, 25.0), 5.0).unwrap();
+    let distance = free.distance_squared(&Point::new(25.0, 25.0)).sqrt();
+    assert!((distance - 30.0).abs() < 0.01, "distance was {}", distance);
+    let mut collisions = Vec::new();
+    qt.collisions(
+        ShapeEnum::Circle(Circle::new(free.x, free.y, 5.0)),
+        &mut collisions,
+    );
+    assert!(collisions.is_empty());
+
+    // Nothing fits when the clearance circle is larger than the tree
+    assert!(qt
+        .nearest_free_point(Point::new(50.0, 50.0), 60.0)
+        .is_none());
+}
+
+#[test]
+fn test_nearest_free_point_small_gap_in_large_leaf() {
+    // Four walls fill a 1000x1000 leaf except for a 10x10 gap at (600, 600)
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 1000.0, 1000.0));
+    let walls = [
+        Rectangle::new(0.0, 0.0, 1000.0, 600.0),
+        Rectangle::new(0.0, 610.0, 1000.0, 390.0),
+        Rectangle::new(0.0, 600.0, 600.0, 10.0),
+        Rectangle::new(610.0, 600.0, 390.0, 10.0),
+    ];
+    for (i, wall) in walls.into_iter().enumerate() {
+        qt.insert(i as u32, ShapeEnum::Rectangle(wall), None);
+    }
+    assert_eq!(qt.stats().leaf_count, 1);
+
+    let free = qt
+        .nearest_free_point(Point::new(100.0, 100.0), 3.0)
+        .unwrap();
+    assert!((603.0..=607.0).contains(&free.x), "x was {}", free.x);
+    assert!((603.0..=607.0).contains(&free.y), "y was {}", free.y);
+}
+
+#[test]
+fn test_nearest_free_point_huge_world() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 1_000_000.0, 1_000_000.0));
+    qt.insert(
+        0,
+        ShapeEnum::Rectangle(Rectangle::new(400_000.0, 400_000.0, 100.0, 100.0)),
+        None,
+    );
+
+    // A small clearance in a huge leaf only searches the tiles near the point
+    let free = qt
+        .nearest_free_point(Point::new(400_010.0, 400_050.0), 1.0)
+        .unwrap();
+    assert!((free.x - 399_999.0).abs() < 0.1, "x was {}", free.x);
+    assert!((free.y - 400_050.0).abs() < 0.1, "y was {}", free.y);
+
+    // A world with no free space gives up once the sample cap is reached
+    qt.insert(
+        1,
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 1_000_000.0, 1_000_000.0)),
+        None,
+    );
+    assert!(qt
+        .nearest_free_point(Point::new(500_000.0, 500_000.0), 1.0)
+        .is_none());
+}
+
+#[test]
+fn test_line_of_sight_batch() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    // A wall across the middle of the map, and a unit that should never block vision
+    qt.insert(
+        0,
+        ShapeEnum::Rectangle(Rectangle::new(45.0, 0.0, 10.0, 60.0)),
+        Some(1),
+    );
+    qt.insert(1, ShapeEnum::Circle(Circle::new(20.0, 80.0, 5.0)), Some(2));
+
+    let pairs = vec![
+        (Point::new(10.0, 10.0), Point::new(90.0, 10.0)),
+        (Point::new(10.0, 90.0), Point::new(90.0, 90.0)),
+        (Point::new(10.0, 80.0), Point::new(30.0, 80.0)),
+        (Point::new(10.0, 10.0), Point::new(90.0, 90.0)),
+    ];
+
+    let visible = qt.line_of_sight_batch(&pairs, None);
+    assert_eq!(visible, vec![false, true, false, false]);
+
+    let visible = qt.line_of_sight_batch(&pairs, Some(vec![1]));
+    assert_eq!(visible, vec![false, true, true, false]);
+}
+
+#[test]
+fn test_collisions_batch_matches_single_queries() {
+    fn assert_sync<T: Sync>(_: &T) {}
+
+    let mut rng = rand::thread_rng();
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 1000.0, 1000.0));
+    for i in 0..2000 {
+        qt.insert(
+            i,
+            ShapeEnum::Circle(Circle::new(
+                rng.gen_range(0.0..1000.0),
+                rng.gen_range(0.0..1000.0),
+                rng.gen_range(1.0..10.0),
+            )),
+            Some(i % 3),
+        );
+    }
+    assert_sync(&qt);
+
+    let queries: Vec<ShapeEnum> = (0..500)
+        .map(|_| {
+            ShapeEnum::Rectangle(Rectangle::new(
+                rng.gen_range(0.0..950.0),
+                rng.gen_range(0.0..950.0),
+                50.0,
+                50.0,
+            ))
+        })
+        .collect();
+
+    let batch = qt.collisions_batch_filter(queries.clone(), Some(vec![0, 2]));
+    assert_eq!(batch.len(), queries.len());
+    for (query, batch_result) in queries.into_iter().zip(batch) {
+        let mut single = Vec::new();
+        qt.collisions_filter(query, Some(vec![0, 2]), &mut single);
+        assert_eq!(single, batch_result);
+    }
+}
+
+#[test]
+fn test_bulk_load_matches_incremental_inserts() {
+    let mut rng = rand::thread_rng();
+    let items: Vec<(u32, ShapeEnum, Option<u32>)> = (0..3000)
+        .map(|i| {
+            let shape = ShapeEnum::Rectangle(Rectangle::new(
+                rng.gen_range(0.0..990.0),
+                rng.gen_range(0.0..990.0),
+                rng.gen_range(0.5..10.0),
+                rng.gen_range(0.5..10.0),
+            ));
+            (i, shape, Some(i % 4))
+        })
+        .collect();
+
+    let bounding_box = Rectangle::new(0.0, 0.0, 1000.0, 1000.0);
+    let mut incremental = QuadTree::new(bounding_box);
+    for (value, shape, entity_type) in items.clone() {
+        incremental.insert(value, shape, entity_type);
+    }
+    let bulk = QuadTree::bulk_load_with_config(bounding_box, Config::default(), items.clone());
+    let fitted = QuadTree::bulk_load(items);
+
+    let mut shapes = Vec::new();
+    bulk.all_shapes(&mut shapes);
+    assert_eq!(shapes.len(), 3000);
+
+    for _ in 0..200 {
+        let query = ShapeEnum::Circle(Circle::new(
+            rng.gen_range(0.0..1000.0),
+            rng.gen_range(0.0..1000.0),
+            rng.gen_range(1.0..50.0),
+        ));
+        let mut expected = Vec::new();
+        incremental.collisions_filter(query.clone(), Some(vec![1, 2]), &mut expected);
+        expected.sort();
+        for tree in [&bulk, &fitted] {
+            let mut actual = Vec::new();
+            tree.collisions_filter(query.clone(), Some(vec![1, 2]), &mut actual);
+            actual.sort();
+            assert_eq!(actual, expected);
+        }
+    }
+}
+
+#[test]
+fn test_insert_batch_keeps_last_duplicate() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let mut items = Vec::new();
+    for i in 0..20 {
+        let x = (i % 10) as Float * 10.0;
+        items.push((i, ShapeEnum::Circle(Circle::new(x + 5.0, 5.0, 1.0)), None));
+    }
+    items.push((3, ShapeEnum::Circle(Circle::new(95.0, 95.0, 1.0)), None));
+    qt.insert_batch(items);
+
+    let mut shapes = Vec::new();
+    qt.all_shapes(&mut shapes);
+    assert_eq!(shapes.len(), 20);
+
+    let mut collisions = Vec::new();
+    qt.collisions(
+        ShapeEnum::Circle(Circle::new(95.0, 95.0, 1.0)),
+        &mut collisions,
+    );
+    assert_eq!(collisions, vec![3]);
+}
+
+#[test]
+fn test_proximity_graph() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    qt.insert(1, ShapeEnum::Circle(Circle::new(10.0, 10.0, 1.0)), None);
+    qt.insert(2, ShapeEnum::Circle(Circle::new(14.0, 10.0, 1.0)), None);
+    qt.insert(3, ShapeEnum::Circle(Circle::new(10.0, 40.0, 1.0)), None);
+    qt.insert(
+        4,
+        ShapeEnum::Rectangle(Rectangle::new(16.0, 0.0, 10.0, 10.0)),
+        None,
+    );
+    for i in 10..30 {
+        qt.insert(
+            i,
+            ShapeEnum::Circle(Circle::new(
+                60.0 + (i % 5) as Float * 8.0,
+                60.0 + (i / 5) as Float * 8.0,
+                1.0,
+            )),
+            None,
+        );
+    }
+
+    let edges = qt.proximity_graph(2.5);
+    let near_origin: Vec<_> = edges.iter().filter(|(a, _, _)| *a < 10).collect();
+    assert_eq!(near_origin.len(), 2);
+    assert_eq!((near_origin[0].0, near_origin[0].1), (1, 2));
+    assert!((near_origin[0].2 - 2.0).abs() < 1e-5);
+    assert_eq!((near_origin[1].0, near_origin[1].1), (2, 4));
+    assert!((near_origin[1].2 - 1.0).abs() < 1e-5);
+
+    // Grid circles are 6 units apart, so none of them are connected yet
+    assert!(edges.iter().all(|(a, b, _)| *a < 10 && *b < 10));
+
+    // Every grid circle connects to its horizontal and vertical neighbours
+    let grid_edges = qt
+        .proximity_graph(6.0)
+        .into_iter()
+        .filter(|(a, _, _)| *a >= 10)
+        .count();
+    assert_eq!(grid_edges, 4 * 4 + 5 * 3);
+}
+
+#[test]
+fn test_cluster() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    // Two dense groups of points and one isolated point
+    for i in 0..6 {
+        let offset = i as Float;
+        qt.insert(
+            i,
+            ShapeEnum::Circle(Circle::new(10.0 + offset, 10.0, 0.0)),
+            None,
+        );
+        qt.insert(
+            10 + i,
+            ShapeEnum::Circle(Circle::new(80.0, 80.0 + offset, 0.0)),
+            None,
+        );
+    }
+    qt.insert(20, ShapeEnum::Circle(Circle::new(50.0, 50.0, 0.0)), None);
+
+    let labels = qt.cluster(1.5, 3);
+    assert_eq!(labels.len(), 13);
+    assert_eq!(labels[&20], None);
+
+    let first = labels[&0].unwrap();
+    let second = labels[&10].unwrap();
+    assert_ne!(first, second);
+    for i in 0..6 {
+        assert_eq!(labels[&i], Some(first));
+        assert_eq!(labels[&(10 + i)], Some(second));
+    }
+
+    // With a larger neighbourhood requirement every point is noise
+    assert!(qt.cluster(1.5, 4).values().all(|label| label.is_none()));
+}
+
+#[cfg(feature = "f64")]
+#[test]
+fn test_f64_precision_for_large_coordinates() {
+    // These rectangles are 0.1 units apart, which f32 cannot represent at this magnitude
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100_000_000.0, 100_000_000.0));
+    qt.insert(
+        0,
+        ShapeEnum::Rectangle(Rectangle::new(50_000_000.0, 50_000_000.0, 1.0, 1.0)),
+        None,
+    );
+    let mut collisions = Vec::new();
+    qt.collisions(
+        ShapeEnum::Rectangle(Rectangle::new(50_000_001.1, 50_000_000.0, 1.0, 1.0)),
+        &mut collisions,
+    );
+    assert!(collisions.is_empty());
+}
+
+#[test]
+fn test_point_shapes() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let mut rng = rand::thread_rng();
+    let mut points = Vec::new();
+    for i in 0..500 {
+        let point = Point::new(rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0));
+        qt.insert(i, ShapeEnum::Point(point), None);
+        points.push(point);
+    }
+    // Points on the split lines and the tree edges are stored like any other point
+    qt.insert(500, ShapeEnum::Point(Point::new(50.0, 50.0)), None);
+    qt.insert(501, ShapeEnum::Point(Point::new(100.0, 0.0)), None);
+    points.push(Point::new(50.0, 50.0));
+    points.push(Point::new(100.0, 0.0));
+
+    let query = Circle::new(50.0, 50.0, 20.0);
+    let mut collisions = Vec::new();
+    qt.collisions(ShapeEnum::Circle(query), &mut collisions);
+    collisions.sort();
+    let expected: Vec<u32> = (0..points.len() as u32)
+        .filter(|&i| points[i as usize].distance_squared(&Point::new(50.0, 50.0)) <= 400.0)
+        .collect();
+    assert_eq!(collisions, expected);
+
+    // Points on a rectangle edge count as inside it
+    let mut collisions = Vec::new();
+    qt.collisions(
+        ShapeEnum::Rectangle(Rectangle::new(90.0, 0.0, 10.0, 0.0)),
+        &mut collisions,
+    );
+    assert!(collisions.contains(&501));
+
+    // Relocating a point moves it between quadrants
+    qt.relocate(500, ShapeEnum::Point(Point::new(10.0, 90.0)), None);
+    let mut collisions = Vec::new();
+    qt.collisions(ShapeEnum::Point(Point::new(10.0, 90.0)), &mut collisions);
+    assert!(collisions.contains(&500));
+}
+
+#[test]
+fn test_spatial_join() {
+    let mut roads = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    roads.insert(
+        1,
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 10.0, 100.0, 2.0)),
+        None,
+    );
+    roads.insert(
+        2,
+        ShapeEnum::Rectangle(Rectangle::new(50.0, 0.0, 2.0, 100.0)),
+        None,
+    );
+
+    let mut buildings = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    buildings.insert(10, ShapeEnum::Point(Point::new(20.0, 11.0)), None);
+    buildings.insert(11, ShapeEnum::Point(Point::new(51.0, 11.0)), None);
+    buildings.insert(12, ShapeEnum::Point(Point::new(55.0, 80.0)), None);
+    buildings.insert(13, ShapeEnum::Point(Point::new(90.0, 90.0)), None);
+
+    let pairs = roads.spatial_join(&buildings, JoinPredicate::Intersects);
+    assert_eq!(pairs, vec![(1, 10), (1, 11), (2, 11)]);
+
+    let pairs = roads.spatial_join(&buildings, JoinPredicate::WithinDistance(3.0));
+    assert_eq!(pairs, vec![(1, 10), (1, 11), (2, 11), (2, 12)]);
+}
+
+#[test]
+fn test_size_classes_match_single_tree() {
+    let bounding_box = Rectangle::new(0.0, 0.0, 1000.0, 1000.0);
+    let config = Config {
+        size_classes: Some(SizeClasses {
+            tiny_max: 10.0,
+            medium_max: 200.0,
+        }),
+        ..Default::default()
+    };
+    let mut classed = QuadTree::new_with_config(bounding_box, config);
+    let mut single = QuadTree::new(bounding_box);
+
+    let mut rng = rand::thread_rng();
+    for i in 0..500 {
+        let size = match i % 50 {
+            0 => 900.0,
+            1..=9 => rng.gen_range(10.0..200.0),
+            _ => rng.gen_range(1.0..10.0),
+        };
+        let shape = ShapeEnum::Rectangle(Rectangle::new(
+            rng.gen_range(0.0..1000.0 - size),
+            rng.gen_range(0.0..1000.0 - size),
+            size,
+            size,
+        ));
+        classed.insert(i, shape.clone(), None);
+        single.insert(i, shape, None);
+    }
+
+    // Grow a tiny entity into a huge one so it changes size class
+    let grown = ShapeEnum::Rectangle(Rectangle::new(50.0, 50.0, 800.0, 800.0));
+    classed.relocate(1, grown.clone(), None);
+    single.relocate(1, grown, None);
+
+    for _ in 0..100 {
+        let query = ShapeEnum::Circle(Circle::new(
+            rng.gen_range(0.0..1000.0),
+            rng.gen_range(0.0..1000.0),
+            rng.gen_range(1.0..50.0),
+        ));
+        let mut expected = Vec::new();
+        single.collisions(query.clone(), &mut expected);
+        expected.sort();
+        let mut actual = Vec::new();
+        classed.collisions(query, &mut actual);
+        actual.sort();
+        assert_eq!(actual, expected);
+    }
+}
+
+#[test]
+fn test_loose_tree_relocations_match_tight_tree() {
+    let bounding_box = Rectangle::new(0.0, 0.0, 1000.0, 1000.0);
+    let config = Config {
+        looseness: 2.0,
+        ..Default::default()
+    };
+    let mut loose = QuadTree::new_with_config(bounding_box, config);
+    let mut tight = QuadTree::new(bounding_box);
+
+    let mut rng = rand::thread_rng();
+    let mut positions: Vec<(Float, Float)> = (0..300)
+        .map(|_| (rng.gen_range(0.0..990.0), rng.gen_range(0.0..990.0)))
+        .collect();
+    for (i, &(x, y)) in positions.iter().enumerate() {
+        let shape = ShapeEnum::Rectangle(Rectangle::new(x, y, 10.0, 10.0));
+        loose.insert(i as u32, shape.clone(), None);
+        tight.insert(i as u32, shape, None);
+    }
+
+    for _ in 0..20 {
+        // Nudge every entity a little, as a frame of movement would
+        for (i, (x, y)) in positions.iter_mut().enumerate() {
+            *x = (*x + rng.gen_range(-5.0..5.0)).clamp(0.0, 990.0);
+            *y = (*y + rng.gen_range(-5.0..5.0)).clamp(0.0, 990.0);
+            let shape = ShapeEnum::Rectangle(Rectangle::new(*x, *y, 10.0, 10.0));
+            loose.relocate(i as u32, shape.clone(), None);
+            tight.relocate(i as u32, shape, None);
+        }
+
+        let query = ShapeEnum::Circle(Circle::new(
+            rng.gen_range(0.0..1000.0),
+            rng.gen_range(0.0..1000.0),
+            rng.gen_range(10.0..100.0),
+        ));
+        let mut expected = Vec::new();
+        tight.collisions(query.clone(), &mut expected);
+        expected.sort();
+        let mut actual = Vec::new();
+        loose.collisions(query, &mut actual);
+        actual.sort();
+        assert_eq!(actual, expected);
+    }
+}
+
+#[test]
+fn test_auto_expand() {
+    let config = Config {
+        auto_expand: true,
+        ..Default::default()
+    };
+    let mut qt = QuadTree::new_with_config(Rectangle::new(0.0, 0.0, 100.0, 100.0), config);
+    for i in 0..50 {
+        let offset = (i % 10) as Float * 9.0;
+        qt.insert(
+            i,
+            ShapeEnum::Rectangle(Rectangle::new(offset, offset, 5.0, 5.0)),
+            None,
+        );
+    }
+
+    // Inserts and relocates outside the bounds grow the tree towards them
+    qt.insert(100, ShapeEnum::Circle(Circle::new(-250.0, 30.0, 5.0)), None);
+    qt.relocate(0, ShapeEnum::Point(Point::new(350.0, 420.0)), None);
+
+    let mut bounding_boxes = Vec::new();
+    qt.all_node_bounding_boxes(&mut bounding_boxes);
+    let root = bounding_boxes[0];
+    assert!(root.x <= -255.0 && root.right() >= 350.0 && root.bottom() >= 420.0);
+
+    let mut collisions = Vec::new();
+    qt.collisions(
+        ShapeEnum::Rectangle(Rectangle::new(-260.0, 20.0, 20.0, 20.0)),
+        &mut collisions,
+    );
+    assert_eq!(collisions, vec![100]);
+    let mut collisions = Vec::new();
+    qt.collisions(
+        ShapeEnum::Circle(Circle::new(350.0, 420.0, 1.0)),
+        &mut collisions,
+    );
+    assert_eq!(collisions, vec![0]);
+    let mut collisions = Vec::new();
+    qt.collisions(
+        ShapeEnum::Rectangle(Rectangle::new(9.0, 9.0, 1.0, 1.0)),
+        &mut collisions,
+    );
+    collisions.sort();
+    assert_eq!(collisions, vec![1, 11, 21, 31, 41]);
+
+    // Explicit expansion works without auto_expand
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    qt.expand_to(&Rectangle::new(-50.0, 0.0, 300.0, 100.0));
+    let mut bounding_boxes = Vec::new();
+    qt.all_node_bounding_boxes(&mut bounding_boxes);
+    assert_eq!(bounding_boxes.len(), 1);
+    assert!(bounding_boxes[0].x <= -50.0 && bounding_boxes[0].right() >= 250.0);
+
+    // Growing keeps the smallest cells the same size whether or not the root had split
+    let config = Config {
+        auto_expand: true,
+        ..Default::default()
+    };
+    let far = ShapeEnum::Point(Point::new(300.0, 300.0));
+    let mut depths = Vec::new();
+    for far_first in [true, false] {
+        let mut qt =
+            QuadTree::new_with_config(Rectangle::new(0.0, 0.0, 100.0, 100.0), config.clone());
+        if far_first {
+            qt.insert(1000, far.clone(), None);
+        }
+        for i in 0..100 {
+            qt.insert(i, ShapeEnum::Point(Point::new(1.0, 1.0)), None);
+        }
+        if !far_first {
+            qt.insert(1000, far.clone(), None);
+        }
+        depths.push(qt.stats().max_depth);
+    }
+    assert_eq!(depths, vec![8, 8]);
+}
+
+#[test]
+fn test_root_heavy_entities() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    for i in 0..20 {
+        let offset = (i % 4) as Float * 20.0 + 2.0;
+        qt.insert(
+            i,
+            ShapeEnum::Rectangle(Rectangle::new(offset, offset, 1.0, 1.0)),
+            None,
+        );
+    }
+    qt.insert(
+        100,
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 90.0, 90.0)),
+        None,
+    );
+    qt.insert(101, ShapeEnum::Circle(Circle::new(50.0, 50.0, 2.0)), None);
+    qt.insert(102, ShapeEnum::Circle(Circle::new(120.0, 50.0, 2.0)), None);
+
+    let root_heavy = qt.root_heavy_entities(1);
+    let reasons: Vec<(u32, RootHeavyReason)> = root_heavy
+        .iter()
+        .map(|entity| (entity.value, entity.reason))
+        .collect();
+    assert_eq!(
+        reasons,
+        vec![
+            (100, RootHeavyReason::TooLarge),
+            (101, RootHeavyReason::Straddling),
+            (102, RootHeavyReason::OutOfBounds),
+        ]
+    );
+    assert!(root_heavy.iter().all(|entity| entity.depth == 0));
+
+    let counts = qt.record_root_heavy_counts(1);
+    assert_eq!(counts.total, 3);
+    qt.delete(100);
+    let counts = qt.record_root_heavy_counts(1);
+    assert_eq!(counts.too_large, 0);
+    let history = qt.root_heavy_history();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].total, 3);
+    assert_eq!(history[1].total, 2);
+}
+
+#[test]
+fn test_iterators() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let mut rng = rand::thread_rng();
+    for i in 0..200 {
+        let shape = ShapeEnum::Circle(Circle::new(
+            rng.gen_range(0.0..100.0),
+            rng.gen_range(0.0..100.0),
+            rng.gen_range(0.5..3.0),
+        ));
+        qt.insert(i, shape, Some(i % 3));
+    }
+
+    let mut values: Vec<u32> = qt.iter().map(|(value, _, _)| value).collect();
+    values.sort();
+    assert_eq!(values, (0..200).collect::<Vec<u32>>());
+    assert!(qt
+        .iter()
+        .all(|(value, _, entity_type)| entity_type == Some(value % 3)));
+
+    let region = Rectangle::new(20.0, 30.0, 25.0, 15.0);
+    let mut in_region: Vec<u32> = qt
+        .iter_in_region(region)
+        .map(|(value, _, _)| value)
+        .collect();
+    in_region.sort();
+    let mut expected = Vec::new();
+    qt.collisions(ShapeEnum::Rectangle(region), &mut expected);
+    expected.sort();
+    assert_eq!(in_region, expected);
+
+    let mut bounding_boxes = Vec::new();
+    qt.all_node_bounding_boxes(&mut bounding_boxes);
+    let nodes: Vec<_> = qt.nodes().collect();
+    assert_eq!(nodes.len(), bounding_boxes.len());
+    assert_eq!(
+        nodes.iter().map(|node| node.entity_count).sum::<usize>(),
+        200
+    );
+    assert!(nodes
+        .iter()
+        .all(|node| node.is_leaf || node.bounding_box.width > 100.0 / 64.0));
+}
+
+#[test]
+fn test_collisions_coarse() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let mut rng = rand::thread_rng();
+    for i in 0..300 {
+        let shape = ShapeEnum::Circle(Circle::new(
+            rng.gen_range(0.0..100.0),
+            rng.gen_range(0.0..100.0),
+            1.0,
+        ));
+        qt.insert(i, shape, None);
+    }
+    qt.insert(
+        1000,
+        ShapeEnum::Rectangle(Rectangle::new(10.0, 10.0, 80.0, 80.0)),
+        None,
+    );
+
+    let query = ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 40.0, 40.0));
+    let mut collisions = Vec::new();
+    let mut summaries = Vec::new();
+    qt.collisions_coarse(query.clone(), 1, &mut collisions, &mut summaries);
+    assert!(collisions.contains(&1000));
+    // Only the north-west quadrant touches the query
+    assert_eq!(summaries.len(), 1);
+    assert_eq!(summaries[0].depth, 1);
+    assert_eq!(summaries[0].bounding_box.width, 50.0);
+
+    // Every exact hit is either reported directly or inside a summarized subtree
+    let mut exact = Vec::new();
+    qt.collisions(query, &mut exact);
+    assert!(collisions.iter().all(|value| exact.contains(value)));
+    let covered: usize = summaries.iter().map(|summary| summary.entity_count).sum();
+    assert!(covered + collisions.len() >= exact.len());
+}
+
+#[test]
+fn test_collisions_with_callback() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    for i in 0..50 {
+        let x = (i % 10) as Float * 10.0 + 5.0;
+        let y = (i / 10) as Float * 10.0 + 5.0;
+        qt.insert(i, ShapeEnum::Circle(Circle::new(x, y, 2.0)), Some(i % 2));
+    }
+
+    let query = ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 50.0, 30.0));
+    let mut expected = Vec::new();
+    qt.collisions(query.clone(), &mut expected);
+    expected.sort();
+
+    let mut visited = Vec::new();
+    let flow = qt.collisions_with(&query, None, |value| {
+        visited.push(value);
+        ControlFlow::Continue(())
+    });
+    assert!(flow.is_continue());
+    visited.sort();
+    assert_eq!(visited, expected);
+
+    // Breaking stops after the first hit
+    let mut visits = 0;
+    let flow = qt.collisions_with(&query, Some(&[1]), |value| {
+        visits += 1;
+        assert_eq!(value % 2, 1);
+        ControlFlow::Break(())
+    });
+    assert!(flow.is_break());
+    assert_eq!(visits, 1);
+
+    assert!(qt.any_collision(&query, Some(&[0])));
+    assert!(!qt.any_collision(&query, Some(&[7])));
+    assert!(!qt.any_collision(&ShapeEnum::Circle(Circle::new(10.0, 10.0, 1.0)), None));
+}
+
+#[test]
+fn test_interpolated_shapes() {
+    let a = ShapeEnum::Circle(Circle::new(0.0, 10.0, 2.0));
+    let b = ShapeEnum::Circle(Circle::new(10.0, 20.0, 4.0));
+    match ShapeEnum::lerp(&a, &b, 0.25) {
+        ShapeEnum::Circle(circle) => {
+            assert_eq!((circle.x, circle.y, circle.radius), (2.5, 12.5, 2.5));
+            assert_eq!(circle.bounding_box.width, 5.0);
+        }
+        _ => panic!("Expected a circle"),
+    }
+    // Shapes of different kinds snap to the later one
+    let point = ShapeEnum::Point(Point::new(1.0, 1.0));
+    assert!(matches!(
+        ShapeEnum::lerp(&a, &point, 0.1),
+        ShapeEnum::Point(_)
+    ));
+
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    qt.insert(
+        1,
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 10.0, 10.0)),
+        None,
+    );
+    qt.insert(2, ShapeEnum::Point(Point::new(50.0, 50.0)), None);
+    let previous = qt.shape_snapshot();
+
+    qt.relocate(
+        1,
+        ShapeEnum::Rectangle(Rectangle::new(20.0, 40.0, 10.0, 10.0)),
+        None,
+    );
+    qt.insert(3, ShapeEnum::Point(Point::new(5.0, 5.0)), None);
+
+    let shapes = qt.interpolated_shapes(&previous, 0.5);
+    let values: Vec<u32> = shapes.iter().map(|(value, _)| *value).collect();
+    assert_eq!(values, vec![1, 2, 3]);
+    match &shapes[0].1 {
+        ShapeEnum::Rectangle(rectangle) => assert_eq!((rectangle.x, rectangle.y), (10.0, 20.0)),
+        _ => panic!("Expected a rectangle"),
+    }
+    match &shapes[2].1 {
+        ShapeEnum::Point(point) => assert_eq!((point.x, point.y), (5.0, 5.0)),
+        _ => panic!("Expected a point"),
+    }
+}
+
+#[test]
+fn test_collisions_mask() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let mut rng = rand::thread_rng();
+    for i in 0..300 {
+        let shape = ShapeEnum::Circle(Circle::new(
+            rng.gen_range(0.0..100.0),
+            rng.gen_range(0.0..100.0),
+            rng.gen_range(0.5..3.0),
+        ));
+        // Include untyped entities and a type too large for the mask
+        let entity_type = match i % 5 {
+            0 => None,
+            4 => Some(100),
+            _ => Some(i % 5),
+        };
+        qt.insert(i, shape, entity_type);
+    }
+
+    let query = ShapeEnum::Rectangle(Rectangle::new(10.0, 10.0, 60.0, 60.0));
+    let mut by_mask = Vec::new();
+    qt.collisions_mask(query.clone(), (1 << 1) | (1 << 3), &mut by_mask);
+    by_mask.sort();
+    let mut by_list = Vec::new();
+    qt.collisions_filter(query.clone(), Some(vec![1, 3]), &mut by_list);
+    by_list.sort();
+    assert!(!by_mask.is_empty());
+    assert_eq!(by_mask, by_list);
+
+    // Lists with types beyond the mask width still work through the old API
+    let mut large = Vec::new();
+    qt.collisions_filter(query.clone(), Some(vec![100]), &mut large);
+    let mut all = Vec::new();
+    qt.collisions(query, &mut all);
+    all.sort();
+    large.sort();
+    let expected: Vec<u32> = all.into_iter().filter(|value| value % 5 == 4).collect();
+    assert_eq!(large, expected);
+}
+
+#[test]
+fn test_cancellation() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    for i in 0..100 {
+        let x = (i % 10) as Float * 10.0;
+        let y = (i / 10) as Float * 10.0;
+        qt.insert(i, ShapeEnum::Circle(Circle::new(x, y, 3.0)), None);
+    }
+    let shapes = vec![ShapeEnum::Circle(Circle::new(50.0, 50.0, 20.0)); 10];
+
+    let token = CancellationToken::new();
+    let results = qt
+        .collisions_batch_cancellable(shapes.clone(), None, &token)
+        .unwrap();
+    assert_eq!(results, qt.collisions_batch(shapes.clone()));
+    assert_eq!(
+        qt.proximity_graph_cancellable(10.0, &token).unwrap(),
+        qt.proximity_graph(10.0)
+    );
+
+    // A clone shares the flag, so cancelling it stops queries holding the original
+    token.clone().cancel();
+    assert!(token.is_cancelled());
+    assert_eq!(
+        qt.collisions_batch_cancellable(shapes, None, &token),
+        Err(Cancelled)
+    );
+    assert_eq!(qt.proximity_graph_cancellable(10.0, &token), Err(Cancelled));
+    assert_eq!(
+        qt.spatial_join_cancellable(&qt, JoinPredicate::Intersects, &token),
+        Err(Cancelled)
+    );
+}
+
+#[test]
+fn test_stats() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let stats = qt.stats();
+    assert_eq!(
+        (stats.node_count, stats.entry_count, stats.splits),
+        (1, 0, 0)
+    );
+    assert_eq!(stats.entries_per_node, vec![1]);
+
+    for i in 0..40 {
+        let x = (i % 8) as Float * 12.0 + 2.0;
+        let y = (i / 8) as Float * 12.0 + 2.0;
+        qt.insert(i, ShapeEnum::Circle(Circle::new(x, y, 1.0)), None);
+    }
+    let stats = qt.stats();
+    let mut bounding_boxes = Vec::new();
+    qt.all_node_bounding_boxes(&mut bounding_boxes);
+    assert_eq!(stats.node_count, bounding_boxes.len());
+    assert_eq!(stats.entry_count, 40);
+    assert_eq!(
+        stats.entries_per_node.iter().sum::<usize>(),
+        stats.node_count
+    );
+    assert_eq!(stats.node_count, 1 + 4 * stats.splits as usize);
+    assert!(stats.max_depth >= 2 && stats.average_depth > 1.0);
+    assert_eq!(stats.pool_size, 4000);
+
+    // Deleting everything collapses the tree and returns its nodes to the pool
+    for i in 0..40 {
+        qt.delete(i);
+    }
+    let stats = qt.stats();
+    assert!(stats.merges > 0);
+    assert!(stats.pooled_nodes > 0 && stats.pool_utilization > 0.0);
+}
+
+#[test]
+fn test_density_pyramid() {
+    let bounding_box = Rectangle::new(0.0, 0.0, 100.0, 100.0);
+    let mut qt = QuadTree::new(bounding_box);
+    // Quadrants split off the center straddle grid cells from level 1 down
+    let mut split = QuadTree::new_with_config(
+        bounding_box,
+        Config {
+            root_split: Some(Point::new(20.0, 30.0)),
+            ..Default::default()
+        },
+    );
+    let mut rng = rand::thread_rng();
+    let mut centers = Vec::new();
+    for i in 0..500 {
+        let x = rng.gen_range(1.0..99.0);
+        let y = rng.gen_range(1.0..99.0);
+        qt.insert(i, ShapeEnum::Circle(Circle::new(x, y, 0.5)), None);
+        split.insert(i, ShapeEnum::Circle(Circle::new(x, y, 0.5)), None);
+        centers.push((x, y));
+    }
+
+    for tree in [&qt, &split] {
+        let pyramid = tree.density_pyramid(5);
+        assert_eq!(pyramid.len(), 5);
+        assert_eq!(pyramid[0].counts, vec![500]);
+        for (level, grid) in pyramid.iter().enumerate() {
+            assert_eq!(grid.resolution, 1 << level);
+            assert_eq!(grid.counts.iter().sum::<usize>(), 500);
+
+            let cell_size = 100.0 / grid.resolution as Float;
+            for row in 0..grid.resolution {
+                for column in 0..grid.resolution {
+                    let expected = centers
+                        .iter()
+                        .filter(|&&(x, y)| {
+                            (x / cell_size) as usize == column && (y / cell_size) as usize == row
+                        })
+                        .count();
+                    assert_eq!(grid.count(column, row), expected);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_degenerate_shapes() {
+    let bounding_box = Rectangle::new(0.0, 0.0, 100.0, 100.0);
+    let tree_with = |degenerate_shapes| {
+        let config = Config {
+            degenerate_shapes,
+            ..Default::default()
+        };
+        let mut qt = QuadTree::new_with_config(bounding_box, config);
+        qt.insert(
+            1,
+            ShapeEnum::Rectangle(Rectangle::new(10.0, 10.0, 10.0, 10.0)),
+            None,
+        );
+        qt
+    };
+    // A zero-width rectangle lying on the right edge of entity 1
+    let edge = ShapeEnum::Rectangle(Rectangle::new(20.0, 12.0, 0.0, 5.0));
+    let dot = ShapeEnum::Circle(Circle::new(15.0, 15.0, 0.0));
+
+    let mut collisions = Vec::new();
+    tree_with(DegenerateShapeMode::PointSemantics).collisions(edge.clone(), &mut collisions);
+    assert_eq!(collisions, vec![1]);
+    collisions.clear();
+    tree_with(DegenerateShapeMode::PointSemantics).collisions(dot.clone(), &mut collisions);
+    assert_eq!(collisions, vec![1]);
+
+    let always_miss = tree_with(DegenerateShapeMode::AlwaysMiss);
+    collisions.clear();
+    always_miss.collisions(edge, &mut collisions);
+    always_miss.collisions(dot.clone(), &mut collisions);
+    assert!(collisions.is_empty());
+
+    let mut strict = tree_with(DegenerateShapeMode::ErrorOnInsert);
+    assert_eq!(
+        strict.try_insert(2, dot, None),
+        Err(QuadTreeError::DegenerateShape(2))
+    );
+    assert!(strict
+        .try_insert(3, ShapeEnum::Point(Point::new(50.0, 50.0)), None)
+        .is_ok());
+    assert_eq!(
+        strict.try_relocate(
+            1,
+            ShapeEnum::Rectangle(Rectangle::new(10.0, 10.0, 0.0, 0.0)),
+            None
+        ),
+        Err(QuadTreeError::DegenerateShape(1))
+    );
+    assert_eq!(strict.iter().count(), 2);
+}
+
+#[test]
+fn test_degenerate_shapes_in_other_queries() {
+    let tree_with = |degenerate_shapes| {
+        let config = Config {
+            degenerate_shapes,
+            ..Default::default()
+        };
+        let mut qt = QuadTree::new_with_config(Rectangle::new(0.0, 0.0, 100.0, 100.0), config);
+        // A wall without width across the middle of the map, and a point just beside it
+        qt.insert(
+            1,
+            ShapeEnum::Rectangle(Rectangle::new(50.0, 0.0, 0.0, 100.0)),
+            None,
+        );
+        qt.insert(2, ShapeEnum::Point(Point::new(50.5, 20.0)), None);
+        qt
+    };
+    let mover = ShapeEnum::Point(Point::new(10.0, 50.0));
+    let sight_line = [(Point::new(10.0, 50.0), Point::new(90.0, 50.0))];
+
+    let points = tree_with(DegenerateShapeMode::PointSemantics);
+    let hits = points.collisions_swept(&mover, (100.0, 0.0), 1.0);
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].value, 1);
+    assert!((hits[0].time_of_impact - 0.4).abs() < 1e-4);
+    assert_eq!(points.line_of_sight_batch(&sight_line, None), vec![false]);
+    assert_eq!(points.proximity_graph(1.0), vec![(1, 2, 0.5)]);
+
+    let always_miss = tree_with(DegenerateShapeMode::AlwaysMiss);
+    assert!(always_miss
+        .collisions_swept(&mover, (100.0, 0.0), 1.0)
+        .is_empty());
+    assert_eq!(
+        always_miss.line_of_sight_batch(&sight_line, None),
+        vec![true]
+    );
+    assert!(always_miss.proximity_graph(1.0).is_empty());
+}
+
+#[test]
+fn test_collisions_swept() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    // A thin wall that a fast circle would skip over between ticks
+    qt.insert(
+        1,
+        ShapeEnum::Rectangle(Rectangle::new(50.0, 0.0, 0.5, 100.0)),
+        None,
+    );
+    qt.insert(2, ShapeEnum::Circle(Circle::new(30.0, 21.0, 1.0)), None);
+    qt.insert(3, ShapeEnum::Point(Point::new(11.0, 50.0)), None);
+
+    let circle = ShapeEnum::Circle(Circle::new(10.0, 50.0, 2.0));
+    let mut collisions = Vec::new();
+    qt.collisions(
+        ShapeEnum::Circle(Circle::new(90.0, 50.0, 2.0)),
+        &mut collisions,
+    );
+    assert!(collisions.is_empty());
+
+    let hits = qt.collisions_swept(&circle, (80.0, 0.0), 1.0);
+    let values: Vec<u32> = hits.iter().map(|hit| hit.value).collect();
+    assert_eq!(values, vec![3, 1]);
+    assert_eq!(hits[0].time_of_impact, 0.0);
+    assert!((hits[1].time_of_impact - 0.475).abs() < 1e-4);
+
+    // A rectangle swept into a circle hits it once its right edge reaches x = 29
+    let rectangle = ShapeEnum::Rectangle(Rectangle::new(10.0, 20.0, 2.0, 2.0));
+    let hits = qt.collisions_swept(&rectangle, (40.0, 0.0), 0.5);
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].value, 2);
+    assert!((hits[0].time_of_impact - 0.425).abs() < 1e-4);
+
+    // Passing diagonally by the circle misses its rounded edge
+    let hits = qt.collisions_swept(&rectangle, (20.0, -20.0), 1.0);
+    assert!(hits.is_empty());
+}
+
+#[test]
+fn test_take_counters() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let mut rng = rand::thread_rng();
+    for i in 0..200 {
+        let x = rng.gen_range(0.0..99.0);
+        let y = rng.gen_range(0.0..99.0);
+        qt.insert(
+            i,
+            ShapeEnum::Rectangle(Rectangle::new(x, y, 1.0, 1.0)),
+            None,
+        );
+    }
+    let counters = qt.take_counters();
+    assert_eq!(counters.inserts, 200);
+    assert!(counters.splits > 0);
+    assert_eq!(counters.deletes, 0);
+
+    // Taking the counters resets them
+    assert_eq!(qt.take_counters(), OperationCounters::default());
+
+    let mut collisions = Vec::new();
+    qt.collisions(
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 100.0, 100.0)),
+        &mut collisions,
+    );
+    assert_eq!(
+        qt.take_counters().nodes_visited,
+        qt.stats().node_count as u64
+    );
+
+    for i in 0..100 {
+        qt.relocate(i, ShapeEnum::Circle(Circle::new(50.0, 50.0, 1.0)), None);
+    }
+    for i in 0..200 {
+        qt.delete(i);
+    }
+    let counters = qt.take_counters();
+    assert_eq!(counters.relocates, 100);
+    assert_eq!(counters.deletes, 200);
+    assert!(counters.collapses > 0);
+    assert_eq!(counters.inserts, 0);
+}
+
+#[test]
+fn test_update_and_rebalance() {
+    let config = Config {
+        node_capacity: 4,
+        ..Default::default()
+    };
+    let mut qt = QuadTree::new_with_config(Rectangle::new(0.0, 0.0, 100.0, 100.0), config);
+    let mut rng = rand::thread_rng();
+    for i in 0..500 {
+        let x = rng.gen_range(0.0..99.0);
+        let y = rng.gen_range(0.0..99.0);
+        qt.insert(
+            i,
+            ShapeEnum::Rectangle(Rectangle::new(x, y, 1.0, 1.0)),
+            Some(7),
+        );
+    }
+
+    // Updates keep the entity type
+    assert!(qt.update(0, ShapeEnum::Circle(Circle::new(5.0, 5.0, 1.0))));
+    let mut collisions = Vec::new();
+    qt.collisions_filter(
+        ShapeEnum::Circle(Circle::new(5.0, 5.0, 0.5)),
+        Some(vec![7]),
+        &mut collisions,
+    );
+    assert!(collisions.contains(&0));
+    assert!(!qt.update(1000, ShapeEnum::Point(Point::new(5.0, 5.0))));
+    assert!(!qt.iter().any(|(value, _, _)| value == 1000));
+
+    // Gather every entity in one corner, leaving the rest of the tree empty
+    for i in 0..500 {
+        let x = rng.gen_range(0.0..5.0);
+        let y = rng.gen_range(0.0..5.0);
+        qt.update(i, ShapeEnum::Rectangle(Rectangle::new(x, y, 1.0, 1.0)));
+    }
+    let before = qt.stats().node_count;
+    qt.rebalance();
+    assert!(qt.stats().node_count < before);
+
+    for i in 0..497 {
+        qt.delete(i);
+    }
+    qt.rebalance();
+    let stats = qt.stats();
+    assert_eq!(stats.node_count, 1);
+    assert_eq!(stats.entry_count, 3);
+
+    qt.shrink_to_fit();
+    let stats = qt.stats();
+    assert_eq!(stats.pooled_nodes, 0);
+    assert_eq!(stats.node_count, 1);
+    collisions.clear();
+    qt.collisions(
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 100.0, 100.0)),
+        &mut collisions,
+    );
+    collisions.sort();
+    assert_eq!(collisions, vec![497, 498, 499]);
+}
+
+#[test]
+fn test_duplicate_ids() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let far_corner = ShapeEnum::Circle(Circle::new(90.0, 90.0, 1.0));
+    let mut collisions = Vec::new();
+
+    qt.insert(1, ShapeEnum::Circle(Circle::new(10.0, 10.0, 1.0)), None);
+    assert_eq!(
+        qt.try_insert(1, far_corner.clone(), None),
+        Err(QuadTreeError::AlreadyExists(1))
+    );
+
+    // Inserting the same value again replaces the entry rather than adding a second one
+    qt.insert(1, far_corner.clone(), None);
+    assert_eq!(qt.iter().count(), 1);
+    qt.collisions(
+        ShapeEnum::Circle(Circle::new(10.0, 10.0, 1.0)),
+        &mut collisions,
+    );
+    assert!(collisions.is_empty());
+
+    assert!(qt.upsert(1, ShapeEnum::Point(Point::new(50.0, 50.0)), Some(2)));
+    assert!(!qt.upsert(2, far_corner.clone(), None));
+    assert_eq!(qt.iter().count(), 2);
+
+    assert!(qt.relocate(2, ShapeEnum::Point(Point::new(5.0, 5.0)), None));
+    assert!(!qt.relocate(3, ShapeEnum::Point(Point::new(6.0, 6.0)), None));
+    assert_eq!(qt.try_relocate(3, far_corner, None), Ok(true));
+
+    assert!(qt.delete(1));
+    assert!(!qt.delete(1));
+    assert!(!qt.delete(42));
+    assert_eq!(qt.iter().count(), 2);
+
+    // try_insert_batch rejects values already in the tree or repeated in the batch, while
+    // insert_batch replaces them
+    let point = |x| ShapeEnum::Point(Point::new(x, x));
+    assert_eq!(
+        qt.try_insert_batch(vec![(4, point(1.0), None), (2, point(2.0), None)]),
+        Err(QuadTreeError::AlreadyExists(2))
+    );
+    assert_eq!(
+        qt.try_insert_batch(vec![(4, point(1.0), None), (4, point(2.0), None)]),
+        Err(QuadTreeError::AlreadyExists(4))
+    );
+    assert_eq!(qt.iter().count(), 2);
+    qt.insert_batch(vec![(4, point(1.0), None), (4, point(2.0), None)]);
+    assert_eq!(qt.iter().count(), 3);
+}
+
+#[test]
+fn test_templates() {
+    let config = Config {
+        degenerate_shapes: DegenerateShapeMode::ErrorOnInsert,
+        ..Default::default()
+    };
+    let mut qt = QuadTree::new_with_config(Rectangle::new(0.0, 0.0, 100.0, 100.0), config);
+    let bullet = qt.register_template(ShapeEnum::Circle(Circle::new(0.0, 0.0, 0.5)), Some(3));
+    let tile = qt.register_template(
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 4.0, 4.0)),
+        None,
+    );
+    let marker = qt.register_template(ShapeEnum::Circle(Circle::new(0.0, 0.0, 0.0)), None);
+
+    for i in 0..100 {
+        let position = i as Float;
+        qt.insert_from_template(i, bullet, position, position)
+            .unwrap();
+    }
+    qt.insert_from_template(100, tile, 20.0, 60.0).unwrap();
+
+    let mut collisions = Vec::new();
+    qt.collisions_filter(
+        ShapeEnum::Circle(Circle::new(50.0, 50.0, 0.1)),
+        Some(vec![3]),
+        &mut collisions,
+    );
+    assert_eq!(collisions, vec![50]);
+    collisions.clear();
+    qt.collisions(ShapeEnum::Point(Point::new(23.0, 63.0)), &mut collisions);
+    assert_eq!(collisions, vec![100]);
+
+    assert_eq!(
+        qt.insert_from_template(100, tile, 0.0, 0.0),
+        Err(QuadTreeError::AlreadyExists(100))
+    );
+    assert_eq!(
+        qt.insert_from_template(101, marker, 0.0, 0.0),
+        Err(QuadTreeError::DegenerateShape(101))
+    );
+    assert_eq!(
+        qt.insert_from_template(101, 7, 0.0, 0.0),
+        Err(QuadTreeError::UnknownTemplate(7))
+    );
+}
+
+#[test]
+fn test_lock_region() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let inside = ShapeEnum::Circle(Circle::new(20.0, 20.0, 1.0));
+    let outside = ShapeEnum::Circle(Circle::new(80.0, 80.0, 1.0));
+    qt.insert(1, inside.clone(), None);
+    qt.insert(2, outside.clone(), None);
+
+    let lock = qt.lock_region(ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 50.0, 50.0)));
+    assert_eq!(
+        qt.try_insert(3, inside.clone(), None),
+        Err(QuadTreeError::RegionLocked(3))
+    );
+    // Moving into or out of the region, or deleting from it, is rejected too
+    assert_eq!(
+        qt.try_relocate(2, inside.clone(), None),
+        Err(QuadTreeError::RegionLocked(2))
+    );
+    assert_eq!(
+        qt.try_relocate(1, outside.clone(), None),
+        Err(QuadTreeError::RegionLocked(1))
+    );
+    assert_eq!(qt.try_delete(1), Err(QuadTreeError::RegionLocked(1)));
+
+    // Changes elsewhere go through
+    qt.insert(4, ShapeEnum::Point(Point::new(90.0, 10.0)), None);
+    assert_eq!(
+        qt.try_relocate(2, ShapeEnum::Circle(Circle::new(70.0, 70.0, 1.0)), None),
+        Ok(true)
+    );
+
+    lock.release();
+    assert_eq!(qt.try_relocate(2, inside, None), Ok(true));
+    assert_eq!(qt.try_delete(1), Ok(true));
+    assert_eq!(qt.iter().count(), 2);
+}
+
+#[test]
+fn test_try_batch_and_update() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let inside = ShapeEnum::Circle(Circle::new(20.0, 20.0, 1.0));
+    let outside = ShapeEnum::Circle(Circle::new(80.0, 80.0, 1.0));
+    qt.insert(1, outside.clone(), None);
+    let _lock = qt.lock_region(ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 50.0, 50.0)));
+
+    // A rejected shape anywhere in a batch leaves the tree untouched
+    assert_eq!(
+        qt.try_insert_batch(vec![(2, outside.clone(), None), (3, inside.clone(), None)]),
+        Err(QuadTreeError::RegionLocked(3))
+    );
+    assert_eq!(qt.iter().count(), 1);
+    let requests = vec![
+        RelocationRequest {
+            value: 1,
+            shape: ShapeEnum::Point(Point::new(90.0, 90.0)),
+            entity_type: None,
+        },
+        RelocationRequest {
+            value: 4,
+            shape: inside.clone(),
+            entity_type: None,
+        },
+    ];
+    assert_eq!(
+        qt.try_relocate_batch(requests),
+        Err(QuadTreeError::RegionLocked(4))
+    );
+    assert_eq!(
+        format!("{:?}", qt.shape_snapshot()[&1]),
+        format!("{:?}", outside)
+    );
+
+    assert_eq!(
+        qt.try_update(1, inside.clone()),
+        Err(QuadTreeError::RegionLocked(1))
+    );
+    assert_eq!(
+        qt.try_upsert(1, inside.clone(), None),
+        Err(QuadTreeError::RegionLocked(1))
+    );
+    assert_eq!(qt.try_update(5, inside), Ok(false));
+    assert_eq!(
+        qt.try_upsert(1, ShapeEnum::Point(Point::new(70.0, 70.0)), None),
+        Ok(true)
+    );
+    assert_eq!(qt.try_insert_batch(vec![(2, outside, None)]), Ok(()));
+    assert_eq!(qt.iter().count(), 2);
+}
+
+#[test]
+fn test_concurrent_quadtree() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<QuadTree>();
+
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    for i in 0..100 {
+        let position = i as Float;
+        qt.insert(i, ShapeEnum::Circle(Circle::new(position, 50.0, 0.5)), None);
+    }
+    let shared = ConcurrentQuadTree::new(qt);
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    let mut collisions = Vec::new();
+                    shared.read().collisions(
+                        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 100.0, 100.0)),
+                        &mut collisions,
+                    );
+                    // Relocations are applied as a whole between reads
+                    assert_eq!(collisions.len(), 100);
+                }
+            })
+        })
+        .collect();
+
+    for frame in 0..50 {
+        let mut tree = shared.write();
+        for i in 0..100 {
+            let y = 10.0 + (frame % 80) as Float;
+            tree.relocate(i, ShapeEnum::Circle(Circle::new(i as Float, y, 0.5)), None);
+        }
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(shared.read().iter().count(), 100);
+}
+
+#[test]
+fn test_trace_replay() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    qt.insert(0, ShapeEnum::Circle(Circle::new(10.0, 10.0, 2.0)), Some(1));
+    qt.start_recording();
+
+    let mut rng = rand::thread_rng();
+    for i in 1..200 {
+        let x = rng.gen_range(0.0..100.0);
+        let y = rng.gen_range(0.0..100.0);
+        qt.insert(i, ShapeEnum::Circle(Circle::new(x, y, 1.0)), Some(i % 3));
+    }
+    for i in (0..200).step_by(3) {
+        qt.delete(i);
+    }
+    for i in (1..200).step_by(3) {
+        qt.relocate(i, ShapeEnum::Point(Point::new(50.0, 50.0)), None);
+    }
+    qt.update(2, ShapeEnum::Rectangle(Rectangle::new(1.0, 1.0, 2.0, 2.0)));
+    qt.rebalance();
+    let mut collisions = Vec::new();
+    qt.collisions_filter(
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 50.0, 50.0)),
+        Some(vec![2]),
+        &mut collisions,
+    );
+    qt.line_of_sight_batch(&[(Point::new(0.0, 0.0), Point::new(100.0, 100.0))], None);
+
+    let trace = qt.stop_recording().unwrap();
+    assert!(qt.stop_recording().is_none());
+    // The entry present before recording is captured as the first event
+    assert!(matches!(
+        trace.events[0],
+        TraceEvent::Insert {
+            value: 0,
+            entity_type: Some(1),
+            ..
+        }
+    ));
+    assert!(trace.events.iter().any(|event| matches!(
+        event,
+        TraceEvent::Collisions {
+            filter: TraceFilter::Mask(0b100),
+            ..
+        }
+    )));
+
+    let mut bytes = Vec::new();
+    trace.write_to(&mut bytes).unwrap();
+    let read_back = Trace::read_from(&mut &bytes[..]).unwrap();
+    assert_eq!(read_back.events.len(), trace.events.len());
+    assert!(Trace::read_from(&mut &bytes[1..]).is_err());
+
+    let replayed = replay(&read_back);
+    let mut expected = qt.shape_snapshot().into_iter().collect::<Vec<_>>();
+    let mut actual = replayed.shape_snapshot().into_iter().collect::<Vec<_>>();
+    expected.sort_by_key(|(value, _)| *value);
+    actual.sort_by_key(|(value, _)| *value);
+    assert_eq!(format!("{:?}", expected), format!("{:?}", actual));
+    assert_eq!(replayed.stats().node_count, qt.stats().node_count);
+}
+
+#[test]
+fn test_trace_records_public_queries_once() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    qt.insert(
+        0,
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 50.0, 50.0)),
+        None,
+    );
+    qt.insert(1, ShapeEnum::Circle(Circle::new(60.0, 60.0, 2.0)), None);
+    qt.start_recording();
+
+    // Both probe the tree many times, but only the nearest free point search is recorded
+    qt.nearest_free_point(Point::new(25.0, 25.0), 5.0);
+    let mut rng = rand::thread_rng();
+    qt.spawn_position(&Rectangle::new(0.0, 0.0, 100.0, 100.0), 5.0, None, || {
+        rng.gen_range(0.0..1.0)
+    });
+    qt.collisions_coarse(
+        ShapeEnum::Point(Point::new(1.0, 1.0)),
+        2,
+        &mut Vec::new(),
+        &mut Vec::new(),
+    );
+    qt.proximity_graph(5.0);
+    qt.cluster(5.0, 2);
+    assert!(qt.any_collision(&ShapeEnum::Point(Point::new(1.0, 1.0)), None));
+
+    let trace = qt.stop_recording().unwrap();
+    let tags: Vec<_> = trace
+        .events
+        .iter()
+        .map(|event| match event {
+            TraceEvent::Insert { .. } => "insert",
+            TraceEvent::NearestFreePoint { .. } => "nearest_free_point",
+            TraceEvent::CollisionsCoarse { .. } => "collisions_coarse",
+            TraceEvent::ProximityGraph(_) => "proximity_graph",
+            TraceEvent::Cluster { .. } => "cluster",
+            TraceEvent::Collisions { .. } => "collisions",
+            _ => "other",
+        })
+        .collect();
+    assert_eq!(
+        tags,
+        [
+            "insert",
+            "insert",
+            "nearest_free_point",
+            "collisions_coarse",
+            "proximity_graph",
+            "cluster",
+            "collisions"
+        ]
+    );
+
+    let mut bytes = Vec::new();
+    trace.write_to(&mut bytes).unwrap();
+    let read_back = Trace::read_from(&mut &bytes[..]).unwrap();
+    assert_eq!(read_back.events.len(), trace.events.len());
+    assert_eq!(replay(&read_back).iter().count(), 2);
+}
+
+#[test]
+fn test_min_cell_size() {
+    let config = Config {
+        min_cell_size: Some(10.0),
+        ..Default::default()
+    };
+    let bounding_box = Rectangle::new(0.0, 0.0, 100.0, 100.0);
+    assert_eq!(config.max_depth_for(&bounding_box), 3);
+    assert_eq!(config.max_depth_for(&Rectangle::new(0.0, 0.0, 5.0, 5.0)), 0);
+
+    // Piling entries onto one spot splits down to cells of 12.5, the last size above 10
+    let mut qt = QuadTree::new_with_config(bounding_box, config);
+    for i in 0..100 {
+        qt.insert(i, ShapeEnum::Point(Point::new(1.0, 1.0)), None);
+    }
+    assert_eq!(qt.stats().max_depth, 3);
+    let mut bounding_boxes = Vec::new();
+    qt.all_node_bounding_boxes(&mut bounding_boxes);
+    assert!(bounding_boxes.iter().all(|rect| rect.width >= 10.0));
+
+    // Auto-expanding a root smaller than one cell re-derives the depth from the grown bounds
+    let config = Config {
+        min_cell_size: Some(10.0),
+        auto_expand: true,
+        ..Default::default()
+    };
+    let mut qt = QuadTree::new_with_config(Rectangle::new(0.0, 0.0, 8.0, 8.0), config);
+    qt.insert(1000, ShapeEnum::Point(Point::new(100.0, 100.0)), None);
+    for i in 0..100 {
+        qt.insert(i, ShapeEnum::Point(Point::new(1.0, 1.0)), None);
+    }
+    assert_eq!(qt.stats().max_depth, 3);
+    let mut bounding_boxes = Vec::new();
+    qt.all_node_bounding_boxes(&mut bounding_boxes);
+    let smallest = bounding_boxes
+        .iter()
+        .map(|rect| rect.width.min(rect.height))
+        .fold(Float::INFINITY, Float::min);
+    assert_eq!(smallest, 16.0);
+}
+
+#[test]
+fn test_clone_snapshot() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    for i in 0..50 {
+        let position = i as Float * 2.0;
+        qt.insert(i, ShapeEnum::Point(Point::new(position, position)), None);
+    }
+    let _lock = qt.lock_region(ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 10.0, 10.0)));
+    let mut snapshot = qt.clone();
+
+    for i in 10..50 {
+        qt.delete(i);
+    }
+    let everything = ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let mut collisions = Vec::new();
+    snapshot.collisions(everything.clone(), &mut collisions);
+    assert_eq!(collisions.len(), 50);
+
+    // Region locks stay with the original tree
+    assert!(qt.try_delete(0).is_err());
+    assert_eq!(snapshot.try_delete(0), Ok(true));
+    collisions.clear();
+    qt.collisions(everything, &mut collisions);
+    assert_eq!(collisions.len(), 10);
+}
+
+#[test]
+fn test_make_mut_copy_on_write() {
+    let mut qt = Arc::new(QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0)));
+    for i in 0..50 {
+        let position = i as Float * 2.0;
+        QuadTree::make_mut(&mut qt).insert(
+            i,
+            ShapeEnum::Point(Point::new(position, position)),
+            None,
+        );
+    }
+    let _lock = qt.lock_region(ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 10.0, 10.0)));
+    QuadTree::make_mut(&mut qt).start_recording();
+
+    // Taking a snapshot shares the tree, and the first write after it copies the tree
+    let snapshot = Arc::clone(&qt);
+    let shared = Arc::as_ptr(&qt);
+    QuadTree::make_mut(&mut qt).delete(20);
+    assert!(!std::ptr::eq(Arc::as_ptr(&qt), shared));
+    assert_eq!(snapshot.iter().count(), 50);
+    assert_eq!(qt.iter().count(), 49);
+
+    // Once unshared the tree is written in place
+    drop(snapshot);
+    let unshared = Arc::as_ptr(&qt);
+    QuadTree::make_mut(&mut qt).delete(21);
+    assert!(std::ptr::eq(Arc::as_ptr(&qt), unshared));
+
+    // Locks, counters and the recording stay with the written handle
+    let tree = QuadTree::make_mut(&mut qt);
+    assert!(tree.try_delete(0).is_err());
+    assert_eq!(tree.take_counters().deletes, 2);
+    let trace = tree.stop_recording().unwrap();
+    assert!(matches!(trace.events.last(), Some(TraceEvent::Delete(21))));
+}
+
+#[test]
+fn test_move_entity() {
+    let mut west = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    let mut east = QuadTree::new(Rectangle::new(100.0, 0.0, 100.0, 100.0));
+    west.insert(1, ShapeEnum::Circle(Circle::new(99.0, 50.0, 2.0)), Some(3));
+    west.insert(2, ShapeEnum::Circle(Circle::new(50.0, 50.0, 2.0)), None);
+    east.insert(2, ShapeEnum::Point(Point::new(150.0, 50.0)), None);
+
+    assert_eq!(west.move_entity(1, &mut east), Ok(true));
+    assert!(west.iter().all(|(value, _, _)| value != 1));
+    let mut collisions = Vec::new();
+    east.collisions_filter(
+        ShapeEnum::Circle(Circle::new(99.0, 50.0, 1.0)),
+        Some(vec![3]),
+        &mut collisions,
+    );
+    assert_eq!(collisions, vec![1]);
+    assert_eq!(west.move_entity(1, &mut east), Ok(false));
+
+    // A rejected move leaves both trees unchanged
+    assert_eq!(
+        west.move_entity(2, &mut east),
+        Err(QuadTreeError::AlreadyExists(2))
+    );
+    let _lock = east.lock_region(ShapeEnum::Circle(Circle::new(99.0, 50.0, 5.0)));
+    assert_eq!(
+        east.move_entity(1, &mut west),
+        Err(QuadTreeError::RegionLocked(1))
+    );
+    assert_eq!(west.iter().count(), 1);
+    assert_eq!(east.iter().count(), 2);
+}
+
+#[test]
+fn test_spawn_position() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 100.0, 100.0));
+    // Walls of type 1 cover the left half, scattered pickups of type 2 the right half
+    for i in 0..50 {
+        let y = i as Float * 2.0;
+        qt.insert(
+            i,
+            ShapeEnum::Rectangle(Rectangle::new(0.0, y, 50.0, 2.0)),
+            Some(1),
+        );
+    }
+    for i in 0..20 {
+        let position = 55.0 + i as Float * 2.0;
+        qt.insert(
+            100 + i,
+            ShapeEnum::Circle(Circle::new(position, position, 0.5)),
+            Some(2),
+        );
+    }
+
+    let mut rng = rand::thread_rng();
+    let region = Rectangle::new(0.0, 0.0, 100.0, 100.0);
+    for _ in 0..50 {
+        let spawn = qt
+            .spawn_position(&region, 2.0, None, || rng.gen::<Float>())
+            .unwrap();
+        assert!(spawn.x >= 50.0 && spawn.x <= 98.0 && spawn.y >= 2.0 && spawn.y <= 98.0);
+        let clearance = ShapeEnum::Circle(Circle::new(spawn.x, spawn.y, 2.0));
+        assert!(!qt.any_collision(&clearance, None));
+    }
+
+    // Only walls block when filtering on them, and a fully walled region has no room
+    let spawn = qt
+        .spawn_position(&region, 2.0, Some(&[1]), || rng.gen::<Float>())
+        .unwrap();
+    let clearance = ShapeEnum::Circle(Circle::new(spawn.x, spawn.y, 2.0));
+    assert!(!qt.any_collision(&clearance, Some(&[1])));
+    let walled = Rectangle::new(0.0, 0.0, 50.0, 100.0);
+    assert!(qt
+        .spawn_position(&walled, 1.0, Some(&[1]), || rng.gen::<Float>())
+        .is_none());
+}
+
+#[test]
+fn test_root_split() {
+    let config = Config {
+        node_capacity: 4,
+        root_split: Some(Point::new(20.0, 30.0)),
+        ..Default::default()
+    };
+    let mut qt = QuadTree::new_with_config(Rectangle::new(0.0, 0.0, 100.0, 100.0), config);
+    for i in 0..20 {
+        let offset = i as Float * 0.5;
+        qt.insert(
+            i,
+            ShapeEnum::Point(Point::new(15.0 + offset, 25.0 + offset)),
+            None,
+        );
+    }
+
+    let depth_one: Vec<(Float, Float, Float, Float)> = qt
+        .nodes()
+        .filter(|node| node.depth == 1)
+        .map(|node| {
+            let rect = node.bounding_box;
+            (rect.x, rect.y, rect.width, rect.height)
+        })
+        .collect();
+    assert_eq!(depth_one.len(), 4);
+    assert!(depth_one.contains(&(0.0, 0.0, 20.0, 30.0)));
+    assert!(depth_one.contains(&(20.0, 30.0, 80.0, 70.0)));
+
+    // Points on either side of the split land in the right quadrant
+    let mut collisions = Vec::new();
+    qt.collisions(
+        ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 20.0, 30.0)),
+        &mut collisions,
+    );
+    collisions.sort();
+    assert_eq!(collisions, (0..11).collect::<Vec<u32>>());
+
+    // Below the root, nodes are still halved
+    let deeper = qt.nodes().find(|node| node.depth == 2).unwrap();
+    assert!(deeper.bounding_box.width == 10.0 || deeper.bounding_box.width == 40.0);
+}
+
+#[test]
+fn test_fragmentation_report() {
+    let mut qt = QuadTree::new(Rectangle::new(0.0, 0.0, 1000.0, 1000.0));
+    let mut rng = rand::thread_rng();
+    for i in 0..2000 {
+        let x = rng.gen_range(0.0..1000.0);
+        let y = rng.gen_range(0.0..1000.0);
+        qt.insert(i, ShapeEnum::Circle(Circle::new(x, y, 1.0)), None);
+    }
+    // Entries gathering in one corner leave their old subtrees underfull, and the
+    // deletions afterwards leave holes in the node arena
+    for i in 0..2000 {
+        let x = rng.gen_range(0.0..100.0);
+        let y = rng.gen_range(0.0..100.0);
+        qt.relocate(i, ShapeEnum::Circle(Circle::new(x, y, 1.0)), None);
+    }
+    for i in 100..2000 {
+        qt.delete(i);
+    }
+
+    let report = qt.fragmentation_report();
+    assert!(report.empty_subtrees > 0 && report.reclaimable_nodes > 0);
+    assert!(report.optimize_recommended);
+    assert_eq!(report.live_nodes, qt.stats().node_count);
+
+    // Merging them frees the nodes but leaves holes behind
+    qt.rebalance();
+    let report = qt.fragmentation_report();
+    assert_eq!(report.mergeable_subtrees, 0);
+    assert!(report.arena_holes > 0);
+    assert!(report.optimize_recommended);
+
+    let everything = ShapeEnum::Rectangle(Rectangle::new(0.0, 0.0, 1000.0, 1000.0));
+    let mut before = Vec::new();
+    qt.collisions(everything.clone(), &mut before);
+    qt.optimize();
+
+    let report = qt.fragmentation_report();
+    assert_eq!(report.arena_holes, 0);
+    assert_eq!(report.mergeable_subtrees, 0);
+    assert!(!report.optimize_recommended);
+    assert_eq!(report.arena_slots, qt.stats().node_count);
+
+    let mut after = Vec::new();
+    qt.collisions(everything, &mut after);
+    before.sort();
+    after.sort();
+    assert_eq!(before, after);
+    // The renumbered tree keeps working as usual
+    qt.relocate(0, ShapeEnum::Circle(Circle::new(900.0, 900.0, 1.0)), None);
+    assert!(qt.delete(1));
+    assert_eq!(qt.iter().count(), 99);
+}
@@ -0,0 +1,378 @@
+// JavaScript bindings mirroring the Python package, so a browser client runs the same
+// tree as the server. Methods take camelCase names, following JavaScript conventions.
+use quadtree::quadtree::{Config, DegenerateShapeMode, QuadTree, RelocationRequest, SizeClasses};
+use quadtree::shapes::{Circle, Float, Point, Rectangle, ShapeEnum};
+
+use js_sys::{Array, Reflect, Uint32Array};
+use wasm_bindgen::prelude::*;
+
+#[derive(Debug, Clone)]
+#[wasm_bindgen(js_name = Circle)]
+pub struct JsCircle {
+    pub x: Float,
+    pub y: Float,
+    pub radius: Float,
+}
+
+#[wasm_bindgen(js_class = Circle)]
+impl JsCircle {
+    #[wasm_bindgen(constructor)]
+    pub fn new(x: Float, y: Float, radius: Float) -> Self {
+        JsCircle { x, y, radius }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[wasm_bindgen(js_name = Rectangle)]
+pub struct JsRectangle {
+    pub x: Float,
+    pub y: Float,
+    pub width: Float,
+    pub height: Float,
+}
+
+#[wasm_bindgen(js_class = Rectangle)]
+impl JsRectangle {
+    #[wasm_bindgen(constructor)]
+    pub fn new(x: Float, y: Float, width: Float, height: Float) -> Self {
+        JsRectangle {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[wasm_bindgen(js_name = Point)]
+pub struct JsPoint {
+    pub x: Float,
+    pub y: Float,
+}
+
+#[wasm_bindgen(js_class = Point)]
+impl JsPoint {
+    #[wasm_bindgen(constructor)]
+    pub fn new(x: Float, y: Float) -> Self {
+        JsPoint { x, y }
+    }
+}
+
+// Size thresholds measured on the longer side of an entity's bounding box
+#[derive(Debug, Clone, Copy)]
+#[wasm_bindgen(js_name = SizeClasses)]
+pub struct JsSizeClasses {
+    #[wasm_bindgen(js_name = tinyMax)]
+    pub tiny_max: Float,
+    #[wasm_bindgen(js_name = mediumMax)]
+    pub medium_max: Float,
+}
+
+#[wasm_bindgen(js_class = SizeClasses)]
+impl JsSizeClasses {
+    #[wasm_bindgen(constructor)]
+    pub fn new(tiny_max: Float, medium_max: Float) -> Self {
+        JsSizeClasses {
+            tiny_max,
+            medium_max,
+        }
+    }
+}
+
+// How rectangles without area and circles without radius are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[wasm_bindgen(js_name = DegenerateShapeMode)]
+pub enum JsDegenerateShapeMode {
+    AlwaysMiss,
+    PointSemantics,
+    ErrorOnInsert,
+}
+
+#[derive(Debug, Clone)]
+#[wasm_bindgen(js_name = Config)]
+pub struct JsConfig {
+    #[wasm_bindgen(js_name = poolSize)]
+    pub pool_size: usize,
+    #[wasm_bindgen(js_name = nodeCapacity)]
+    pub node_capacity: usize,
+    #[wasm_bindgen(js_name = maxDepth)]
+    pub max_depth: usize,
+    // Overrides maxDepth with the depth at which cells reach this size
+    #[wasm_bindgen(js_name = minCellSize)]
+    pub min_cell_size: Option<Float>,
+    // Where roots are split into quadrants instead of their center
+    #[wasm_bindgen(js_name = rootSplit)]
+    pub root_split: Option<JsPoint>,
+    // Route entities into separate trees by size
+    #[wasm_bindgen(js_name = sizeClasses)]
+    pub size_classes: Option<JsSizeClasses>,
+    pub looseness: Float,
+    #[wasm_bindgen(js_name = autoExpand)]
+    pub auto_expand: bool,
+    #[wasm_bindgen(js_name = degenerateShapes)]
+    pub degenerate_shapes: JsDegenerateShapeMode,
+}
+
+#[wasm_bindgen(js_class = Config)]
+impl JsConfig {
+    #[wasm_bindgen(constructor)]
+    pub fn new(pool_size: usize, node_capacity: usize, max_depth: usize) -> Self {
+        JsConfig {
+            pool_size,
+            node_capacity,
+            max_depth,
+            min_cell_size: None,
+            root_split: None,
+            size_classes: None,
+            looseness: 1.0,
+            auto_expand: false,
+            degenerate_shapes: JsDegenerateShapeMode::PointSemantics,
+        }
+    }
+}
+
+// Read a shape from any object with the fields of a Circle, Rectangle or Point, which
+// includes the classes above and plain objects such as {x: 1, y: 2, radius: 3}. Circles
+// may also give their radius as r, as in the Python dict form.
+fn js_to_shape(shape: &JsValue) -> Result<ShapeEnum, JsError> {
+    let field = |name: &str| {
+        Reflect::get(shape, &JsValue::from_str(name))
+            .ok()
+            .and_then(|value| value.as_f64())
+            .map(|value| value as Float)
+    };
+    match (
+        field("x"),
+        field("y"),
+        field("radius").or_else(|| field("r")),
+        field("width"),
+        field("height"),
+    ) {
+        (Some(x), Some(y), Some(radius), _, _) => Ok(ShapeEnum::Circle(Circle::new(x, y, radius))),
+        (Some(x), Some(y), None, Some(width), Some(height)) => {
+            Ok(ShapeEnum::Rectangle(Rectangle::new(x, y, width, height)))
+        }
+        (Some(x), Some(y), None, None, None) => Ok(ShapeEnum::Point(Point::new(x, y))),
+        _ => Err(JsError::new("Expected a Rectangle, Circle or Point object")),
+    }
+}
+
+// Numbers that are not whole or do not fit in a u32 are rejected rather than truncated
+fn js_to_u32(value: &JsValue, name: &str) -> Result<u32, JsError> {
+    value
+        .as_f64()
+        .filter(|value| value.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(value))
+        .map(|value| value as u32)
+        .ok_or_else(|| {
+            JsError::new(&format!(
+                "Expected the {} to be a whole number from 0 to {}",
+                name,
+                u32::MAX
+            ))
+        })
+}
+
+// Null and undefined mean an untyped entity
+fn js_to_entity_type(entity_type: &JsValue) -> Result<Option<u32>, JsError> {
+    if entity_type.is_null() || entity_type.is_undefined() {
+        return Ok(None);
+    }
+    js_to_u32(entity_type, "entity type").map(Some)
+}
+
+// Read the [value, shape, entityType] arrays passed to the batch methods
+fn js_to_items(items: &Array) -> Result<Vec<(u32, ShapeEnum, Option<u32>)>, JsError> {
+    items
+        .iter()
+        .map(|item| {
+            let item = Array::from(&item);
+            let value = js_to_u32(&item.get(0), "value")?;
+            let shape = js_to_shape(&item.get(1))?;
+            let entity_type = js_to_entity_type(&item.get(2))?;
+            Ok((value, shape, entity_type))
+        })
+        .collect()
+}
+
+#[wasm_bindgen(js_name = QuadTree)]
+pub struct JsQuadTree {
+    quadtree: QuadTree,
+}
+
+#[wasm_bindgen(js_class = QuadTree)]
+impl JsQuadTree {
+    #[wasm_bindgen(constructor)]
+    pub fn new(bounding_box: &JsRectangle) -> Self {
+        let bounding_rect = Rectangle::new(
+            bounding_box.x,
+            bounding_box.y,
+            bounding_box.width,
+            bounding_box.height,
+        );
+        JsQuadTree {
+            quadtree: QuadTree::new(bounding_rect),
+        }
+    }
+
+    #[wasm_bindgen(js_name = newWithConfig)]
+    pub fn new_with_config(bounding_box: &JsRectangle, config: &JsConfig) -> Self {
+        let bounding_rect = Rectangle::new(
+            bounding_box.x,
+            bounding_box.y,
+            bounding_box.width,
+            bounding_box.height,
+        );
+        let rust_config = Config {
+            pool_size: config.pool_size,
+            node_capacity: config.node_capacity,
+            max_depth: config.max_depth,
+            min_cell_size: config.min_cell_size,
+            root_split: config.root_split.map(|point| Point::new(point.x, point.y)),
+            size_classes: config.size_classes.map(|size_classes| SizeClasses {
+                tiny_max: size_classes.tiny_max,
+                medium_max: size_classes.medium_max,
+            }),
+            looseness: config.looseness,
+            auto_expand: config.auto_expand,
+            degenerate_shapes: match config.degenerate_shapes {
+                JsDegenerateShapeMode::AlwaysMiss => DegenerateShapeMode::AlwaysMiss,
+                JsDegenerateShapeMode::PointSemantics => DegenerateShapeMode::PointSemantics,
+                JsDegenerateShapeMode::ErrorOnInsert => DegenerateShapeMode::ErrorOnInsert,
+            },
+        };
+        JsQuadTree {
+            quadtree: QuadTree::new_with_config(bounding_rect, rust_config),
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        value: u32,
+        shape: &JsValue,
+        entity_type: Option<u32>,
+    ) -> Result<(), JsError> {
+        let shape = js_to_shape(shape)?;
+        self.quadtree
+            .try_insert(value, shape, entity_type)
+            .map_err(|err| JsError::new(&err.to_string()))
+    }
+
+    // Insert an array of [value, shape, entityType] items. Throws, inserting nothing, if a
+    // value is already in the tree or repeated in the array.
+    #[wasm_bindgen(js_name = insertBatch)]
+    pub fn insert_batch(&mut self, items: &Array) -> Result<(), JsError> {
+        let items = js_to_items(items)?;
+        self.quadtree
+            .try_insert_batch(items)
+            .map_err(|err| JsError::new(&err.to_string()))
+    }
+
+    // Insert a shape, replacing any entry with the same value. Returns whether an entry
+    // was replaced.
+    pub fn upsert(
+        &mut self,
+        value: u32,
+        shape: &JsValue,
+        entity_type: Option<u32>,
+    ) -> Result<bool, JsError> {
+        let shape = js_to_shape(shape)?;
+        self.quadtree
+            .try_upsert(value, shape, entity_type)
+            .map_err(|err| JsError::new(&err.to_string()))
+    }
+
+    // Returns whether there was an entry with this value
+    pub fn delete(&mut self, value: u32) -> bool {
+        self.quadtree.delete(value)
+    }
+
+    // Returns whether there was an entry with this value. Otherwise the shape is inserted.
+    pub fn relocate(
+        &mut self,
+        value: u32,
+        shape: &JsValue,
+        entity_type: Option<u32>,
+    ) -> Result<bool, JsError> {
+        let shape = js_to_shape(shape)?;
+        self.quadtree
+            .try_relocate(value, shape, entity_type)
+            .map_err(|err| JsError::new(&err.to_string()))
+    }
+
+    // Relocate an array of [value, shape, entityType] items
+    #[wasm_bindgen(js_name = relocateBatch)]
+    pub fn relocate_batch(&mut self, items: &Array) -> Result<(), JsError> {
+        let requests = js_to_items(items)?
+            .into_iter()
+            .map(|(value, shape, entity_type)| RelocationRequest {
+                value,
+                shape,
+                entity_type,
+            })
+            .collect::<Vec<_>>();
+        self.quadtree
+            .try_relocate_batch(requests)
+            .map_err(|err| JsError::new(&err.to_string()))
+    }
+
+    // Change the shape of an entry, keeping its entity type. Returns false if there is no
+    // entry with this value.
+    pub fn update(&mut self, value: u32, shape: &JsValue) -> Result<bool, JsError> {
+        let shape = js_to_shape(shape)?;
+        self.quadtree
+            .try_update(value, shape)
+            .map_err(|err| JsError::new(&err.to_string()))
+    }
+
+    pub fn collisions(&self, shape: &JsValue) -> Result<Vec<u32>, JsError> {
+        self.collisions_filter(shape, None)
+    }
+
+    #[wasm_bindgen(js_name = collisionsFilter)]
+    pub fn collisions_filter(
+        &self,
+        shape: &JsValue,
+        entity_types: Option<Vec<u32>>,
+    ) -> Result<Vec<u32>, JsError> {
+        let shape = js_to_shape(shape)?;
+        let mut collisions = Vec::new();
+        self.quadtree
+            .collisions_filter(shape, entity_types, &mut collisions);
+        Ok(collisions)
+    }
+
+    #[wasm_bindgen(js_name = anyCollision)]
+    pub fn any_collision(
+        &self,
+        shape: &JsValue,
+        entity_types: Option<Vec<u32>>,
+    ) -> Result<bool, JsError> {
+        let shape = js_to_shape(shape)?;
+        Ok(self.quadtree.any_collision(&shape, entity_types.as_deref()))
+    }
+
+    // Returns an array holding a Uint32Array of hits per shape
+    #[wasm_bindgen(js_name = collisionsBatch)]
+    pub fn collisions_batch(&self, shapes: &Array) -> Result<Array, JsError> {
+        self.collisions_batch_filter(shapes, None)
+    }
+
+    #[wasm_bindgen(js_name = collisionsBatchFilter)]
+    pub fn collisions_batch_filter(
+        &self,
+        shapes: &Array,
+        entity_types: Option<Vec<u32>>,
+    ) -> Result<Array, JsError> {
+        let shapes = shapes
+            .iter()
+            .map(|shape| js_to_shape(&shape))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self
+            .quadtree
+            .collisions_batch_filter(shapes, entity_types)
+            .iter()
+            .map(|collisions| Uint32Array::from(&collisions[..]))
+            .collect())
+    }
+}